@@ -0,0 +1,21 @@
+//! Driver control-flow benchmarks on the simulated device
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use lzma2_fpga_driver::device::{HardwareCompressionDevice, SimulatedDevice};
+
+fn compress_block(c: &mut Criterion) {
+    let device = SimulatedDevice::simulated();
+    let input: Vec<u8> = (0..32 * 1024)
+        .map(|i| b"simulated benchmark input "[(i * 5 + i / 26) % 26])
+        .collect();
+
+    let mut group = c.benchmark_group("simulated");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("compress_32k", |b| {
+        b.iter(|| device.compress(black_box(&input)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, compress_block);
+criterion_main!(benches);
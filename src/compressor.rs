@@ -0,0 +1,778 @@
+//! Arbitrary-length compression for LZMA2 FPGA Compression Driver
+//!
+//! The hardware works on blocks of at most `BLOCK_SIZE` bytes. The block
+//! compressor splits input into such blocks, compresses each one on the
+//! device and frames the results:
+//!
+//! | Field               | Size                     |
+//! |---------------------|--------------------------|
+//! | Magic `LZ2B`        | 4                        |
+//! | Uncompressed length | 4 (LE), 1..=`BLOCK_SIZE` |
+//! | Compressed length   | 4 (LE); bit 31 marks a   |
+//! |                     | stored block             |
+//! | Compressed data     | compressed length        |
+//! | ...                 |                          |
+//! | End marker          | 4 (LE), zero             |
+//!
+//! The final block carries its real length, so decompression restores the
+//! exact input. The shipped engine only takes full blocks; `PcieDevice`
+//! encodes a short final block in software unless the engine has
+//! `EngineFeatures::short_blocks`.
+//!
+//! Alternatively each block becomes one LZMA2 chunk, producing a raw LZMA2
+//! stream that standard decoders accept, or one block of an `.xz` file.
+//!
+//! Legacy `.lzma` files hold a single LZMA stream, so their blocks are
+//! chained on the device instead. Engines without
+//! `EngineFeatures::chained_streams`, like the shipped RTL, only write
+//! `.lzma` files of up to one block.
+//!
+//! Blocks the device cannot shrink are stored uncompressed, so output
+//! grows by at most the framing overhead.
+//!
+//! With verification enabled, every block of the block stream, LZMA2 and
+//! `.xz` formats is decoded in software and compared with its input before
+//! it is emitted. A mismatching block is compressed again or stored.
+
+use std::sync::{Mutex, MutexGuard};
+
+use crate::device::{ChainedBlock, EngineGuard, HardwareCompressionDevice, PerformanceMetrics, BLOCK_SIZE};
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::format::lzma2::{self, Chunk, ChunkReader, ChunkReset};
+use crate::format::lzma_alone::LzmaAloneHeader;
+use crate::format::xz::XzWriter;
+use crate::lzma::encoder::LzmaEncoder;
+use crate::lzma::{self, LzmaProperties, DICT_SIZE};
+
+/// Magic bytes opening a block stream
+pub const BLOCK_STREAM_MAGIC: [u8; 4] = *b"LZ2B";
+
+/// Compressed length flag of a block stored uncompressed
+const STORED_BLOCK: u32 = 1 << 31;
+
+/// Handling of hardware blocks that fail verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyPolicy {
+    /// Device attempts after the first mismatch
+    pub retries: u32,
+
+    /// Store the block once the retries are exhausted instead of failing
+    pub store_on_mismatch: bool,
+}
+
+impl Default for VerifyPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 1,
+            store_on_mismatch: true,
+        }
+    }
+}
+
+/// Encoded form of one block
+enum EncodedBlock {
+    /// Raw LZMA stream from the device
+    Lzma(Vec<u8>),
+
+    /// Block kept uncompressed
+    Stored,
+}
+
+/// Compressor for input of any length on a block-based device
+#[derive(Debug)]
+pub struct BlockCompressor<D> {
+    device: D,
+
+    /// Verification of hardware output, `None` to trust the device
+    verify: Option<VerifyPolicy>,
+
+    /// Counters kept by the compressor rather than the device
+    metrics: Mutex<PerformanceMetrics>,
+}
+
+impl<D: HardwareCompressionDevice> BlockCompressor<D> {
+    /// Create a compressor running on `device`
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            verify: None,
+            metrics: Mutex::new(PerformanceMetrics::default()),
+        }
+    }
+
+    /// Decode every hardware block in software and handle mismatches
+    /// according to `policy`
+    pub fn with_verification(mut self, policy: VerifyPolicy) -> Self {
+        self.verify = Some(policy);
+        self
+    }
+
+    /// Device metrics together with the compressor's stored block and
+    /// verification counters
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the device metrics cannot be retrieved
+    pub fn get_performance_metrics(&self) -> Lzma2Result<PerformanceMetrics> {
+        let mut metrics = self.device.get_performance_metrics()?;
+        let counters = self.lock_metrics();
+        metrics.incompressible_blocks = counters.incompressible_blocks;
+        metrics.verify_metrics = counters.verify_metrics.clone();
+        Ok(metrics)
+    }
+
+    /// Device the blocks run on
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Release the device
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Compress `input` into a block stream
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if a block fails to compress
+    pub fn compress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        let mut output = BLOCK_STREAM_MAGIC.to_vec();
+
+        for (index, block) in input.chunks(BLOCK_SIZE).enumerate() {
+            output.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            match self.encode_block(block, index, 0)? {
+                EncodedBlock::Lzma(compressed) => {
+                    output.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                    output.extend_from_slice(&compressed);
+                },
+                EncodedBlock::Stored => {
+                    output.extend_from_slice(&(block.len() as u32 | STORED_BLOCK).to_le_bytes());
+                    output.extend_from_slice(block);
+                },
+            }
+        }
+
+        output.extend_from_slice(&0u32.to_le_bytes());
+        Ok(output)
+    }
+
+    /// Restore the input of a block stream
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if the stream is malformed,
+    /// or the device error if a block fails to decompress
+    pub fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        let mut reader = FrameReader { input, pos: 0 };
+        if reader.take(BLOCK_STREAM_MAGIC.len())? != BLOCK_STREAM_MAGIC {
+            return Err(Lzma2Error::InputValidationError(
+                "Not a block stream: bad magic".to_string()
+            ));
+        }
+
+        let mut output = Vec::new();
+        loop {
+            let uncompressed_len = reader.read_u32()? as usize;
+            if uncompressed_len == 0 {
+                break;
+            }
+            if uncompressed_len > BLOCK_SIZE {
+                return Err(Lzma2Error::InputValidationError(
+                    format!("Block of {} bytes exceeds the {}-byte block size", uncompressed_len, BLOCK_SIZE)
+                ));
+            }
+
+            let compressed_len = reader.read_u32()?;
+            if compressed_len & STORED_BLOCK != 0 {
+                if (compressed_len & !STORED_BLOCK) as usize != uncompressed_len {
+                    return Err(Lzma2Error::InputValidationError(
+                        format!("Stored block of {} bytes holds {}", uncompressed_len, compressed_len & !STORED_BLOCK)
+                    ));
+                }
+                output.extend_from_slice(reader.take(uncompressed_len)?);
+                continue;
+            }
+
+            let block = reader.take(compressed_len as usize)?;
+            output.extend_from_slice(&self.device.decompress_block(block, uncompressed_len)?);
+        }
+
+        if reader.pos != input.len() {
+            return Err(Lzma2Error::InputValidationError(
+                format!("{} trailing bytes after the end marker", input.len() - reader.pos)
+            ));
+        }
+
+        Ok(output)
+    }
+
+    /// Compress `input` into a raw LZMA2 stream
+    ///
+    /// Every hardware block starts from an empty dictionary, so each one
+    /// becomes a chunk with a full reset.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if a block fails to compress
+    pub fn compress_lzma2(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for (index, block) in input.chunks(BLOCK_SIZE).enumerate() {
+            self.write_lzma2_chunk(&mut output, block, index)?;
+        }
+
+        lzma2::write_end(&mut output);
+        Ok(output)
+    }
+
+    /// Compress `input` into an `.xz` file with one block per hardware block
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if a block fails to compress
+    pub fn compress_xz(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        let mut writer = XzWriter::new();
+
+        for (index, block) in input.chunks(BLOCK_SIZE).enumerate() {
+            let mut stream = Vec::new();
+            self.write_lzma2_chunk(&mut stream, block, index)?;
+            lzma2::write_end(&mut stream);
+            writer.write_block(&stream, block)?;
+        }
+
+        Ok(writer.finish())
+    }
+
+    /// Compress `input` into a `.lzma` file recording its size
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if a block fails to compress or the device
+    /// cannot chain the blocks of a longer input
+    pub fn compress_lzma(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        let mut encoder = self.lzma_encoder(Some(input.len() as u64));
+        let mut output = encoder.update(input)?;
+        output.extend_from_slice(&encoder.finish()?);
+        Ok(output)
+    }
+
+    /// Start an incremental `.lzma` encoder
+    ///
+    /// Without an `uncompressed_size` the stream ends with an end-of-stream
+    /// marker.
+    pub fn lzma_encoder(&self, uncompressed_size: Option<u64>) -> LzmaAloneEncoder<'_, D> {
+        LzmaAloneEncoder {
+            device: &self.device,
+            uncompressed_size,
+            received: 0,
+            pending: Vec::new(),
+            header_written: false,
+            stream_open: false,
+            engine: None,
+        }
+    }
+
+    /// Compress one block and append it as a chunk with a full reset
+    pub(crate) fn write_lzma2_chunk(&self, output: &mut Vec<u8>, block: &[u8], index: usize) -> Lzma2Result<()> {
+        let framing = lzma2::LZMA_CHUNK_HEADER_SIZE - lzma2::STORED_CHUNK_HEADER_SIZE;
+        match self.encode_block(block, index, framing)? {
+            EncodedBlock::Lzma(compressed) => {
+                let props = LzmaProperties::default().to_byte();
+                lzma2::write_lzma_chunk(output, ChunkReset::Dictionary, props, block.len(), &compressed)
+            },
+            EncodedBlock::Stored => lzma2::write_stored_chunk(output, true, block),
+        }
+    }
+
+    /// Compress block number `index` on the device, verifying the output
+    /// if enabled
+    ///
+    /// `framing` is how many bytes more the format spends framing an LZMA
+    /// block than a stored one. Output that would not come out smaller
+    /// than the stored block is dropped in favour of storing it.
+    fn encode_block(&self, block: &[u8], index: usize, framing: usize) -> Lzma2Result<EncodedBlock> {
+        let mut attempt = 0;
+        loop {
+            let compressed = self.device.compress_block(block)?;
+            if compressed.len() + framing >= block.len() {
+                self.lock_metrics().incompressible_blocks += 1;
+                return Ok(EncodedBlock::Stored);
+            }
+
+            let Some(policy) = self.verify else {
+                return Ok(EncodedBlock::Lzma(compressed));
+            };
+            let matches = lzma::decode_block(&compressed, block.len()).is_ok_and(|decoded| decoded == block);
+
+            let mut counters = self.lock_metrics();
+            let metrics = &mut counters.verify_metrics;
+            metrics.blocks_verified += 1;
+            if matches {
+                return Ok(EncodedBlock::Lzma(compressed));
+            }
+
+            metrics.mismatches += 1;
+            tracing::warn!("Block {} failed verification (attempt {})", index, attempt + 1);
+            if attempt < policy.retries {
+                metrics.retries += 1;
+                attempt += 1;
+            } else if policy.store_on_mismatch {
+                metrics.stored_blocks += 1;
+                return Ok(EncodedBlock::Stored);
+            } else {
+                return Err(Lzma2Error::VerificationFailed {
+                    offset: (index * BLOCK_SIZE) as u64,
+                });
+            }
+        }
+    }
+
+    fn lock_metrics(&self) -> MutexGuard<'_, PerformanceMetrics> {
+        self.metrics.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Restore the input of a raw LZMA2 stream on the device
+    ///
+    /// LZMA chunks must be self-contained blocks in the engine's format, as
+    /// produced by `compress_lzma2`.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if the stream is malformed
+    /// or needs state the engine cannot restore, or the device error if a
+    /// block fails to decompress
+    pub fn decompress_lzma2(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        let mut reader = ChunkReader::new(input);
+        let mut output = Vec::new();
+
+        for chunk in reader.by_ref() {
+            self.decompress_chunk(chunk?, &mut output)?;
+        }
+
+        if !reader.is_finished() || reader.position() != input.len() {
+            return Err(Lzma2Error::InputValidationError(
+                "LZMA2 stream is not terminated by its end marker".to_string()
+            ));
+        }
+
+        Ok(output)
+    }
+
+    /// Restore one LZMA2 chunk, appending to `output`
+    pub(crate) fn decompress_chunk(&self, chunk: Chunk<'_>, output: &mut Vec<u8>) -> Lzma2Result<()> {
+        match chunk {
+            Chunk::Stored { data, .. } => output.extend_from_slice(data),
+            Chunk::Lzma { reset, props, unpacked_len, data } => {
+                if reset != ChunkReset::Dictionary
+                    || props != Some(LzmaProperties::default().to_byte())
+                    || unpacked_len > BLOCK_SIZE
+                {
+                    return Err(Lzma2Error::InputValidationError(format!(
+                        "LZMA2 chunk ({:?} reset, {} bytes) is not a hardware block",
+                        reset, unpacked_len
+                    )));
+                }
+                output.extend_from_slice(&self.device.decompress_block(data, unpacked_len)?);
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Incremental `.lzma` encoder chaining hardware blocks into one stream
+///
+/// A full block is held back until more input arrives, so the final block
+/// is always the one that closes the stream. Once the stream spans several
+/// blocks, the encoder reserves the device until it is finished or dropped,
+/// keeping jobs of other threads from discarding the open stream.
+#[derive(Debug)]
+pub struct LzmaAloneEncoder<'a, D> {
+    device: &'a D,
+    uncompressed_size: Option<u64>,
+    received: u64,
+    pending: Vec<u8>,
+    header_written: bool,
+    stream_open: bool,
+    engine: Option<EngineGuard<'a>>,
+}
+
+impl<D: HardwareCompressionDevice> LzmaAloneEncoder<'_, D> {
+    /// Feed `input` and return the output completed so far
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if the input exceeds the
+    /// announced size, or the device error if a block fails to compress
+    pub fn update(&mut self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        self.received += input.len() as u64;
+        if let Some(size) = self.uncompressed_size.filter(|&size| self.received > size) {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Input exceeds the announced {} bytes", size)
+            ));
+        }
+
+        let mut output = self.header();
+        self.pending.extend_from_slice(input);
+        while self.pending.len() > BLOCK_SIZE {
+            let rest = self.pending.split_off(BLOCK_SIZE);
+            let block = std::mem::replace(&mut self.pending, rest);
+            if self.engine.is_none() {
+                self.engine = Some(self.device.reserve()?);
+            }
+            output.extend_from_slice(&self.device.compress_chained(&block, ChainedBlock {
+                continues: self.stream_open,
+                closes: false,
+                end_marker: false,
+            })?);
+            self.stream_open = true;
+        }
+
+        Ok(output)
+    }
+
+    /// Compress the held-back input and close the stream
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if less input arrived than
+    /// announced, or the device error if the last block fails to compress
+    pub fn finish(mut self) -> Lzma2Result<Vec<u8>> {
+        if let Some(size) = self.uncompressed_size.filter(|&size| self.received != size) {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Received {} of the announced {} bytes", self.received, size)
+            ));
+        }
+
+        let mut output = self.header();
+        let end_marker = self.uncompressed_size.is_none();
+        if self.pending.is_empty() {
+            // Nothing reaches the engine; an empty stream is only a flushed
+            // range coder
+            let mut encoder = LzmaEncoder::new(LzmaProperties::default(), DICT_SIZE);
+            if end_marker {
+                encoder.encode_end_marker();
+            }
+            output.extend_from_slice(&encoder.finish());
+        } else {
+            output.extend_from_slice(&self.device.compress_chained(&self.pending, ChainedBlock {
+                continues: self.stream_open,
+                closes: true,
+                end_marker,
+            })?);
+        }
+
+        Ok(output)
+    }
+
+    /// The header on the first call, nothing afterwards
+    fn header(&mut self) -> Vec<u8> {
+        if std::mem::replace(&mut self.header_written, true) {
+            return Vec::new();
+        }
+
+        LzmaAloneHeader {
+            props: LzmaProperties::default().to_byte(),
+            dict_size: DICT_SIZE,
+            uncompressed_size: self.uncompressed_size,
+        }
+        .to_bytes()
+        .to_vec()
+    }
+}
+
+/// Cursor over a block stream
+struct FrameReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    fn take(&mut self, len: usize) -> Lzma2Result<&'a [u8]> {
+        let bytes = self.input.get(self.pos..self.pos + len).ok_or_else(|| {
+            Lzma2Error::InputValidationError("Truncated block stream".to_string())
+        })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Lzma2Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{SimulatedDevice, SimulatorConfig};
+    use crate::device::simulator::tests::{sample, BlockHook, Hooked};
+
+    #[test]
+    fn test_block_roundtrip() -> Lzma2Result<()> {
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated());
+
+        for len in [0, 1, 1000, BLOCK_SIZE, BLOCK_SIZE + 1, 3 * BLOCK_SIZE + 4321] {
+            let input = sample(len, 7);
+            let compressed = compressor.compress(&input)?;
+            assert_eq!(compressor.decompress(&compressed)?, input, "length {}", len);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lzma2_stream() -> Lzma2Result<()> {
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated());
+
+        for len in [0, 100, 2 * BLOCK_SIZE + 77] {
+            let input = sample(len, 7);
+            let stream = compressor.compress_lzma2(&input)?;
+            assert_eq!(compressor.decompress_lzma2(&stream)?, input);
+
+            // A standard LZMA2 decoder accepts the stream
+            let mut decoded = Vec::new();
+            lzma_rs::lzma2_decompress(&mut &stream[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input, "length {}", len);
+        }
+
+        assert!(compressor.decompress_lzma2(&[0x01, 0x00]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_xz_file() -> Lzma2Result<()> {
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated());
+
+        for len in [0, 100, 2 * BLOCK_SIZE + 77] {
+            let input = sample(len, 7);
+            let file = compressor.compress_xz(&input)?;
+
+            let mut decoded = Vec::new();
+            lzma_rs::xz_decompress(&mut &file[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input, "length {}", len);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lzma_alone() -> Lzma2Result<()> {
+        // A short final block continues the stream on the engine
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated_with(SimulatorConfig {
+            short_blocks: true,
+            chained_streams: true,
+            ..SimulatorConfig::default()
+        }));
+
+        for len in [0, 100, BLOCK_SIZE, 3 * BLOCK_SIZE + 77] {
+            let input = sample(len, 7);
+
+            // Known size in the header
+            let file = compressor.compress_lzma(&input)?;
+            assert_eq!(&file[5..13], &(len as u64).to_le_bytes());
+            let mut decoded = Vec::new();
+            lzma_rs::lzma_decompress(&mut &file[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input, "length {}", len);
+
+            // Unknown size, fed in uneven pieces, ends with the end marker
+            let mut encoder = compressor.lzma_encoder(None);
+            let mut file = Vec::new();
+            for piece in input.chunks(10_000) {
+                file.extend_from_slice(&encoder.update(piece)?);
+            }
+            file.extend_from_slice(&encoder.finish()?);
+            assert_eq!(&file[5..13], &[0xFF; 8]);
+            let mut decoded = Vec::new();
+            lzma_rs::lzma_decompress(&mut &file[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input, "length {}", len);
+        }
+
+        // The announced size must match the input
+        let mut encoder = compressor.lzma_encoder(Some(10));
+        assert!(encoder.update(&sample(11, 7)).is_err());
+        let mut encoder = compressor.lzma_encoder(Some(10));
+        encoder.update(&sample(9, 7))?;
+        assert!(encoder.finish().is_err());
+
+        // Like the RTL, an engine without chaining only takes input that
+        // fits one self-contained block
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated());
+        let file = compressor.compress_lzma(&sample(BLOCK_SIZE, 7))?;
+        let mut decoded = Vec::new();
+        lzma_rs::lzma_decompress(&mut &file[..], &mut decoded).unwrap();
+        assert_eq!(decoded, sample(BLOCK_SIZE, 7));
+        assert!(compressor.compress_lzma(&sample(BLOCK_SIZE + 1, 7)).is_err());
+        assert!(compressor.lzma_encoder(None).finish().is_ok());
+        let mut encoder = compressor.lzma_encoder(None);
+        encoder.update(&sample(BLOCK_SIZE, 7))?;
+        assert!(encoder.finish().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lzma_stream_reserves_device() -> Lzma2Result<()> {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let device = SimulatedDevice::simulated_with(SimulatorConfig {
+            short_blocks: true,
+            chained_streams: true,
+            ..SimulatorConfig::default()
+        });
+        let compressor = BlockCompressor::new(&device);
+        let input = sample(2 * BLOCK_SIZE + 500, 7);
+        let other = sample(BLOCK_SIZE, 3);
+
+        // The first block opens the stream on the engine
+        let mut encoder = compressor.lzma_encoder(None);
+        let mut file = encoder.update(&input[..BLOCK_SIZE + 1])?;
+
+        // Jobs of the same thread that would reset the engine fail, while
+        // reading the counters leaves the stream intact
+        assert!(matches!(device.compress_block(&other), Err(Lzma2Error::EngineBusy(_))));
+        assert!(device.get_performance_metrics().is_ok());
+
+        // A job of another thread waits until the stream is closed
+        let (done, finished) = mpsc::channel();
+        std::thread::scope(|scope| -> Lzma2Result<()> {
+            let job = scope.spawn(|| {
+                let result = device.compress_block(&other);
+                done.send(()).unwrap();
+                result
+            });
+            assert!(finished.recv_timeout(Duration::from_millis(20)).is_err());
+            file.extend_from_slice(&encoder.update(&input[BLOCK_SIZE + 1..])?);
+            file.extend_from_slice(&encoder.finish()?);
+            assert_eq!(job.join().unwrap()?, SimulatedDevice::simulated().compress_block(&other)?);
+            Ok(())
+        })?;
+
+        let mut decoded = Vec::new();
+        lzma_rs::lzma_decompress(&mut &file[..], &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+
+        Ok(())
+    }
+
+    /// Hook corrupting the output of the next `corrupt` blocks
+    struct Corrupting(Mutex<u32>);
+
+    impl BlockHook for Corrupting {
+        fn after(&self, output: &mut Vec<u8>) {
+            let mut corrupt = self.0.lock().unwrap();
+            if *corrupt > 0 {
+                *corrupt -= 1;
+                let middle = output.len() / 2;
+                output[middle] ^= 0x55;
+            }
+        }
+    }
+
+    fn corrupting(corrupt: u32, policy: VerifyPolicy) -> BlockCompressor<Hooked<Corrupting>> {
+        BlockCompressor::new(Hooked::new(Corrupting(Mutex::new(corrupt)))).with_verification(policy)
+    }
+
+    #[test]
+    fn test_verify_after_compress() -> Lzma2Result<()> {
+        let input = sample(2 * BLOCK_SIZE + 500, 7);
+
+        // A single corrupt block is retried on the device
+        let compressor = corrupting(1, VerifyPolicy::default());
+        assert_eq!(compressor.decompress(&compressor.compress(&input)?)?, input);
+        let metrics = compressor.get_performance_metrics()?.verify_metrics;
+        assert_eq!((metrics.blocks_verified, metrics.mismatches, metrics.retries, metrics.stored_blocks), (4, 1, 1, 0));
+
+        // Persistent corruption falls back to stored blocks in every format
+        let compressor = corrupting(u32::MAX, VerifyPolicy { retries: 0, store_on_mismatch: true });
+        let stream = compressor.compress(&input)?;
+        assert_eq!(&stream[8..12], &(BLOCK_SIZE as u32 | STORED_BLOCK).to_le_bytes());
+        assert_eq!(compressor.decompress(&stream)?, input);
+        assert_eq!(compressor.decompress_lzma2(&compressor.compress_lzma2(&input)?)?, input);
+        let mut decoded = Vec::new();
+        lzma_rs::xz_decompress(&mut &compressor.compress_xz(&input)?[..], &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(compressor.get_performance_metrics()?.verify_metrics.stored_blocks, 9);
+
+        // Or fails the job
+        let compressor = corrupting(u32::MAX, VerifyPolicy { retries: 2, store_on_mismatch: false });
+        let err = compressor.compress(&input).unwrap_err();
+        assert!(matches!(err, Lzma2Error::VerificationFailed { offset: 0 }), "{}", err);
+        assert_eq!(compressor.get_performance_metrics()?.verify_metrics.mismatches, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_incompressible_blocks() -> Lzma2Result<()> {
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated());
+
+        // xorshift output does not compress; the text block in between does
+        let mut x = 0x9E37_79B9_7F4A_7C15u64;
+        let mut input: Vec<u8> = (0..BLOCK_SIZE).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        }).collect();
+        input.extend_from_slice(&sample(BLOCK_SIZE, 7));
+        input.extend_from_within(..1000);
+
+        let stream = compressor.compress(&input)?;
+        assert!(stream.len() <= input.len() + 4 + 3 * 8 + 4);
+        assert_eq!(compressor.decompress(&stream)?, input);
+
+        let stream = compressor.compress_lzma2(&input)?;
+        assert!(stream.len() < input.len());
+        assert_eq!(stream[0], lzma2::CONTROL_STORED_RESET);
+        assert_eq!(compressor.decompress_lzma2(&stream)?, input);
+        let mut decoded = Vec::new();
+        lzma_rs::lzma2_decompress(&mut &stream[..], &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+
+        assert_eq!(compressor.get_performance_metrics()?.incompressible_blocks, 4);
+        Ok(())
+    }
+
+    /// Hook padding every block's output to one byte short of a full block
+    struct NearlyFull;
+
+    impl BlockHook for NearlyFull {
+        fn after(&self, output: &mut Vec<u8>) {
+            output.resize(BLOCK_SIZE - 1, 0);
+        }
+    }
+
+    #[test]
+    fn test_store_by_framed_size() -> Lzma2Result<()> {
+        let compressor = BlockCompressor::new(Hooked::new(NearlyFull));
+        let input = sample(BLOCK_SIZE, 7);
+
+        // Block lengths frame both forms alike, so the output is kept
+        let stream = compressor.compress(&input)?;
+        assert_eq!(&stream[8..12], &(BLOCK_SIZE as u32 - 1).to_le_bytes());
+
+        // An LZMA chunk header is 3 bytes longer than a stored one, which
+        // outweighs the byte saved
+        let stream = compressor.compress_lzma2(&input)?;
+        assert_eq!(stream[0], lzma2::CONTROL_STORED_RESET);
+        assert_eq!(stream.len(), lzma2::STORED_CHUNK_HEADER_SIZE + BLOCK_SIZE + 1);
+        assert_eq!(compressor.decompress_lzma2(&stream)?, input);
+        assert_eq!(compressor.get_performance_metrics()?.incompressible_blocks, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_framing() -> Lzma2Result<()> {
+        let compressor = BlockCompressor::new(SimulatedDevice::simulated());
+        let compressed = compressor.compress(&sample(BLOCK_SIZE + 10, 7))?;
+
+        // The final short block records its real length
+        let first_len = u32::from_le_bytes(compressed[8..12].try_into().unwrap()) as usize;
+        let second = 12 + first_len;
+        assert_eq!(&compressed[4..8], &(BLOCK_SIZE as u32).to_le_bytes());
+        assert_eq!(&compressed[second..second + 4], &10u32.to_le_bytes());
+        assert_eq!(&compressed[compressed.len() - 4..], &[0; 4]);
+
+        // Malformed streams are rejected
+        assert!(compressor.decompress(b"LZMA\0\0\0\0").is_err());
+        assert!(compressor.decompress(&compressed[..compressed.len() - 4]).is_err());
+        let mut trailing = compressed.clone();
+        trailing.push(0);
+        assert!(compressor.decompress(&trailing).is_err());
+
+        Ok(())
+    }
+}
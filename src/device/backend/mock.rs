@@ -0,0 +1,169 @@
+//! In-memory mock register backend
+
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+use super::RegisterIo;
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Callback run after every register write with the BAR contents and the
+/// written offset
+pub type WriteHook = Box<dyn FnMut(&mut [u8], u64) + Send>;
+
+/// Mutable state of the mock BAR
+struct MockState {
+    /// BAR contents
+    memory: Vec<u8>,
+
+    /// Optional device behaviour
+    hook: Option<WriteHook>,
+
+    /// Log of 32/64-bit register writes as (offset, value)
+    writes: Vec<(u64, u64)>,
+}
+
+/// In-memory BAR for unit tests
+///
+/// Device behaviour can be scripted with a write hook, e.g. setting a
+/// status bit when the start bit of the control register is written.
+pub struct MockRegisters {
+    state: Mutex<MockState>,
+}
+
+impl fmt::Debug for MockRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockRegisters")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl MockRegisters {
+    /// Create a zero-filled mock BAR of `len` bytes
+    pub fn new(len: usize) -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                memory: vec![0; len],
+                hook: None,
+                writes: Vec::new(),
+            }),
+        }
+    }
+
+    /// Install a hook run after every write
+    pub fn on_write(self, hook: impl FnMut(&mut [u8], u64) + Send + 'static) -> Self {
+        self.lock().hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Access the BAR contents directly, bypassing the write hook
+    pub fn with_memory<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        f(&mut self.lock().memory)
+    }
+
+    /// Register writes performed so far as (offset, value)
+    pub fn write_log(&self) -> Vec<(u64, u64)> {
+        self.lock().writes.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Validate an access and return its byte range
+    fn range(state: &MockState, offset: u64, width: usize, align: usize) -> Lzma2Result<std::ops::Range<usize>> {
+        let start = usize::try_from(offset).map_err(|_| Lzma2Error::DeviceAccessError)?;
+        let end = start.checked_add(width).ok_or(Lzma2Error::DeviceAccessError)?;
+
+        if !start.is_multiple_of(align) || end > state.memory.len() {
+            return Err(Lzma2Error::DeviceAccessError);
+        }
+
+        Ok(start..end)
+    }
+
+    fn write(&self, offset: u64, bytes: &[u8], align: usize, value: Option<u64>) -> Lzma2Result<()> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+        let range = Self::range(state, offset, bytes.len(), align)?;
+
+        state.memory[range].copy_from_slice(bytes);
+        if let Some(value) = value {
+            state.writes.push((offset, value));
+        }
+        if let Some(hook) = state.hook.as_mut() {
+            hook(&mut state.memory, offset);
+        }
+
+        Ok(())
+    }
+}
+
+impl RegisterIo for MockRegisters {
+    fn len(&self) -> usize {
+        self.lock().memory.len()
+    }
+
+    fn read32(&self, offset: u64) -> Lzma2Result<u32> {
+        let state = self.lock();
+        let range = Self::range(&state, offset, 4, 4)?;
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&state.memory[range]);
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn write32(&self, offset: u64, value: u32) -> Lzma2Result<()> {
+        self.write(offset, &value.to_le_bytes(), 4, Some(u64::from(value)))
+    }
+
+    fn read64(&self, offset: u64) -> Lzma2Result<u64> {
+        let state = self.lock();
+        let range = Self::range(&state, offset, 8, 8)?;
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&state.memory[range]);
+        Ok(u64::from_le_bytes(word))
+    }
+
+    fn write64(&self, offset: u64, value: u64) -> Lzma2Result<()> {
+        self.write(offset, &value.to_le_bytes(), 8, Some(value))
+    }
+
+    fn read_burst(&self, offset: u64, buffer: &mut [u8]) -> Lzma2Result<()> {
+        let state = self.lock();
+        let range = Self::range(&state, offset, buffer.len(), 4)?;
+        buffer.copy_from_slice(&state.memory[range]);
+        Ok(())
+    }
+
+    fn write_burst(&self, offset: u64, data: &[u8]) -> Lzma2Result<()> {
+        let mut padded = data.to_vec();
+        padded.resize(data.len().next_multiple_of(4), 0);
+        self.write(offset, &padded, 4, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_hook_and_log() {
+        let regs = MockRegisters::new(64).on_write(|memory, offset| {
+            // Mirror writes to 0x00 into 0x04
+            if offset == 0x00 {
+                let value = memory[0];
+                memory[4] = value;
+            }
+        });
+
+        regs.write32(0x00, 0x5a).unwrap();
+        regs.write64(0x08, 7).unwrap();
+        regs.write_burst(0x10, &[1, 2, 3]).unwrap();
+
+        assert_eq!(regs.read32(0x04).unwrap(), 0x5a);
+        assert_eq!(regs.write_log(), vec![(0x00, 0x5a), (0x08, 7)]);
+        assert_eq!(regs.with_memory(|memory| memory[0x10..0x14].to_vec()), vec![1, 2, 3, 0]);
+        assert!(regs.read64(0x04).is_err());
+        assert!(regs.read32(64).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Register I/O backends for LZMA2 FPGA Compression Driver
+//!
+//! A backend provides raw access to the register BAR of a device.
+//! `PcieDevice` is generic over the backend, so the same driver logic runs
+//! on sysfs, VFIO and UIO mappings as well as on in-memory mocks.
+
+mod mock;
+mod sysfs;
+mod uio;
+mod vfio;
+
+pub use mock::MockRegisters;
+pub use sysfs::SysfsBar;
+pub use uio::{UioMap, DEFAULT_UIO_SYSFS_ROOT};
+#[cfg(test)]
+pub(crate) use vfio::fake::FakeVfio;
+pub use vfio::{DmaMapping, RegionInfo, SystemVfio, VfioDevice, VfioIoctl, VfioRegion};
+
+use crate::device::dma::DmaAllocator;
+use crate::device::mmio::MappedRegion;
+use crate::error::Lzma2Result;
+
+/// Trait defining raw register access to a device BAR
+///
+/// Offsets are byte offsets from the start of the BAR. 32-bit accesses must
+/// be 4-byte aligned and 64-bit accesses 8-byte aligned. Backends are shared
+/// between threads; `PcieDevice` serializes the accesses making up a job.
+pub trait RegisterIo: Send + Sync {
+    /// Size of the register BAR in bytes
+    fn len(&self) -> usize;
+
+    /// Whether the BAR is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read a 32-bit register
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the access is invalid or fails
+    fn read32(&self, offset: u64) -> Lzma2Result<u32>;
+
+    /// Write a 32-bit register
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the access is invalid or fails
+    fn write32(&self, offset: u64, value: u32) -> Lzma2Result<()>;
+
+    /// Read a 64-bit register
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the access is invalid or fails
+    fn read64(&self, offset: u64) -> Lzma2Result<u64>;
+
+    /// Write a 64-bit register
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the access is invalid or fails
+    fn write64(&self, offset: u64, value: u64) -> Lzma2Result<()>;
+
+    /// Read a burst of bytes starting at a 32-bit aligned offset
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the access is invalid or fails
+    fn read_burst(&self, offset: u64, buffer: &mut [u8]) -> Lzma2Result<()>;
+
+    /// Write a burst of bytes starting at a 32-bit aligned offset
+    ///
+    /// A trailing partial word is zero-padded.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the access is invalid or fails
+    fn write_burst(&self, offset: u64, data: &[u8]) -> Lzma2Result<()>;
+
+    /// Allocator for buffers the device can reach by DMA
+    ///
+    /// Backends without an IOMMU mapping return `None`, which limits the
+    /// device to `TransferStrategy::Mmio`.
+    fn dma_allocator(&self) -> Option<&dyn DmaAllocator> {
+        None
+    }
+}
+
+impl RegisterIo for MappedRegion {
+    fn len(&self) -> usize {
+        MappedRegion::len(self)
+    }
+
+    fn read32(&self, offset: u64) -> Lzma2Result<u32> {
+        MappedRegion::read32(self, offset)
+    }
+
+    fn write32(&self, offset: u64, value: u32) -> Lzma2Result<()> {
+        MappedRegion::write32(self, offset, value)
+    }
+
+    fn read64(&self, offset: u64) -> Lzma2Result<u64> {
+        MappedRegion::read64(self, offset)
+    }
+
+    fn write64(&self, offset: u64, value: u64) -> Lzma2Result<()> {
+        MappedRegion::write64(self, offset, value)
+    }
+
+    fn read_burst(&self, offset: u64, buffer: &mut [u8]) -> Lzma2Result<()> {
+        self.read_bytes(offset, buffer)
+    }
+
+    fn write_burst(&self, offset: u64, data: &[u8]) -> Lzma2Result<()> {
+        self.write_bytes(offset, data)
+    }
+}
+
+/// Implement `RegisterIo` for a newtype by delegating to its `region` field
+///
+/// Extra trait items, e.g. a `dma_allocator` override, may follow the type.
+macro_rules! delegate_register_io {
+    ($backend:ty $(, $($extra:tt)*)?) => {
+        impl $crate::device::backend::RegisterIo for $backend {
+            fn len(&self) -> usize {
+                self.region.len()
+            }
+
+            fn read32(&self, offset: u64) -> $crate::error::Lzma2Result<u32> {
+                self.region.read32(offset)
+            }
+
+            fn write32(&self, offset: u64, value: u32) -> $crate::error::Lzma2Result<()> {
+                self.region.write32(offset, value)
+            }
+
+            fn read64(&self, offset: u64) -> $crate::error::Lzma2Result<u64> {
+                self.region.read64(offset)
+            }
+
+            fn write64(&self, offset: u64, value: u64) -> $crate::error::Lzma2Result<()> {
+                self.region.write64(offset, value)
+            }
+
+            fn read_burst(&self, offset: u64, buffer: &mut [u8]) -> $crate::error::Lzma2Result<()> {
+                self.region.read_bytes(offset, buffer)
+            }
+
+            fn write_burst(&self, offset: u64, data: &[u8]) -> $crate::error::Lzma2Result<()> {
+                self.region.write_bytes(offset, data)
+            }
+
+            $($($extra)*)?
+        }
+    };
+}
+
+pub(crate) use delegate_register_io;
@@ -0,0 +1,33 @@
+//! sysfs `resourceN` register backend
+
+use super::delegate_register_io;
+use crate::device::mmio::MappedRegion;
+use crate::device::sysfs::PciFunction;
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// BAR mapped through its sysfs `resourceN` file
+#[derive(Debug)]
+pub struct SysfsBar {
+    /// Mapping of the resource file
+    region: MappedRegion,
+}
+
+impl SysfsBar {
+    /// Map BAR `bar_index` of an enumerated PCI function
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the BAR is not implemented or cannot be mapped
+    pub fn open(function: &PciFunction, bar_index: usize) -> Lzma2Result<Self> {
+        if function.bar_size(bar_index).is_none() {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("BAR{} of {} is not implemented", bar_index, function.address)
+            ));
+        }
+
+        Ok(Self {
+            region: MappedRegion::open(&function.resource_path(bar_index))?,
+        })
+    }
+}
+
+delegate_register_io!(SysfsBar);
@@ -0,0 +1,109 @@
+//! UIO register backend
+
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use super::delegate_register_io;
+use crate::device::mmio::MappedRegion;
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Default location of the UIO class directory in sysfs
+pub const DEFAULT_UIO_SYSFS_ROOT: &str = "/sys/class/uio";
+
+/// Memory map exported by a UIO driver (`/dev/uioN`, map M)
+#[derive(Debug)]
+pub struct UioMap {
+    /// Mapping of the UIO memory region
+    region: MappedRegion,
+}
+
+impl UioMap {
+    /// Map region `map_index` of the UIO device `name` (e.g. `uio0`)
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the map does not exist or cannot be mapped
+    pub fn open(name: &str, map_index: usize) -> Lzma2Result<Self> {
+        Self::open_in(Path::new(DEFAULT_UIO_SYSFS_ROOT), Path::new("/dev"), name, map_index)
+    }
+
+    /// Map a UIO region using explicit sysfs and device directories
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the map does not exist or cannot be mapped
+    pub fn open_in(
+        sysfs_root: &Path,
+        dev_root: &Path,
+        name: &str,
+        map_index: usize,
+    ) -> Lzma2Result<Self> {
+        let size_path = sysfs_root.join(name)
+            .join("maps")
+            .join(format!("map{}", map_index))
+            .join("size");
+
+        let size = fs::read_to_string(&size_path)
+            .map_err(|e| Lzma2Error::DeviceInitError(
+                format!("Failed to read {}: {}", size_path.display(), e)
+            ))?;
+        let size = size.trim();
+        let size = usize::from_str_radix(size.strip_prefix("0x").unwrap_or(size), 16)
+            .map_err(|_| Lzma2Error::DeviceInitError(
+                format!("Invalid UIO map size: {}", size)
+            ))?;
+
+        let dev_path = dev_root.join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&dev_path)
+            .map_err(|e| Lzma2Error::DeviceInitError(
+                format!("Failed to open {}: {}", dev_path.display(), e)
+            ))?;
+
+        // UIO selects map N through an offset of N pages
+        let offset = map_index as u64 * page_size();
+
+        Ok(Self {
+            region: MappedRegion::map(file, offset, size)?,
+        })
+    }
+}
+
+delegate_register_io!(UioMap);
+
+/// System page size
+fn page_size() -> u64 {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u64::try_from(size).unwrap_or(4096)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::backend::RegisterIo;
+
+    #[test]
+    fn test_open_fake_uio() {
+        let root = tempfile::tempdir().unwrap();
+        let sysfs = root.path().join("class");
+        let dev = root.path().join("dev");
+
+        let map1 = sysfs.join("uio0").join("maps").join("map1");
+        fs::create_dir_all(&map1).unwrap();
+        fs::create_dir_all(&dev).unwrap();
+        fs::write(map1.join("size"), "0x2000\n").unwrap();
+
+        let file = fs::File::create(dev.join("uio0")).unwrap();
+        file.set_len(page_size() + 0x2000).unwrap();
+
+        let map = UioMap::open_in(&sysfs, &dev, "uio0", 1).unwrap();
+        assert_eq!(map.len(), 0x2000);
+
+        map.write32(0x1ffc, 0x1234_5678).unwrap();
+        assert_eq!(map.read32(0x1ffc).unwrap(), 0x1234_5678);
+        assert!(map.read32(0x2000).is_err());
+
+        assert!(UioMap::open_in(&sysfs, &dev, "uio0", 0).is_err());
+    }
+}
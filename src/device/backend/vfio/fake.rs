@@ -0,0 +1,174 @@
+//! Fake VFIO container for unit tests
+
+use std::fs::File;
+use std::os::fd::RawFd;
+use std::sync::{Mutex, MutexGuard};
+
+use super::ioctl::{
+    DmaMapping, RegionInfo, VfioIoctl, VFIO_API_VERSION, VFIO_GROUP_FLAGS_VIABLE,
+    VFIO_REGION_INFO_FLAG_MMAP, VFIO_TYPE1V2_IOMMU, VFIO_TYPE1_IOMMU,
+};
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Recorded state of the fake container
+#[derive(Debug, Default)]
+pub(crate) struct FakeState {
+    /// Groups opened, in order
+    pub(crate) groups: Vec<u32>,
+
+    /// Whether a group has been attached to the container
+    pub(crate) attached: bool,
+
+    /// IOMMU model selected with `VFIO_SET_IOMMU`
+    pub(crate) iommu: Option<u32>,
+
+    /// Device names requested from the group
+    pub(crate) devices: Vec<String>,
+
+    /// Live DMA mappings
+    pub(crate) mappings: Vec<DmaMapping>,
+
+    /// IRQ indexes with an eventfd trigger
+    pub(crate) irqs: Vec<u32>,
+}
+
+/// In-process stand-in for the kernel VFIO interface
+///
+/// Device file descriptors are temporary files sized to cover the
+/// configured regions, so BAR mappings work unchanged.
+#[derive(Debug)]
+pub(crate) struct FakeVfio {
+    regions: Vec<RegionInfo>,
+    viable: bool,
+    type1v2: bool,
+    msi: bool,
+    state: Mutex<FakeState>,
+}
+
+impl FakeVfio {
+    /// A viable group whose device has one mappable BAR of `bar_size` bytes
+    pub(crate) fn new(bar_size: u64) -> Self {
+        Self {
+            regions: vec![RegionInfo {
+                flags: VFIO_REGION_INFO_FLAG_MMAP | 0x3,
+                size: bar_size,
+                offset: 0,
+            }],
+            viable: true,
+            type1v2: true,
+            msi: true,
+            state: Mutex::new(FakeState::default()),
+        }
+    }
+
+    /// Report the group as not viable
+    pub(crate) fn not_viable(mut self) -> Self {
+        self.viable = false;
+        self
+    }
+
+    /// Only support the original Type1 IOMMU model
+    pub(crate) fn type1_only(mut self) -> Self {
+        self.type1v2 = false;
+        self
+    }
+
+    /// Reject MSI configuration
+    pub(crate) fn without_msi(mut self) -> Self {
+        self.msi = false;
+        self
+    }
+
+    /// Recorded state
+    pub(crate) fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn fake_error(message: &str) -> Lzma2Error {
+    Lzma2Error::DeviceInitError(message.to_string())
+}
+
+impl VfioIoctl for FakeVfio {
+    fn open_container(&self) -> Lzma2Result<File> {
+        tempfile::tempfile().map_err(|e| fake_error(&e.to_string()))
+    }
+
+    fn open_group(&self, group: u32) -> Lzma2Result<File> {
+        self.state().groups.push(group);
+        tempfile::tempfile().map_err(|e| fake_error(&e.to_string()))
+    }
+
+    fn api_version(&self, _container: &File) -> Lzma2Result<i32> {
+        Ok(VFIO_API_VERSION)
+    }
+
+    fn check_extension(&self, _container: &File, extension: u32) -> Lzma2Result<bool> {
+        Ok(extension == VFIO_TYPE1_IOMMU || (extension == VFIO_TYPE1V2_IOMMU && self.type1v2))
+    }
+
+    fn group_status(&self, _group: &File) -> Lzma2Result<u32> {
+        Ok(if self.viable { VFIO_GROUP_FLAGS_VIABLE } else { 0 })
+    }
+
+    fn set_container(&self, _group: &File, _container: &File) -> Lzma2Result<()> {
+        self.state().attached = true;
+        Ok(())
+    }
+
+    fn set_iommu(&self, _container: &File, iommu_type: u32) -> Lzma2Result<()> {
+        let mut state = self.state();
+        if !state.attached {
+            return Err(fake_error("VFIO_SET_IOMMU before VFIO_GROUP_SET_CONTAINER"));
+        }
+        state.iommu = Some(iommu_type);
+        Ok(())
+    }
+
+    fn device_fd(&self, _group: &File, name: &str) -> Lzma2Result<File> {
+        if self.state().iommu.is_none() {
+            return Err(fake_error("VFIO_GROUP_GET_DEVICE_FD before VFIO_SET_IOMMU"));
+        }
+        self.state().devices.push(name.to_string());
+
+        let file = tempfile::tempfile().map_err(|e| fake_error(&e.to_string()))?;
+        let len = self.regions.iter().map(|region| region.offset + region.size).max().unwrap_or(0);
+        file.set_len(len).map_err(|e| fake_error(&e.to_string()))?;
+        Ok(file)
+    }
+
+    fn region_info(&self, _device: &File, index: u32) -> Lzma2Result<RegionInfo> {
+        self.regions.get(index as usize).copied()
+            .ok_or_else(|| fake_error("invalid region index"))
+    }
+
+    fn map_dma(&self, _container: &File, mapping: &DmaMapping) -> Lzma2Result<()> {
+        let mut state = self.state();
+        let overlaps = state.mappings.iter().any(|existing| {
+            mapping.iova < existing.iova + existing.size && existing.iova < mapping.iova + mapping.size
+        });
+        if overlaps || state.iommu.is_none() {
+            return Err(fake_error("VFIO_IOMMU_MAP_DMA rejected"));
+        }
+        state.mappings.push(*mapping);
+        Ok(())
+    }
+
+    fn unmap_dma(&self, _container: &File, iova: u64, size: u64) -> Lzma2Result<()> {
+        let mut state = self.state();
+        let before = state.mappings.len();
+        state.mappings.retain(|mapping| !(mapping.iova == iova && mapping.size == size));
+        if state.mappings.len() == before {
+            return Err(fake_error("VFIO_IOMMU_UNMAP_DMA of unknown range"));
+        }
+        Ok(())
+    }
+
+    fn set_irq_eventfd(&self, _device: &File, index: u32, _vector: u32, _eventfd: RawFd) -> Lzma2Result<()> {
+        if !self.msi {
+            return Err(fake_error("VFIO_DEVICE_SET_IRQS not supported"));
+        }
+        self.state().irqs.push(index);
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+//! VFIO ioctl layer
+//!
+//! `VfioIoctl` covers the container, group and device operations the
+//! driver needs. `SystemVfio` issues the real ioctls from
+//! `<linux/vfio.h>`; tests substitute a fake container.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Version returned by `VFIO_GET_API_VERSION`
+pub const VFIO_API_VERSION: i32 = 0;
+
+/// Type1 IOMMU model
+pub const VFIO_TYPE1_IOMMU: u32 = 1;
+
+/// Type1v2 IOMMU model
+pub const VFIO_TYPE1V2_IOMMU: u32 = 3;
+
+/// Group status: all devices in the group are bound to VFIO
+pub const VFIO_GROUP_FLAGS_VIABLE: u32 = 1 << 0;
+
+/// Region info: region supports mmap
+pub const VFIO_REGION_INFO_FLAG_MMAP: u32 = 1 << 2;
+
+/// DMA map: device may read the buffer
+pub const VFIO_DMA_MAP_FLAG_READ: u32 = 1 << 0;
+
+/// DMA map: device may write the buffer
+pub const VFIO_DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+
+/// IRQ index of MSI on a vfio-pci device
+pub const VFIO_PCI_MSI_IRQ_INDEX: u32 = 1;
+
+/// IRQ index of MSI-X on a vfio-pci device
+pub const VFIO_PCI_MSIX_IRQ_INDEX: u32 = 2;
+
+/// Location and size of a device region (`struct vfio_region_info`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionInfo {
+    /// `VFIO_REGION_INFO_FLAG_*`
+    pub flags: u32,
+
+    /// Region size in bytes
+    pub size: u64,
+
+    /// Offset of the region within the device file descriptor
+    pub offset: u64,
+}
+
+/// An IOMMU mapping (`struct vfio_iommu_type1_dma_map`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaMapping {
+    /// Process virtual address of the buffer
+    pub vaddr: u64,
+
+    /// I/O virtual address seen by the device
+    pub iova: u64,
+
+    /// Mapping size in bytes
+    pub size: u64,
+
+    /// `VFIO_DMA_MAP_FLAG_*`
+    pub flags: u32,
+}
+
+/// VFIO operations used by the driver
+pub trait VfioIoctl: Send + Sync {
+    /// Open the VFIO container (`/dev/vfio/vfio`)
+    fn open_container(&self) -> Lzma2Result<File>;
+
+    /// Open an IOMMU group (`/dev/vfio/<group>`)
+    fn open_group(&self, group: u32) -> Lzma2Result<File>;
+
+    /// `VFIO_GET_API_VERSION`
+    fn api_version(&self, container: &File) -> Lzma2Result<i32>;
+
+    /// `VFIO_CHECK_EXTENSION`
+    fn check_extension(&self, container: &File, extension: u32) -> Lzma2Result<bool>;
+
+    /// `VFIO_GROUP_GET_STATUS`, returning the group flags
+    fn group_status(&self, group: &File) -> Lzma2Result<u32>;
+
+    /// `VFIO_GROUP_SET_CONTAINER`
+    fn set_container(&self, group: &File, container: &File) -> Lzma2Result<()>;
+
+    /// `VFIO_SET_IOMMU`
+    fn set_iommu(&self, container: &File, iommu_type: u32) -> Lzma2Result<()>;
+
+    /// `VFIO_GROUP_GET_DEVICE_FD`
+    fn device_fd(&self, group: &File, name: &str) -> Lzma2Result<File>;
+
+    /// `VFIO_DEVICE_GET_REGION_INFO`
+    fn region_info(&self, device: &File, index: u32) -> Lzma2Result<RegionInfo>;
+
+    /// `VFIO_IOMMU_MAP_DMA`
+    fn map_dma(&self, container: &File, mapping: &DmaMapping) -> Lzma2Result<()>;
+
+    /// `VFIO_IOMMU_UNMAP_DMA`
+    fn unmap_dma(&self, container: &File, iova: u64, size: u64) -> Lzma2Result<()>;
+
+    /// `VFIO_DEVICE_SET_IRQS` with an eventfd trigger for one vector
+    fn set_irq_eventfd(&self, device: &File, index: u32, vector: u32, eventfd: RawFd) -> Lzma2Result<()>;
+}
+
+/// `_IO(VFIO_TYPE, VFIO_BASE + nr)`
+const fn vfio_io(nr: u64) -> u64 {
+    (b';' as u64) << 8 | (100 + nr)
+}
+
+const VFIO_GET_API_VERSION: u64 = vfio_io(0);
+const VFIO_CHECK_EXTENSION: u64 = vfio_io(1);
+const VFIO_SET_IOMMU: u64 = vfio_io(2);
+const VFIO_GROUP_GET_STATUS: u64 = vfio_io(3);
+const VFIO_GROUP_SET_CONTAINER: u64 = vfio_io(4);
+const VFIO_GROUP_GET_DEVICE_FD: u64 = vfio_io(6);
+const VFIO_DEVICE_GET_REGION_INFO: u64 = vfio_io(8);
+const VFIO_DEVICE_SET_IRQS: u64 = vfio_io(10);
+const VFIO_IOMMU_MAP_DMA: u64 = vfio_io(13);
+const VFIO_IOMMU_UNMAP_DMA: u64 = vfio_io(14);
+
+const VFIO_IRQ_SET_DATA_EVENTFD: u32 = 1 << 2;
+const VFIO_IRQ_SET_ACTION_TRIGGER: u32 = 1 << 5;
+
+#[repr(C)]
+struct GroupStatus {
+    argsz: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct RegionInfoRaw {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct DmaMap {
+    argsz: u32,
+    flags: u32,
+    vaddr: u64,
+    iova: u64,
+    size: u64,
+}
+
+#[repr(C)]
+struct DmaUnmap {
+    argsz: u32,
+    flags: u32,
+    iova: u64,
+    size: u64,
+}
+
+/// `struct vfio_irq_set` carrying a single eventfd
+#[repr(C)]
+struct IrqSetEventFd {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    start: u32,
+    count: u32,
+    eventfd: i32,
+}
+
+/// `argsz` of an ioctl argument
+fn argsz<T>() -> u32 {
+    mem::size_of::<T>() as u32
+}
+
+/// Issue an ioctl, mapping failures to `Lzma2Error`
+fn ioctl(fd: RawFd, request: u64, arg: libc::c_ulong, name: &str) -> Lzma2Result<libc::c_int> {
+    // SAFETY: callers pass a request matching the pointed-to argument type
+    let ret = unsafe { libc::ioctl(fd, request as _, arg) };
+    if ret < 0 {
+        return Err(Lzma2Error::DeviceInitError(
+            format!("{} failed: {}", name, io::Error::last_os_error())
+        ));
+    }
+    Ok(ret)
+}
+
+/// Kernel VFIO interface
+#[derive(Debug, Clone)]
+pub struct SystemVfio {
+    dev_root: PathBuf,
+}
+
+impl Default for SystemVfio {
+    fn default() -> Self {
+        Self::new("/dev/vfio")
+    }
+}
+
+impl SystemVfio {
+    /// Use the VFIO device nodes below `dev_root`
+    pub fn new(dev_root: impl Into<PathBuf>) -> Self {
+        Self { dev_root: dev_root.into() }
+    }
+
+    fn open_node(&self, name: &str) -> Lzma2Result<File> {
+        let path = self.dev_root.join(name);
+        open_rw(&path)
+    }
+}
+
+fn open_rw(path: &Path) -> Lzma2Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| Lzma2Error::DeviceInitError(
+            format!("Failed to open {}: {}", path.display(), e)
+        ))
+}
+
+impl VfioIoctl for SystemVfio {
+    fn open_container(&self) -> Lzma2Result<File> {
+        self.open_node("vfio")
+    }
+
+    fn open_group(&self, group: u32) -> Lzma2Result<File> {
+        self.open_node(&group.to_string())
+    }
+
+    fn api_version(&self, container: &File) -> Lzma2Result<i32> {
+        ioctl(container.as_raw_fd(), VFIO_GET_API_VERSION, 0, "VFIO_GET_API_VERSION")
+    }
+
+    fn check_extension(&self, container: &File, extension: u32) -> Lzma2Result<bool> {
+        let ret = ioctl(
+            container.as_raw_fd(),
+            VFIO_CHECK_EXTENSION,
+            libc::c_ulong::from(extension),
+            "VFIO_CHECK_EXTENSION",
+        )?;
+        Ok(ret > 0)
+    }
+
+    fn group_status(&self, group: &File) -> Lzma2Result<u32> {
+        let mut status = GroupStatus { argsz: argsz::<GroupStatus>(), flags: 0 };
+        ioctl(
+            group.as_raw_fd(),
+            VFIO_GROUP_GET_STATUS,
+            &mut status as *mut GroupStatus as libc::c_ulong,
+            "VFIO_GROUP_GET_STATUS",
+        )?;
+        Ok(status.flags)
+    }
+
+    fn set_container(&self, group: &File, container: &File) -> Lzma2Result<()> {
+        let fd: libc::c_int = container.as_raw_fd();
+        ioctl(
+            group.as_raw_fd(),
+            VFIO_GROUP_SET_CONTAINER,
+            &fd as *const libc::c_int as libc::c_ulong,
+            "VFIO_GROUP_SET_CONTAINER",
+        )?;
+        Ok(())
+    }
+
+    fn set_iommu(&self, container: &File, iommu_type: u32) -> Lzma2Result<()> {
+        ioctl(
+            container.as_raw_fd(),
+            VFIO_SET_IOMMU,
+            libc::c_ulong::from(iommu_type),
+            "VFIO_SET_IOMMU",
+        )?;
+        Ok(())
+    }
+
+    fn device_fd(&self, group: &File, name: &str) -> Lzma2Result<File> {
+        let name = CString::new(name).map_err(|_| Lzma2Error::InputValidationError(
+            format!("Invalid VFIO device name: {}", name)
+        ))?;
+        let fd = ioctl(
+            group.as_raw_fd(),
+            VFIO_GROUP_GET_DEVICE_FD,
+            name.as_ptr() as libc::c_ulong,
+            "VFIO_GROUP_GET_DEVICE_FD",
+        )?;
+
+        // SAFETY: the kernel returned a new descriptor we now own
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn region_info(&self, device: &File, index: u32) -> Lzma2Result<RegionInfo> {
+        let mut info = RegionInfoRaw {
+            argsz: argsz::<RegionInfoRaw>(),
+            flags: 0,
+            index,
+            cap_offset: 0,
+            size: 0,
+            offset: 0,
+        };
+        ioctl(
+            device.as_raw_fd(),
+            VFIO_DEVICE_GET_REGION_INFO,
+            &mut info as *mut RegionInfoRaw as libc::c_ulong,
+            "VFIO_DEVICE_GET_REGION_INFO",
+        )?;
+
+        Ok(RegionInfo {
+            flags: info.flags,
+            size: info.size,
+            offset: info.offset,
+        })
+    }
+
+    fn map_dma(&self, container: &File, mapping: &DmaMapping) -> Lzma2Result<()> {
+        let map = DmaMap {
+            argsz: argsz::<DmaMap>(),
+            flags: mapping.flags,
+            vaddr: mapping.vaddr,
+            iova: mapping.iova,
+            size: mapping.size,
+        };
+        ioctl(
+            container.as_raw_fd(),
+            VFIO_IOMMU_MAP_DMA,
+            &map as *const DmaMap as libc::c_ulong,
+            "VFIO_IOMMU_MAP_DMA",
+        )?;
+        Ok(())
+    }
+
+    fn unmap_dma(&self, container: &File, iova: u64, size: u64) -> Lzma2Result<()> {
+        let mut unmap = DmaUnmap {
+            argsz: argsz::<DmaUnmap>(),
+            flags: 0,
+            iova,
+            size,
+        };
+        ioctl(
+            container.as_raw_fd(),
+            VFIO_IOMMU_UNMAP_DMA,
+            &mut unmap as *mut DmaUnmap as libc::c_ulong,
+            "VFIO_IOMMU_UNMAP_DMA",
+        )?;
+        Ok(())
+    }
+
+    fn set_irq_eventfd(&self, device: &File, index: u32, vector: u32, eventfd: RawFd) -> Lzma2Result<()> {
+        let set = IrqSetEventFd {
+            argsz: argsz::<IrqSetEventFd>(),
+            flags: VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            index,
+            start: vector,
+            count: 1,
+            eventfd,
+        };
+        ioctl(
+            device.as_raw_fd(),
+            VFIO_DEVICE_SET_IRQS,
+            &set as *const IrqSetEventFd as libc::c_ulong,
+            "VFIO_DEVICE_SET_IRQS",
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // Values from <linux/vfio.h>
+        assert_eq!(VFIO_GET_API_VERSION, 0x3b64);
+        assert_eq!(VFIO_GROUP_GET_DEVICE_FD, 0x3b6a);
+        assert_eq!(VFIO_IOMMU_MAP_DMA, 0x3b71);
+        assert_eq!(VFIO_IOMMU_UNMAP_DMA, 0x3b72);
+        assert_eq!(mem::size_of::<RegionInfoRaw>(), 32);
+        assert_eq!(mem::size_of::<DmaMap>(), 32);
+    }
+}
@@ -0,0 +1,378 @@
+//! VFIO register backend
+//!
+//! `VfioDevice` opens the IOMMU group of a PCI function, attaches it to a
+//! container and hands out BAR mappings and IOMMU-mapped DMA buffers. All
+//! kernel interaction goes through `VfioIoctl`.
+
+mod ioctl;
+#[cfg(test)]
+pub(crate) mod fake;
+
+pub use ioctl::{DmaMapping, RegionInfo, SystemVfio, VfioIoctl};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::delegate_register_io;
+use crate::device::dma::{DmaAllocator, DmaBuffer, DmaMapper, IovaAllocator};
+use crate::device::interrupt::EventFdInterrupt;
+use crate::device::mmio::MappedRegion;
+use crate::device::sysfs::{PciAddress, DEFAULT_SYSFS_ROOT};
+use crate::error::{Lzma2Error, Lzma2Result};
+use ioctl::{
+    VFIO_API_VERSION, VFIO_DMA_MAP_FLAG_READ, VFIO_DMA_MAP_FLAG_WRITE, VFIO_GROUP_FLAGS_VIABLE,
+    VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_MSI_IRQ_INDEX, VFIO_REGION_INFO_FLAG_MMAP,
+    VFIO_TYPE1V2_IOMMU, VFIO_TYPE1_IOMMU,
+};
+
+/// First I/O virtual address handed out for DMA buffers
+///
+/// Starting above 4GB keeps buffers clear of the MSI doorbell window.
+const IOVA_BASE: u64 = 1 << 32;
+
+/// BAR mapped through a VFIO device file descriptor
+#[derive(Debug)]
+pub struct VfioRegion {
+    /// Mapping of the VFIO region
+    region: MappedRegion,
+
+    /// Device the region belongs to, if opened through `VfioDevice`
+    device: Option<VfioDevice>,
+}
+
+impl VfioRegion {
+    /// Map a region of a VFIO device at the offset and size reported by
+    /// `VFIO_DEVICE_GET_REGION_INFO`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the region cannot be mapped
+    pub fn map(device: &File, offset: u64, size: usize) -> Lzma2Result<Self> {
+        let file = device.try_clone().map_err(|e| Lzma2Error::DeviceInitError(
+            format!("Failed to duplicate VFIO device fd: {}", e)
+        ))?;
+
+        Ok(Self {
+            region: MappedRegion::map(file, offset, size)?,
+            device: None,
+        })
+    }
+
+    /// VFIO device the region belongs to, for DMA buffer allocation
+    pub fn device(&self) -> Option<&VfioDevice> {
+        self.device.as_ref()
+    }
+}
+
+delegate_register_io!(VfioRegion,
+    fn dma_allocator(&self) -> Option<&dyn DmaAllocator> {
+        self.device.as_ref().map(|device| device as &dyn DmaAllocator)
+    }
+);
+
+/// VFIO container with its IOVA allocator
+struct Container {
+    ioctl: Arc<dyn VfioIoctl>,
+    file: File,
+    iova: IovaAllocator,
+}
+
+impl DmaMapper for Container {
+    fn map(&self, vaddr: u64, size: u64) -> Lzma2Result<u64> {
+        let iova = self.iova.allocate(size);
+        let mapping = DmaMapping {
+            vaddr,
+            iova,
+            size,
+            flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+        };
+
+        if let Err(e) = self.ioctl.map_dma(&self.file, &mapping) {
+            self.iova.release(iova);
+            return Err(e);
+        }
+        Ok(iova)
+    }
+
+    fn unmap(&self, iova: u64, size: u64) -> Lzma2Result<()> {
+        self.ioctl.unmap_dma(&self.file, iova, size)?;
+        self.iova.release(iova);
+        Ok(())
+    }
+}
+
+struct DeviceInner {
+    container: Arc<Container>,
+    _group: File,
+    file: File,
+    address: PciAddress,
+}
+
+/// A PCI function opened through VFIO
+#[derive(Clone)]
+pub struct VfioDevice {
+    inner: Arc<DeviceInner>,
+}
+
+impl fmt::Debug for VfioDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VfioDevice")
+            .field("address", &self.inner.address)
+            .finish()
+    }
+}
+
+impl VfioDevice {
+    /// Open a PCI function bound to `vfio-pci`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the group is not viable or any VFIO step fails
+    pub fn open(address: PciAddress) -> Lzma2Result<Self> {
+        Self::open_with(Arc::new(SystemVfio::default()), Path::new(DEFAULT_SYSFS_ROOT), address)
+    }
+
+    /// Open a PCI function through an explicit ioctl layer and sysfs root
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the group is not viable or any VFIO step fails
+    pub fn open_with(
+        ioctl: Arc<dyn VfioIoctl>,
+        sysfs_root: &Path,
+        address: PciAddress,
+    ) -> Lzma2Result<Self> {
+        let group_id = iommu_group(sysfs_root, address)?;
+
+        let container = ioctl.open_container()?;
+        let version = ioctl.api_version(&container)?;
+        if version != VFIO_API_VERSION {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("Unsupported VFIO API version {}", version)
+            ));
+        }
+
+        let iommu_type = if ioctl.check_extension(&container, VFIO_TYPE1V2_IOMMU)? {
+            VFIO_TYPE1V2_IOMMU
+        } else if ioctl.check_extension(&container, VFIO_TYPE1_IOMMU)? {
+            VFIO_TYPE1_IOMMU
+        } else {
+            return Err(Lzma2Error::DeviceInitError(
+                "VFIO container does not support a Type1 IOMMU".to_string()
+            ));
+        };
+
+        let group = ioctl.open_group(group_id)?;
+        if ioctl.group_status(&group)? & VFIO_GROUP_FLAGS_VIABLE == 0 {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("IOMMU group {} is not viable; bind all its devices to vfio-pci", group_id)
+            ));
+        }
+
+        ioctl.set_container(&group, &container)?;
+        ioctl.set_iommu(&container, iommu_type)?;
+        let file = ioctl.device_fd(&group, &address.to_string())?;
+
+        Ok(Self {
+            inner: Arc::new(DeviceInner {
+                container: Arc::new(Container {
+                    ioctl,
+                    file: container,
+                    iova: IovaAllocator::new(IOVA_BASE),
+                }),
+                _group: group,
+                file,
+                address,
+            }),
+        })
+    }
+
+    /// Bus/device/function address of the device
+    pub fn address(&self) -> PciAddress {
+        self.inner.address
+    }
+
+    /// Location and size of BAR `index`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the region does not exist
+    pub fn region_info(&self, index: u32) -> Lzma2Result<RegionInfo> {
+        self.inner.container.ioctl.region_info(&self.inner.file, index)
+    }
+
+    /// Map BAR `index` through the VFIO region API
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the BAR is absent or cannot be mapped
+    pub fn map_bar(&self, index: u32) -> Lzma2Result<VfioRegion> {
+        let info = self.region_info(index)?;
+        if info.size == 0 || info.flags & VFIO_REGION_INFO_FLAG_MMAP == 0 {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("BAR{} of {} is not mappable", index, self.inner.address)
+            ));
+        }
+
+        let mut region = VfioRegion::map(&self.inner.file, info.offset, info.size as usize)?;
+        region.device = Some(self.clone());
+        Ok(region)
+    }
+
+    /// Allocate a zeroed buffer of at least `size` bytes and map it for DMA
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if allocation or `VFIO_IOMMU_MAP_DMA` fails
+    pub fn alloc_dma(&self, size: usize) -> Lzma2Result<DmaBuffer> {
+        DmaBuffer::new(self.inner.container.clone(), size)
+    }
+
+    /// Route MSI vector `vector` to a new eventfd
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the device does not support MSI
+    pub fn enable_msi(&self, vector: u32) -> Lzma2Result<EventFdInterrupt> {
+        self.enable_irq(VFIO_PCI_MSI_IRQ_INDEX, vector)
+    }
+
+    /// Route MSI-X vector `vector` to a new eventfd
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the device does not support MSI-X
+    pub fn enable_msix(&self, vector: u32) -> Lzma2Result<EventFdInterrupt> {
+        self.enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, vector)
+    }
+
+    fn enable_irq(&self, index: u32, vector: u32) -> Lzma2Result<EventFdInterrupt> {
+        let interrupt = EventFdInterrupt::new()?;
+        self.inner.container.ioctl.set_irq_eventfd(
+            &self.inner.file,
+            index,
+            vector,
+            interrupt.as_raw_fd(),
+        )?;
+        Ok(interrupt)
+    }
+}
+
+impl DmaAllocator for VfioDevice {
+    fn alloc_dma(&self, size: usize) -> Lzma2Result<DmaBuffer> {
+        VfioDevice::alloc_dma(self, size)
+    }
+}
+
+/// IOMMU group number of a PCI function
+fn iommu_group(sysfs_root: &Path, address: PciAddress) -> Lzma2Result<u32> {
+    let link = sysfs_root.join(address.to_string()).join("iommu_group");
+    let target = fs::read_link(&link).map_err(|e| Lzma2Error::DeviceInitError(
+        format!("{} has no IOMMU group ({}): {}", address, link.display(), e)
+    ))?;
+
+    target.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse().ok())
+        .ok_or_else(|| Lzma2Error::DeviceInitError(
+            format!("Invalid IOMMU group link: {}", target.display())
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::backend::RegisterIo;
+    use crate::device::dma::buffer::page_size;
+    use fake::FakeVfio;
+
+    #[test]
+    fn test_map_at_region_offset() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(0x3000).unwrap();
+
+        let region = VfioRegion::map(&file, 0x1000, 0x2000).unwrap();
+        assert_eq!(region.len(), 0x2000);
+
+        region.write64(0x8, 0xfeed_face_cafe_beef).unwrap();
+        assert_eq!(region.read64(0x8).unwrap(), 0xfeed_face_cafe_beef);
+    }
+
+    /// Fake sysfs with `address` in IOMMU group 7
+    fn fake_sysfs(address: &str) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join(address);
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink("../../../kernel/iommu_groups/7", dir.join("iommu_group")).unwrap();
+        root
+    }
+
+    fn open_fake(vfio: &Arc<FakeVfio>, root: &Path) -> Lzma2Result<VfioDevice> {
+        VfioDevice::open_with(vfio.clone(), root, "0000:41:00.0".parse().unwrap())
+    }
+
+    #[test]
+    fn test_open_sequence() {
+        let root = fake_sysfs("0000:41:00.0");
+        let vfio = Arc::new(FakeVfio::new(0x20000));
+
+        let device = open_fake(&vfio, root.path()).unwrap();
+        {
+            let state = vfio.state();
+            assert_eq!(state.groups, vec![7]);
+            assert_eq!(state.iommu, Some(VFIO_TYPE1V2_IOMMU));
+            assert_eq!(state.devices, vec!["0000:41:00.0".to_string()]);
+        }
+
+        let bar = device.map_bar(0).unwrap();
+        assert_eq!(bar.len(), 0x20000);
+        bar.write32(0x04, 0x41).unwrap();
+        assert_eq!(bar.read32(0x04).unwrap(), 0x41);
+        assert!(bar.device().is_some());
+        assert!(device.map_bar(1).is_err());
+
+        device.enable_msix(0).unwrap();
+        device.enable_msi(0).unwrap();
+        assert_eq!(vfio.state().irqs, vec![VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_MSI_IRQ_INDEX]);
+    }
+
+    #[test]
+    fn test_open_failures() {
+        let root = fake_sysfs("0000:41:00.0");
+
+        let vfio = Arc::new(FakeVfio::new(0x20000).not_viable());
+        assert!(open_fake(&vfio, root.path()).is_err());
+        assert!(!vfio.state().attached);
+
+        let vfio = Arc::new(FakeVfio::new(0x20000).type1_only());
+        open_fake(&vfio, root.path()).unwrap();
+        assert_eq!(vfio.state().iommu, Some(VFIO_TYPE1_IOMMU));
+
+        // No iommu_group link: the function is not behind an IOMMU
+        let empty = tempfile::tempdir().unwrap();
+        assert!(open_fake(&Arc::new(FakeVfio::new(0x20000)), empty.path()).is_err());
+    }
+
+    #[test]
+    fn test_dma_buffers() {
+        let root = fake_sysfs("0000:41:00.0");
+        let vfio = Arc::new(FakeVfio::new(0x20000));
+        let device = open_fake(&vfio, root.path()).unwrap();
+
+        let mut first = device.alloc_dma(100).unwrap();
+        let second = device.alloc_dma(32 * 1024).unwrap();
+        assert_eq!(first.iova(), IOVA_BASE);
+        assert_eq!(first.len(), page_size());
+        assert_eq!(second.iova(), IOVA_BASE + page_size() as u64);
+
+        first.as_mut_slice()[..4].copy_from_slice(b"LZMA");
+        {
+            let state = vfio.state();
+            assert_eq!(state.mappings.len(), 2);
+            assert_eq!(state.mappings[0].vaddr, first.as_slice().as_ptr() as u64);
+            assert_eq!(state.mappings[0].flags, VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE);
+        }
+
+        // Freed IOVA space is reused
+        drop(first);
+        assert_eq!(vfio.state().mappings.len(), 1);
+        let third = device.alloc_dma(10).unwrap();
+        assert_eq!(third.iova(), IOVA_BASE);
+        assert!(device.alloc_dma(0).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! IOMMU-mapped DMA buffers
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Adds and removes host memory from a device's IOMMU domain
+pub trait DmaMapper: Send + Sync {
+    /// Make `size` bytes at `vaddr` reachable by the device, returning the
+    /// I/O virtual address assigned to them
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the mapping is rejected
+    fn map(&self, vaddr: u64, size: u64) -> Lzma2Result<u64>;
+
+    /// Remove a mapping created by `map`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the range is not mapped
+    fn unmap(&self, iova: u64, size: u64) -> Lzma2Result<()>;
+}
+
+/// Source of DMA buffers for a device
+pub trait DmaAllocator: Send + Sync {
+    /// Allocate a zeroed buffer of at least `size` bytes mapped for DMA
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if allocation or mapping fails
+    fn alloc_dma(&self, size: usize) -> Lzma2Result<DmaBuffer>;
+}
+
+/// First-fit allocator of I/O virtual address ranges
+#[derive(Debug)]
+pub(crate) struct IovaAllocator {
+    base: u64,
+
+    /// Live ranges as start -> size
+    ranges: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl IovaAllocator {
+    /// Hand out addresses starting at `base`
+    pub(crate) fn new(base: u64) -> Self {
+        Self {
+            base,
+            ranges: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Reserve the lowest free range of `size` bytes
+    pub(crate) fn allocate(&self, size: u64) -> u64 {
+        let mut ranges = self.ranges.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let mut candidate = self.base;
+        for (&start, &len) in ranges.iter() {
+            if candidate + size <= start {
+                break;
+            }
+            candidate = candidate.max(start + len);
+        }
+
+        ranges.insert(candidate, size);
+        candidate
+    }
+
+    /// Return a range to the allocator
+    pub(crate) fn release(&self, iova: u64) {
+        self.ranges.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&iova);
+    }
+}
+
+/// Page-aligned host memory mapped into a device's IOMMU domain
+///
+/// The mapping is removed and the memory released on drop.
+pub struct DmaBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    iova: u64,
+    mapper: Arc<dyn DmaMapper>,
+}
+
+// SAFETY: the buffer exclusively owns its memory
+unsafe impl Send for DmaBuffer {}
+// SAFETY: shared access only hands out shared slices
+unsafe impl Sync for DmaBuffer {}
+
+impl fmt::Debug for DmaBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("iova", &format_args!("0x{:x}", self.iova))
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl DmaBuffer {
+    /// Allocate zeroed memory and map it through `mapper`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if allocation or mapping fails
+    pub fn new(mapper: Arc<dyn DmaMapper>, size: usize) -> Lzma2Result<Self> {
+        if size == 0 {
+            return Err(Lzma2Error::InputValidationError(
+                "DMA buffer size must be non-zero".to_string()
+            ));
+        }
+        let len = size.next_multiple_of(page_size());
+
+        // SAFETY: anonymous mapping with no preconditions; checked below
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("DMA buffer allocation failed: {}", std::io::Error::last_os_error())
+            ));
+        }
+        let ptr = NonNull::new(ptr.cast::<u8>()).ok_or(Lzma2Error::DeviceAccessError)?;
+
+        let iova = match mapper.map(ptr.as_ptr() as u64, len as u64) {
+            Ok(iova) => iova,
+            Err(e) => {
+                // SAFETY: unmapping the region created above
+                unsafe { libc::munmap(ptr.as_ptr().cast(), len) };
+                return Err(e);
+            },
+        };
+
+        Ok(Self { ptr, len, iova, mapper })
+    }
+
+    /// I/O virtual address of the buffer as seen by the device
+    pub fn iova(&self) -> u64 {
+        self.iova
+    }
+
+    /// Buffer length in bytes (a multiple of the page size)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Buffer contents
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: ptr/len describe memory owned by this buffer
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Mutable buffer contents
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: ptr/len describe memory owned by this buffer
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Read a little-endian word written by the device
+    ///
+    /// Volatile, since the device updates the buffer behind the compiler's
+    /// back.
+    ///
+    /// # Panics
+    /// Panics if `offset` is unaligned or out of bounds
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.len && offset.is_multiple_of(4));
+        // SAFETY: aligned and in bounds
+        u32::from_le(unsafe { std::ptr::read_volatile(self.ptr.as_ptr().add(offset).cast::<u32>()) })
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Err(e) = self.mapper.unmap(self.iova, self.len as u64) {
+            // Leak the memory rather than free pages the device can still reach
+            tracing::warn!("Failed to unmap DMA buffer at 0x{:x}: {}", self.iova, e);
+            return;
+        }
+
+        // SAFETY: ptr/len describe the mapping created in `new`
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+/// System page size
+pub(crate) fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size).unwrap_or(4096)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iova_first_fit() {
+        let iova = IovaAllocator::new(0x1000_0000);
+
+        assert_eq!(iova.allocate(0x2000), 0x1000_0000);
+        assert_eq!(iova.allocate(0x1000), 0x1000_2000);
+        assert_eq!(iova.allocate(0x1000), 0x1000_3000);
+
+        iova.release(0x1000_0000);
+        assert_eq!(iova.allocate(0x1000), 0x1000_0000);
+        assert_eq!(iova.allocate(0x2000), 0x1000_4000);
+    }
+}
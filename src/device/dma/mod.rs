@@ -0,0 +1,559 @@
+//! DMA engine driver for LZMA2 FPGA Compression Driver
+//!
+//! The engine walks a ring of 32-byte descriptors in host memory. Each
+//! descriptor moves one scatter-gather segment between an IOVA and a byte
+//! offset in the BAR; consecutive descriptors flagged `CHAIN` form one
+//! transfer. The driver publishes descriptors by writing the producer
+//! index to the `HEAD` doorbell, and the engine reports progress through
+//! the `TAIL` register and a status word written back into each
+//! descriptor.
+//!
+//! Descriptor layout (little-endian):
+//!
+//! | Offset | Field           |
+//! |--------|-----------------|
+//! | 0x00   | IOVA (u64)      |
+//! | 0x08   | length          |
+//! | 0x0C   | BAR offset      |
+//! | 0x10   | flags           |
+//! | 0x14   | status          |
+//! | 0x18   | bytes moved     |
+//! | 0x1C   | reserved        |
+
+pub(crate) mod buffer;
+
+pub use buffer::{DmaAllocator, DmaBuffer, DmaMapper};
+pub(crate) use buffer::IovaAllocator;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::backend::RegisterIo;
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::transfer::{DataTransfer, JobControl, TransferContext, TransferStrategy};
+
+/// Register offsets relative to the DMA register block
+pub mod registers {
+    /// Descriptor ring IOVA (64-bit)
+    pub const RING_BASE: u64 = 0x00;
+
+    /// Number of descriptors in the ring
+    pub const RING_SIZE: u64 = 0x08;
+
+    /// Producer index doorbell, written by the driver
+    pub const HEAD: u64 = 0x0C;
+
+    /// Consumer index, advanced by the engine
+    pub const TAIL: u64 = 0x10;
+
+    /// Engine status: `STATUS_ERROR`, fault code [15:8], slot [31:16]
+    pub const STATUS: u64 = 0x14;
+
+    /// Engine control
+    pub const CONTROL: u64 = 0x18;
+
+    /// Control: process descriptors
+    pub const CONTROL_ENABLE: u32 = 1 << 0;
+
+    /// Control: clear indices and status
+    pub const CONTROL_RESET: u32 = 1 << 31;
+
+    /// Status: the engine stopped on a faulting descriptor
+    pub const STATUS_ERROR: u32 = 1 << 0;
+}
+
+/// Size of one descriptor in bytes
+pub const DESCRIPTOR_SIZE: usize = 32;
+
+/// Descriptor flag: move data from host memory to the device
+pub const DESC_TO_DEVICE: u32 = 1 << 0;
+
+/// Descriptor flag: the next descriptor belongs to the same transfer
+pub const DESC_CHAIN: u32 = 1 << 1;
+
+/// Descriptor status: segment complete
+pub const DESC_DONE: u32 = 1 << 0;
+
+/// Descriptor status: segment failed, fault code in [15:8]
+pub const DESC_ERROR: u32 = 1 << 1;
+
+/// Default number of descriptors in the ring
+pub const DEFAULT_RING_ENTRIES: u32 = 64;
+
+/// Granularity at which the device bus moves data (256 bits)
+const BEAT_SIZE: usize = 32;
+
+/// Poll interval while waiting for the engine
+const POLL_INTERVAL: Duration = Duration::from_micros(10);
+
+/// Direction of a DMA transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// Host memory to BAR
+    ToDevice,
+
+    /// BAR to host memory
+    FromDevice,
+}
+
+/// Faults reported by the engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaFault {
+    /// The IOVA range is not mapped in the IOMMU
+    Translation,
+
+    /// The BAR range lies outside the data windows
+    DeviceRange,
+
+    /// Zero-length or misaligned segment
+    InvalidSegment,
+
+    /// Code not known to this driver
+    Unknown(u8),
+}
+
+impl DmaFault {
+    /// Decode a fault code
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Self::Translation,
+            2 => Self::DeviceRange,
+            3 => Self::InvalidSegment,
+            other => Self::Unknown(other),
+        }
+    }
+
+    /// Raw fault code
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Translation => 1,
+            Self::DeviceRange => 2,
+            Self::InvalidSegment => 3,
+            Self::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for DmaFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Translation => f.write_str("IOMMU translation fault"),
+            Self::DeviceRange => f.write_str("device address out of range"),
+            Self::InvalidSegment => f.write_str("invalid segment"),
+            Self::Unknown(code) => write!(f, "unknown fault {}", code),
+        }
+    }
+}
+
+/// One scatter-gather segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgEntry {
+    /// Host IOVA of the segment
+    pub iova: u64,
+
+    /// Segment length in bytes
+    pub len: u32,
+
+    /// BAR offset the segment maps to
+    pub device_offset: u32,
+}
+
+/// A descriptor as stored in the ring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    /// Host IOVA
+    pub iova: u64,
+
+    /// Length in bytes
+    pub len: u32,
+
+    /// BAR offset
+    pub device_offset: u32,
+
+    /// `DESC_*` flags
+    pub flags: u32,
+
+    /// Status written back by the engine
+    pub status: u32,
+
+    /// Bytes moved, written back by the engine
+    pub transferred: u32,
+}
+
+impl Descriptor {
+    /// Serialize into the ring format
+    pub fn to_bytes(&self) -> [u8; DESCRIPTOR_SIZE] {
+        let mut bytes = [0u8; DESCRIPTOR_SIZE];
+        bytes[0x00..0x08].copy_from_slice(&self.iova.to_le_bytes());
+        bytes[0x08..0x0C].copy_from_slice(&self.len.to_le_bytes());
+        bytes[0x0C..0x10].copy_from_slice(&self.device_offset.to_le_bytes());
+        bytes[0x10..0x14].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[0x14..0x18].copy_from_slice(&self.status.to_le_bytes());
+        bytes[0x18..0x1C].copy_from_slice(&self.transferred.to_le_bytes());
+        bytes
+    }
+
+    /// Parse from the ring format
+    ///
+    /// # Panics
+    /// Panics if `bytes` is shorter than `DESCRIPTOR_SIZE`
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+
+        Self {
+            iova: u64::from_le_bytes(bytes[0x00..0x08].try_into().unwrap()),
+            len: u32_at(0x08),
+            device_offset: u32_at(0x0C),
+            flags: u32_at(0x10),
+            status: u32_at(0x14),
+            transferred: u32_at(0x18),
+        }
+    }
+
+    /// Fault reported in the status word, if any
+    pub fn fault(&self) -> Option<DmaFault> {
+        (self.status & DESC_ERROR != 0).then(|| DmaFault::from_code((self.status >> 8) as u8))
+    }
+}
+
+/// Driver for the descriptor-ring DMA engine
+///
+/// Transfers are synchronous: `submit` rings the doorbell and waits until
+/// the engine has consumed every descriptor of the list. Batches keep a
+/// second staging buffer so the next block is copied in while the device
+/// compresses the current one.
+#[derive(Debug)]
+pub struct DmaEngine {
+    /// Offset of the DMA register block in the BAR
+    base: u64,
+
+    /// Descriptor ring
+    ring: DmaBuffer,
+
+    /// Number of descriptors in the ring
+    entries: u32,
+
+    /// Free-running producer index
+    head: u32,
+
+    /// Bounce buffer for uploads and downloads
+    staging: DmaBuffer,
+
+    /// Staging buffer filled with the next input of a batch
+    spare: DmaBuffer,
+
+    /// Address and length of the input held in `spare`
+    staged: Option<(usize, usize)>,
+
+    /// Largest segment placed in one descriptor
+    segment_size: usize,
+
+    /// Timeout for one transfer
+    timeout: Duration,
+}
+
+impl DmaEngine {
+    /// Allocate the ring and two staging buffers of `staging_size` bytes
+    /// and program the engine at register block `base`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if allocation or register access fails
+    pub fn new(
+        io: &dyn RegisterIo,
+        allocator: &dyn DmaAllocator,
+        base: u64,
+        staging_size: usize,
+    ) -> Lzma2Result<Self> {
+        let entries = DEFAULT_RING_ENTRIES;
+        let mut engine = Self {
+            base,
+            ring: allocator.alloc_dma(entries as usize * DESCRIPTOR_SIZE)?,
+            entries,
+            head: 0,
+            staging: allocator.alloc_dma(staging_size)?,
+            spare: allocator.alloc_dma(staging_size)?,
+            staged: None,
+            segment_size: TransferStrategy::Dma.optimal_chunk_size(),
+            timeout: Duration::from_millis(100),
+        };
+        engine.init(io)?;
+        Ok(engine)
+    }
+
+    /// Set the largest segment placed in one descriptor
+    pub fn with_segment_size(mut self, segment_size: usize) -> Self {
+        self.segment_size = segment_size.next_multiple_of(BEAT_SIZE);
+        self
+    }
+
+    /// Number of descriptors in the ring
+    pub fn entries(&self) -> u32 {
+        self.entries
+    }
+
+    /// Reset the engine and program the ring
+    fn init(&mut self, io: &dyn RegisterIo) -> Lzma2Result<()> {
+        io.write32(self.base + registers::CONTROL, registers::CONTROL_RESET)?;
+        io.write64(self.base + registers::RING_BASE, self.ring.iova())?;
+        io.write32(self.base + registers::RING_SIZE, self.entries)?;
+        io.write32(self.base + registers::CONTROL, registers::CONTROL_ENABLE)?;
+        self.head = 0;
+        Ok(())
+    }
+
+    /// Run a scatter-gather list and wait for its completion
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::TransferError` if the engine reports a fault or
+    /// does not finish in time; the engine is reset in that case
+    pub fn submit(
+        &mut self,
+        io: &dyn RegisterIo,
+        direction: DmaDirection,
+        list: &[SgEntry],
+    ) -> Lzma2Result<()> {
+        if list.is_empty() {
+            return Ok(());
+        }
+        if list.len() > self.entries as usize {
+            return Err(Lzma2Error::TransferError(
+                format!("Scatter-gather list of {} segments exceeds the {}-entry ring", list.len(), self.entries)
+            ));
+        }
+
+        let direction_flag = match direction {
+            DmaDirection::ToDevice => DESC_TO_DEVICE,
+            DmaDirection::FromDevice => 0,
+        };
+
+        let start = self.head;
+        for (i, entry) in list.iter().enumerate() {
+            let chain = if i + 1 < list.len() { DESC_CHAIN } else { 0 };
+            let descriptor = Descriptor {
+                iova: entry.iova,
+                len: entry.len,
+                device_offset: entry.device_offset,
+                flags: direction_flag | chain,
+                ..Descriptor::default()
+            };
+
+            let offset = self.slot_offset(start.wrapping_add(i as u32));
+            self.ring.as_mut_slice()[offset..offset + DESCRIPTOR_SIZE]
+                .copy_from_slice(&descriptor.to_bytes());
+        }
+        self.head = start.wrapping_add(list.len() as u32);
+
+        // Register writes are ordered after the descriptor stores
+        io.write32(self.base + registers::HEAD, self.head)?;
+
+        let result = self.wait(io).and_then(|()| self.check_descriptors(start, list.len()));
+        if result.is_err() {
+            self.init(io)?;
+        }
+        result
+    }
+
+    /// Copy `data` into the BAR at `device_offset`
+    ///
+    /// A trailing partial 256-bit beat is zero-padded.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the data does not fit the staging buffer or
+    /// the transfer fails
+    pub fn upload(&mut self, io: &dyn RegisterIo, device_offset: u64, data: &[u8]) -> Lzma2Result<()> {
+        let len = data.len().next_multiple_of(BEAT_SIZE);
+        self.check_staging(len)?;
+
+        let staging = self.staging.as_mut_slice();
+        staging[..data.len()].copy_from_slice(data);
+        staging[data.len()..len].fill(0);
+
+        let list = self.scatter(device_offset, len)?;
+        self.submit(io, DmaDirection::ToDevice, &list)
+    }
+
+    /// Fill `buffer` from the BAR at `device_offset`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the buffer does not fit the staging buffer
+    /// or the transfer fails
+    pub fn download(&mut self, io: &dyn RegisterIo, device_offset: u64, buffer: &mut [u8]) -> Lzma2Result<()> {
+        let len = buffer.len().next_multiple_of(BEAT_SIZE);
+        self.check_staging(len)?;
+
+        let list = self.scatter(device_offset, len)?;
+        self.submit(io, DmaDirection::FromDevice, &list)?;
+
+        buffer.copy_from_slice(&self.staging.as_slice()[..buffer.len()]);
+        Ok(())
+    }
+
+    /// Copy `data` into the spare staging buffer, zero-padding the last beat
+    fn stage(&mut self, data: &[u8]) -> Lzma2Result<()> {
+        let len = data.len().next_multiple_of(BEAT_SIZE);
+        self.check_staging(len)?;
+
+        let spare = self.spare.as_mut_slice();
+        spare[..data.len()].copy_from_slice(data);
+        spare[data.len()..len].fill(0);
+        self.staged = Some((data.as_ptr() as usize, data.len()));
+        Ok(())
+    }
+
+    /// Split the first `len` staging bytes into segments
+    fn scatter(&self, device_offset: u64, len: usize) -> Lzma2Result<Vec<SgEntry>> {
+        let device_offset = u32::try_from(device_offset).map_err(|_| Lzma2Error::TransferError(
+            format!("BAR offset 0x{:x} is out of DMA range", device_offset)
+        ))?;
+
+        Ok((0..len).step_by(self.segment_size)
+            .map(|start| SgEntry {
+                iova: self.staging.iova() + start as u64,
+                len: (len - start).min(self.segment_size) as u32,
+                device_offset: device_offset + start as u32,
+            })
+            .collect())
+    }
+
+    fn check_staging(&self, len: usize) -> Lzma2Result<()> {
+        if len > self.staging.len() {
+            return Err(Lzma2Error::TransferError(
+                format!("DMA transfer of {} bytes exceeds the {}-byte staging buffer", len, self.staging.len())
+            ));
+        }
+        Ok(())
+    }
+
+    fn slot_offset(&self, index: u32) -> usize {
+        (index % self.entries) as usize * DESCRIPTOR_SIZE
+    }
+
+    /// Wait until the engine has consumed every published descriptor
+    fn wait(&self, io: &dyn RegisterIo) -> Lzma2Result<()> {
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let status = io.read32(self.base + registers::STATUS)?;
+            if status & registers::STATUS_ERROR != 0 {
+                return Err(Lzma2Error::TransferError(format!(
+                    "DMA engine stopped at descriptor {}: {}",
+                    status >> 16,
+                    DmaFault::from_code((status >> 8) as u8)
+                )));
+            }
+
+            if io.read32(self.base + registers::TAIL)? == self.head {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(Lzma2Error::TransferError(
+                    "DMA transfer timed out".to_string()
+                ));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Verify the write-back status of `count` descriptors from `start`
+    fn check_descriptors(&self, start: u32, count: usize) -> Lzma2Result<()> {
+        for i in 0..count {
+            let index = start.wrapping_add(i as u32);
+            let offset = self.slot_offset(index);
+
+            // Only the write-back words are read volatile
+            let mut bytes = [0u8; DESCRIPTOR_SIZE];
+            bytes.copy_from_slice(&self.ring.as_slice()[offset..offset + DESCRIPTOR_SIZE]);
+            let mut descriptor = Descriptor::from_bytes(&bytes);
+            descriptor.status = self.ring.read_u32(offset + 0x14);
+            descriptor.transferred = self.ring.read_u32(offset + 0x18);
+
+            if let Some(fault) = descriptor.fault() {
+                return Err(Lzma2Error::TransferError(
+                    format!("DMA descriptor {} failed: {}", index % self.entries, fault)
+                ));
+            }
+            if descriptor.status & DESC_DONE == 0 || descriptor.transferred != descriptor.len {
+                return Err(Lzma2Error::TransferError(format!(
+                    "DMA descriptor {} incomplete: {} of {} bytes",
+                    index % self.entries, descriptor.transferred, descriptor.len
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DataTransfer for DmaEngine {
+    fn strategy(&self) -> Option<TransferStrategy> {
+        Some(TransferStrategy::Dma)
+    }
+
+    fn transfer_input(&mut self, ctx: &TransferContext<'_>, data: &[u8]) -> Lzma2Result<()> {
+        self.upload(ctx.io, ctx.input_window, data)
+    }
+
+    fn read_output(&mut self, ctx: &TransferContext<'_>, len: usize) -> Lzma2Result<Vec<u8>> {
+        let mut output = vec![0u8; len];
+        self.download(ctx.io, ctx.output_window, &mut output)?;
+        Ok(output)
+    }
+
+    /// Copy the next block into the spare staging buffer while the device
+    /// works on this one
+    fn run_batch_job(
+        &mut self,
+        ctx: &TransferContext<'_>,
+        input: &[u8],
+        next: Option<&[u8]>,
+        job: &dyn JobControl,
+    ) -> Lzma2Result<Vec<u8>> {
+        if self.staged.take() != Some((input.as_ptr() as usize, input.len())) {
+            self.stage(input)?;
+            self.staged = None;
+        }
+        std::mem::swap(&mut self.staging, &mut self.spare);
+        let list = self.scatter(ctx.input_window, input.len().next_multiple_of(BEAT_SIZE))?;
+        self.submit(ctx.io, DmaDirection::ToDevice, &list)?;
+        job.start(0)?;
+
+        // A block that does not fit fails when its own job stages it
+        if let Some(next) = next {
+            let _ = self.stage(next);
+        }
+
+        job.wait()?;
+        self.read_output(ctx, job.output_len()?)
+    }
+
+    fn finish_batch(&mut self) {
+        self.staged = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor_layout() {
+        let descriptor = Descriptor {
+            iova: 0x1_0000_2000,
+            len: 4096,
+            device_offset: 0x8000,
+            flags: DESC_TO_DEVICE | DESC_CHAIN,
+            status: DESC_ERROR | 2 << 8,
+            transferred: 0,
+        };
+
+        let bytes = descriptor.to_bytes();
+        assert_eq!(&bytes[0x00..0x08], &0x1_0000_2000u64.to_le_bytes());
+        assert_eq!(&bytes[0x10..0x14], &3u32.to_le_bytes());
+        assert_eq!(Descriptor::from_bytes(&bytes), descriptor);
+        assert_eq!(descriptor.fault(), Some(DmaFault::DeviceRange));
+        assert_eq!(DmaFault::from_code(9).code(), 9);
+    }
+}
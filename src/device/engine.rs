@@ -0,0 +1,221 @@
+//! Engine ownership for LZMA2 FPGA Compression Driver
+//!
+//! The engine runs one register sequence at a time. `EngineLock` hands it
+//! to one thread at a time; other threads wait for it to be released. The
+//! owning thread can take it again, so a stream reserved across several
+//! chained jobs still runs those jobs. What the owner may start depends on
+//! what it holds: a command queue drives the registers itself until it is
+//! dropped, and an open chained stream rules out jobs that reset the
+//! engine until it is closed.
+
+use std::marker::PhantomData;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// What the engine is taken for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Hold {
+    /// Job or register sequence that resets the engine
+    Reset,
+
+    /// Job continuing the open stream, or access that leaves it intact
+    Keep,
+
+    /// Command queue
+    Queue,
+}
+
+/// Thread holding the engine
+#[derive(Debug, Clone, Copy)]
+struct Owner {
+    thread: ThreadId,
+
+    /// Guards held by the thread
+    depth: usize,
+
+    /// Whether a command queue holds the engine
+    queue: bool,
+
+    /// Whether a chained stream is open on the engine
+    stream_open: bool,
+}
+
+/// Lock on the engine, reentrant for the thread holding it
+#[derive(Debug, Default)]
+pub(super) struct EngineLock {
+    owner: Mutex<Option<Owner>>,
+    released: Condvar,
+}
+
+impl EngineLock {
+    /// Take the engine for `hold`, waiting while another thread holds it
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::EngineBusy` if what the calling thread already
+    /// holds rules out `hold`: a command queue rules out everything else, a
+    /// queue needs the engine to itself, and an open chained stream rules
+    /// out resets
+    pub(super) fn lock(&self, hold: Hold) -> Lzma2Result<EngineGuard<'_>> {
+        let current = thread::current().id();
+        let mut owner = self.state();
+        loop {
+            match owner.as_mut() {
+                None => {
+                    *owner = Some(Owner {
+                        thread: current,
+                        depth: 1,
+                        queue: hold == Hold::Queue,
+                        stream_open: false,
+                    });
+                    break;
+                },
+                Some(held) if held.thread == current => {
+                    if held.queue {
+                        return Err(Lzma2Error::EngineBusy(
+                            "A command queue on this thread holds the engine".to_string()
+                        ));
+                    }
+                    match hold {
+                        Hold::Queue => return Err(Lzma2Error::EngineBusy(
+                            "This thread is running jobs on the engine".to_string()
+                        )),
+                        Hold::Reset if held.stream_open => return Err(Lzma2Error::EngineBusy(
+                            "A chained stream is open on this thread; a reset would discard it".to_string()
+                        )),
+                        _ => {},
+                    }
+                    held.depth += 1;
+                    break;
+                },
+                Some(_) => {
+                    owner = self.released.wait(owner).unwrap_or_else(|poisoned| poisoned.into_inner());
+                },
+            }
+        }
+
+        Ok(EngineGuard {
+            lock: Some(self),
+            _thread: PhantomData,
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, Option<Owner>> {
+        self.owner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn release(&self) {
+        let mut owner = self.state();
+        if let Some(held) = owner.as_mut() {
+            held.depth -= 1;
+            if held.depth == 0 {
+                *owner = None;
+                self.released.notify_all();
+            }
+        }
+    }
+}
+
+/// Hold on a device's engine, released on drop
+///
+/// Bound to the thread that took it.
+#[derive(Debug)]
+#[must_use = "the engine is released as soon as the guard is dropped"]
+pub struct EngineGuard<'a> {
+    lock: Option<&'a EngineLock>,
+    _thread: PhantomData<*const ()>,
+}
+
+impl EngineGuard<'_> {
+    /// Guard of a device without an engine to hold
+    pub fn unheld() -> Self {
+        Self {
+            lock: None,
+            _thread: PhantomData,
+        }
+    }
+
+    /// Whether the guard holds an engine
+    pub fn is_held(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    /// Record whether the job run under this guard left a chained stream
+    /// open
+    ///
+    /// Kept until the thread releases its last guard on the engine.
+    pub(super) fn set_stream_open(&self, open: bool) {
+        if let Some(lock) = self.lock {
+            if let Some(held) = lock.state().as_mut() {
+                held.stream_open = open;
+            }
+        }
+    }
+}
+
+impl Drop for EngineGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock {
+            lock.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reentrant_owner() -> Lzma2Result<()> {
+        let lock = EngineLock::default();
+
+        // The owner takes the engine again; a queue cannot join in
+        let outer = lock.lock(Hold::Reset)?;
+        let inner = lock.lock(Hold::Reset)?;
+        assert!(matches!(lock.lock(Hold::Queue), Err(Lzma2Error::EngineBusy(_))));
+        drop(inner);
+
+        // Other threads wait for the last guard
+        let (done, finished) = mpsc::channel();
+        thread::scope(|scope| -> Lzma2Result<()> {
+            scope.spawn(|| {
+                let _guard = lock.lock(Hold::Reset).unwrap();
+                done.send(()).unwrap();
+            });
+            assert!(finished.recv_timeout(Duration::from_millis(50)).is_err());
+            drop(outer);
+            finished.recv().unwrap();
+            Ok(())
+        })?;
+
+        // A queue shuts out jobs of its own thread
+        let queue = lock.lock(Hold::Queue)?;
+        assert!(matches!(lock.lock(Hold::Reset), Err(Lzma2Error::EngineBusy(_))));
+        drop(queue);
+        assert!(lock.lock(Hold::Reset)?.is_held());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_stream() -> Lzma2Result<()> {
+        let lock = EngineLock::default();
+
+        // An open stream shuts out resets but not its next block
+        let reserved = lock.lock(Hold::Keep)?;
+        lock.lock(Hold::Reset)?.set_stream_open(true);
+        assert!(matches!(lock.lock(Hold::Reset), Err(Lzma2Error::EngineBusy(_))));
+        lock.lock(Hold::Keep)?.set_stream_open(false);
+        assert!(lock.lock(Hold::Reset)?.is_held());
+
+        // Releasing the engine drops a stream left open
+        reserved.set_stream_open(true);
+        drop(reserved);
+        assert!(lock.lock(Hold::Reset)?.is_held());
+
+        Ok(())
+    }
+}
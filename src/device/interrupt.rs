@@ -0,0 +1,248 @@
+//! Completion interrupts for LZMA2 FPGA Compression Driver
+//!
+//! The controller raises an MSI/MSI-X vector when a job leaves the
+//! COMPRESS state. Userspace receives it either through an eventfd
+//! registered with VFIO (`VFIO_DEVICE_SET_IRQS`) or through the UIO device
+//! file. Devices without an interrupt source fall back to polling the
+//! status register.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Source of completion interrupts
+pub trait InterruptSource: Send + Sync {
+    /// Wait for an interrupt
+    ///
+    /// Blocks indefinitely when `timeout` is `None`. Returns `false` if the
+    /// timeout expired without an interrupt.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the interrupt file descriptor fails
+    fn wait(&self, timeout: Option<Duration>) -> Lzma2Result<bool>;
+
+    /// Consume a pending interrupt without blocking
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the interrupt file descriptor fails
+    fn try_wait(&self) -> Lzma2Result<bool> {
+        self.wait(Some(Duration::ZERO))
+    }
+}
+
+/// Interrupt delivered through an eventfd
+///
+/// The eventfd is handed to VFIO as the trigger of an MSI vector; the
+/// kernel increments its counter on every interrupt.
+#[derive(Debug)]
+pub struct EventFdInterrupt {
+    fd: OwnedFd,
+}
+
+impl EventFdInterrupt {
+    /// Create a non-blocking eventfd
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the eventfd cannot be created
+    pub fn new() -> Lzma2Result<Self> {
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("eventfd failed: {}", io::Error::last_os_error())
+            ));
+        }
+
+        // SAFETY: fd is a freshly created descriptor owned by nobody else
+        Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    /// Duplicate the eventfd, e.g. to keep one end for signalling
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the descriptor cannot be duplicated
+    pub fn try_clone(&self) -> Lzma2Result<Self> {
+        let fd = self.fd.try_clone().map_err(|e| Lzma2Error::DeviceInitError(
+            format!("Failed to duplicate eventfd: {}", e)
+        ))?;
+        Ok(Self { fd })
+    }
+
+    /// Increment the counter as the kernel does on an interrupt
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the write fails
+    pub fn signal(&self) -> Lzma2Result<()> {
+        let value = 1u64.to_ne_bytes();
+        // SAFETY: writing 8 bytes from a valid buffer
+        let written = unsafe { libc::write(self.fd.as_raw_fd(), value.as_ptr().cast(), value.len()) };
+        if written != value.len() as isize {
+            return Err(Lzma2Error::DeviceAccessError);
+        }
+        Ok(())
+    }
+
+    /// Read and reset the counter, returning `false` if it was zero
+    fn consume(&self) -> Lzma2Result<bool> {
+        let mut value = [0u8; 8];
+        // SAFETY: reading 8 bytes into a valid buffer
+        let read = unsafe { libc::read(self.fd.as_raw_fd(), value.as_mut_ptr().cast(), value.len()) };
+        if read == value.len() as isize {
+            return Ok(u64::from_ne_bytes(value) > 0);
+        }
+
+        match io::Error::last_os_error().kind() {
+            io::ErrorKind::WouldBlock => Ok(false),
+            _ => Err(Lzma2Error::DeviceAccessError),
+        }
+    }
+}
+
+impl AsRawFd for EventFdInterrupt {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl InterruptSource for EventFdInterrupt {
+    fn wait(&self, timeout: Option<Duration>) -> Lzma2Result<bool> {
+        if !poll_readable(self.fd.as_raw_fd(), timeout)? {
+            return Ok(false);
+        }
+        self.consume()
+    }
+}
+
+/// Interrupt delivered through a UIO device file (`/dev/uioN`)
+///
+/// Reading returns the interrupt count; writing 1 re-enables the
+/// interrupt, which `uio_pci_generic` masks after each delivery.
+#[derive(Debug)]
+pub struct UioInterrupt {
+    file: File,
+}
+
+impl UioInterrupt {
+    /// Open the interrupt of the UIO device `name` (e.g. `uio0`)
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the device cannot be opened
+    pub fn open(name: &str) -> Lzma2Result<Self> {
+        Self::open_path(&Path::new("/dev").join(name))
+    }
+
+    /// Open a UIO device file and enable its interrupt
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the device cannot be opened
+    pub fn open_path(path: &Path) -> Lzma2Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| Lzma2Error::DeviceInitError(
+                format!("Failed to open {}: {}", path.display(), e)
+            ))?;
+
+        let interrupt = Self { file };
+        interrupt.enable()?;
+        Ok(interrupt)
+    }
+
+    /// Unmask the interrupt
+    fn enable(&self) -> Lzma2Result<()> {
+        (&self.file).write_all(&1u32.to_ne_bytes())
+            .map_err(|_| Lzma2Error::DeviceAccessError)
+    }
+}
+
+impl InterruptSource for UioInterrupt {
+    fn wait(&self, timeout: Option<Duration>) -> Lzma2Result<bool> {
+        if !poll_readable(self.file.as_raw_fd(), timeout)? {
+            return Ok(false);
+        }
+
+        let mut count = [0u8; 4];
+        (&self.file).read_exact(&mut count)
+            .map_err(|_| Lzma2Error::DeviceAccessError)?;
+
+        self.enable()?;
+        Ok(true)
+    }
+}
+
+/// Wait until `fd` is readable, returning `false` on timeout
+fn poll_readable(fd: RawFd, timeout: Option<Duration>) -> Lzma2Result<bool> {
+    let timeout_ms = match timeout {
+        Some(timeout) => i32::try_from(timeout.as_micros().div_ceil(1000)).unwrap_or(i32::MAX),
+        None => -1,
+    };
+
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    loop {
+        // SAFETY: pollfd is a valid array of one entry
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        match ready {
+            0 => return Ok(false),
+            n if n > 0 => return Ok(pollfd.revents & libc::POLLIN != 0),
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            _ => return Err(Lzma2Error::DeviceAccessError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_eventfd_wait() {
+        let interrupt = EventFdInterrupt::new().unwrap();
+        assert!(!interrupt.try_wait().unwrap());
+
+        let start = Instant::now();
+        assert!(!interrupt.wait(Some(Duration::from_millis(20))).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // Signals coalesce into one wakeup
+        let trigger = interrupt.try_clone().unwrap();
+        trigger.signal().unwrap();
+        trigger.signal().unwrap();
+        assert!(interrupt.wait(None).unwrap());
+        assert!(!interrupt.try_wait().unwrap());
+    }
+
+    #[test]
+    fn test_eventfd_wakes_blocked_waiter() {
+        let interrupt = EventFdInterrupt::new().unwrap();
+        let trigger = interrupt.try_clone().unwrap();
+
+        let waiter = std::thread::spawn(move || interrupt.wait(Some(Duration::from_secs(5))));
+        std::thread::sleep(Duration::from_millis(10));
+        trigger.signal().unwrap();
+
+        assert!(waiter.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_uio_interrupt() {
+        // A regular file is always readable; the enable write lands in the
+        // first word and the count is read from the second
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), [0u8, 0, 0, 0, 7, 0, 0, 0]).unwrap();
+
+        let interrupt = UioInterrupt::open_path(file.path()).unwrap();
+        assert!(interrupt.try_wait().unwrap());
+        assert!(UioInterrupt::open_path(&file.path().join("missing")).is_err());
+    }
+}
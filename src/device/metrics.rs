@@ -27,6 +27,22 @@ impl CacheMetrics {
     }
 }
 
+/// Software verification of hardware output
+#[derive(Debug, Clone, Default)]
+pub struct VerifyMetrics {
+    /// Blocks decoded and compared against their input
+    pub blocks_verified: u64,
+    
+    /// Hardware outputs that did not decode to their input
+    pub mismatches: u64,
+    
+    /// Blocks compressed again after a mismatch
+    pub retries: u64,
+    
+    /// Blocks stored uncompressed after a mismatch
+    pub stored_blocks: u64,
+}
+
 /// Comprehensive performance metrics for compression device
 #[derive(Debug, Clone, Default)]
 pub struct PerformanceMetrics {
@@ -50,6 +66,12 @@ pub struct PerformanceMetrics {
     
     /// Number of match hits during compression
     pub match_hits: u64,
+    
+    /// Blocks stored uncompressed because the device could not shrink them
+    pub incompressible_blocks: u64,
+    
+    /// Verification of hardware output, when enabled on the compressor
+    pub verify_metrics: VerifyMetrics,
 }
 
 impl PerformanceMetrics {
@@ -61,6 +83,26 @@ impl PerformanceMetrics {
             1.0
         };
     }
+    
+    /// Add the counters of `other`, e.g. another device, and recalculate
+    /// the ratios
+    pub fn accumulate(&mut self, other: &PerformanceMetrics) {
+        self.total_bytes_processed += other.total_bytes_processed;
+        self.compressed_bytes += other.compressed_bytes;
+        self.cycles += other.cycles;
+        self.cache_metrics.hits += other.cache_metrics.hits;
+        self.cache_metrics.misses += other.cache_metrics.misses;
+        self.literal_count += other.literal_count;
+        self.match_hits += other.match_hits;
+        self.incompressible_blocks += other.incompressible_blocks;
+        self.verify_metrics.blocks_verified += other.verify_metrics.blocks_verified;
+        self.verify_metrics.mismatches += other.verify_metrics.mismatches;
+        self.verify_metrics.retries += other.verify_metrics.retries;
+        self.verify_metrics.stored_blocks += other.verify_metrics.stored_blocks;
+        
+        self.calculate_compression_ratio();
+        self.cache_metrics.calculate_hit_ratio();
+    }
 }
 
 impl fmt::Display for PerformanceMetrics {
@@ -72,7 +114,9 @@ impl fmt::Display for PerformanceMetrics {
             Cycles: {}\n\
             Cache Hits: {} ({:.2}%)\n\
             Literals: {}\n\
-            Match Hits: {}",
+            Match Hits: {}\n\
+            Incompressible Blocks: {}\n\
+            Verify Mismatches: {} of {} blocks",
             self.total_bytes_processed,
             self.compressed_bytes,
             self.compression_ratio,
@@ -80,7 +124,10 @@ impl fmt::Display for PerformanceMetrics {
             self.cache_metrics.hits,
             self.cache_metrics.hit_ratio * 100.0,
             self.literal_count,
-            self.match_hits
+            self.match_hits,
+            self.incompressible_blocks,
+            self.verify_metrics.mismatches,
+            self.verify_metrics.blocks_verified
         )
     }
 }
@@ -1,13 +1,40 @@
 //! Device abstraction for LZMA2 FPGA Compression Driver
 
+pub mod backend;
+pub mod dma;
+mod engine;
+pub mod interrupt;
 mod metrics;
+pub mod mmio;
+pub mod offload;
 mod pcie;
+mod pcie_trait_impl;
+pub mod pool;
+pub mod queue;
+pub(crate) mod simulator;
+pub mod status;
+pub mod stream;
+pub mod sysfs;
 
-pub use metrics::{PerformanceMetrics, CacheMetrics};
+pub use metrics::{PerformanceMetrics, CacheMetrics, VerifyMetrics};
+pub use backend::RegisterIo;
+pub use dma::{DmaAllocator, DmaBuffer, DmaEngine};
+pub use engine::EngineGuard;
+pub use interrupt::{EventFdInterrupt, InterruptSource, UioInterrupt};
 pub use pcie::PcieDevice;
+pub use pool::{BalancePolicy, DevicePool, MemberStatus};
+pub use queue::{CommandQueue, JobCompletion, JobId};
+pub use offload::{AsyncCompressionDevice, BlockingOffload, Completion};
+pub use simulator::{SimulatedBackend, SimulatedDevice, SimulatorConfig};
+pub use stream::StreamEngine;
+pub use status::{ControllerState, HardwareErrorCode, RegisterSnapshot, StatusRegister};
+pub use sysfs::{PciAddress, PciFunction, SysfsScanner};
 
 use crate::error::{Lzma2Error, Lzma2Result};
 
+/// Hardware block size (`lzma2_pkg::INPUT_SIZE`)
+pub const BLOCK_SIZE: usize = 32 * 1024;
+
 /// Configuration for hardware compression device
 #[derive(Debug, Clone)]
 pub struct DeviceConfig {
@@ -19,6 +46,42 @@ pub struct DeviceConfig {
     
     /// BAR (Base Address Register) index
     pub bar_index: usize,
+    
+    /// Engine extensions the driver may use
+    pub features: EngineFeatures,
+}
+
+/// Engine extensions beyond the shipped RTL
+///
+/// The RTL implements none of them and has no register to probe for them,
+/// so each one is only used when enabled for an engine known to have it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineFeatures {
+    /// Decompression mode; without it blocks are decoded in software
+    pub decode_engine: bool,
+    
+    /// Block length register; without it short blocks are encoded in
+    /// software
+    pub short_blocks: bool,
+    
+    /// LZMA streams kept open across jobs for `compress_chained`
+    pub chained_streams: bool,
+    
+    /// Command queue with several jobs in flight
+    pub command_queue: bool,
+}
+
+/// Place of a block within one LZMA stream spanning several blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainedBlock {
+    /// Continue the stream left open by the previous block
+    pub continues: bool,
+    
+    /// Close the stream after this block
+    pub closes: bool,
+    
+    /// Terminate a closing stream with the end-of-stream marker
+    pub end_marker: bool,
 }
 
 /// Trait defining the interface for hardware compression devices
@@ -35,6 +98,76 @@ pub trait HardwareCompressionDevice {
     /// Returns `Lzma2Error` if decompression fails
     fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>>;
     
+    /// Compress a block of 1 to `BLOCK_SIZE` bytes
+    ///
+    /// The default supports only full blocks.
+    /// 
+    /// # Errors
+    /// Returns `Lzma2Error` if compression fails
+    fn compress_block(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        if input.len() != BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Device only compresses full {}-byte blocks, got {}", BLOCK_SIZE, input.len())
+            ));
+        }
+        self.compress(input)
+    }
+    
+    /// Compress a block of 1 to `BLOCK_SIZE` bytes into one LZMA stream
+    /// shared with neighbouring blocks
+    ///
+    /// Blocks that leave the stream open return only the bytes completed
+    /// so far; the closing block flushes the rest. The default supports
+    /// only self-contained full blocks.
+    /// 
+    /// # Errors
+    /// Returns `Lzma2Error` if compression fails or the device cannot
+    /// chain blocks
+    fn compress_chained(&self, input: &[u8], chain: ChainedBlock) -> Lzma2Result<Vec<u8>> {
+        if chain.continues || !chain.closes || chain.end_marker {
+            return Err(Lzma2Error::InputValidationError(
+                "Device does not support LZMA streams spanning several blocks".to_string()
+            ));
+        }
+        self.compress_block(input)
+    }
+    
+    /// Keep jobs of other threads off the device until the guard is dropped
+    ///
+    /// Held across the blocks of a chained stream, which a job in between
+    /// would discard. Jobs of the calling thread still run. The default
+    /// holds nothing.
+    /// 
+    /// # Errors
+    /// Returns `Lzma2Error::EngineBusy` if the calling thread holds the
+    /// device in a way that rules out jobs
+    fn reserve(&self) -> Lzma2Result<EngineGuard<'_>> {
+        Ok(EngineGuard::unheld())
+    }
+    
+    /// Compress independent blocks of 1 to `BLOCK_SIZE` bytes
+    ///
+    /// Each block succeeds or fails on its own. The default compresses the
+    /// blocks one after another.
+    fn compress_batch(&self, blocks: &[&[u8]]) -> Vec<Lzma2Result<Vec<u8>>> {
+        blocks.iter().map(|block| self.compress_block(block)).collect()
+    }
+    
+    /// Decompress a block that restores to `uncompressed_len` bytes
+    /// 
+    /// # Errors
+    /// Returns `Lzma2Error` if decompression fails
+    fn decompress_block(&self, input: &[u8], uncompressed_len: usize) -> Lzma2Result<Vec<u8>> {
+        let mut output = self.decompress(input)?;
+        if output.len() < uncompressed_len {
+            return Err(Lzma2Error::ProcessingError(
+                format!("Block decompressed to {} bytes, expected {}", output.len(), uncompressed_len)
+            ));
+        }
+        output.truncate(uncompressed_len);
+        Ok(output)
+    }
+    
     /// Retrieve performance metrics for the device
     /// 
     /// # Errors
@@ -42,6 +175,40 @@ pub trait HardwareCompressionDevice {
     fn get_performance_metrics(&self) -> Lzma2Result<PerformanceMetrics>;
 }
 
+impl<T: HardwareCompressionDevice + ?Sized> HardwareCompressionDevice for &T {
+    fn compress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        (**self).compress(input)
+    }
+    
+    fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        (**self).decompress(input)
+    }
+    
+    fn compress_block(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        (**self).compress_block(input)
+    }
+    
+    fn compress_chained(&self, input: &[u8], chain: ChainedBlock) -> Lzma2Result<Vec<u8>> {
+        (**self).compress_chained(input, chain)
+    }
+    
+    fn reserve(&self) -> Lzma2Result<EngineGuard<'_>> {
+        (**self).reserve()
+    }
+    
+    fn compress_batch(&self, blocks: &[&[u8]]) -> Vec<Lzma2Result<Vec<u8>>> {
+        (**self).compress_batch(blocks)
+    }
+    
+    fn decompress_block(&self, input: &[u8], uncompressed_len: usize) -> Lzma2Result<Vec<u8>> {
+        (**self).decompress_block(input, uncompressed_len)
+    }
+    
+    fn get_performance_metrics(&self) -> Lzma2Result<PerformanceMetrics> {
+        (**self).get_performance_metrics()
+    }
+}
+
 /// Device discovery and management
 pub struct DeviceManager;
 
@@ -0,0 +1,340 @@
+//! Blocking offload thread for LZMA2 FPGA Compression Driver
+//!
+//! The engine runs one job at a time and its API is synchronous.
+//! `BlockingOffload` owns a single thread that runs queued jobs one after
+//! another through that API, blocking for as long as each job takes: on the
+//! interrupt eventfd when the device has one attached, by polling the status
+//! register otherwise. Each request returns a `Completion` future that is
+//! woken when its job finishes, which keeps the blocking calls off executor
+//! threads. It is not an event loop; jobs do not overlap on the device.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use super::HardwareCompressionDevice;
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Asynchronous counterpart of `HardwareCompressionDevice`
+///
+/// Inputs are taken by value since jobs outlive the call.
+pub trait AsyncCompressionDevice {
+    /// Compress a full block, see `HardwareCompressionDevice::compress`
+    fn compress_async(&self, input: Vec<u8>) -> Completion<Vec<u8>>;
+
+    /// Decompress a full block, see `HardwareCompressionDevice::decompress`
+    fn decompress_async(&self, input: Vec<u8>) -> Completion<Vec<u8>>;
+
+    /// Compress a block of 1 to `BLOCK_SIZE` bytes
+    fn compress_block_async(&self, input: Vec<u8>) -> Completion<Vec<u8>>;
+
+    /// Decompress a block that restores to `uncompressed_len` bytes
+    fn decompress_block_async(&self, input: Vec<u8>, uncompressed_len: usize) -> Completion<Vec<u8>>;
+}
+
+/// Result slot shared by a job and its future
+struct Slot<T> {
+    result: Option<Lzma2Result<T>>,
+    waker: Option<Waker>,
+}
+
+fn lock<T>(slot: &Mutex<Slot<T>>) -> MutexGuard<'_, Slot<T>> {
+    slot.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Future resolving to the result of a queued job
+#[must_use = "the job runs regardless, but its result is lost unless awaited"]
+pub struct Completion<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Completion<T> {
+    /// Whether the job has finished and the future is ready
+    pub fn is_complete(&self) -> bool {
+        lock(&self.slot).result.is_some()
+    }
+}
+
+impl<T> std::fmt::Debug for Completion<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Completion")
+            .field("complete", &self.is_complete())
+            .finish()
+    }
+}
+
+impl<T> Future for Completion<T> {
+    type Output = Lzma2Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = lock(&self.slot);
+        if let Some(result) = slot.result.take() {
+            return Poll::Ready(result);
+        }
+
+        match &slot.waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {},
+            _ => slot.waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
+
+/// Job side of a `Completion`
+///
+/// Fails the future if dropped before completing, e.g. when the offload
+/// thread stops.
+struct Completer<T> {
+    slot: Option<Arc<Mutex<Slot<T>>>>,
+}
+
+impl<T> Completer<T> {
+    fn complete(mut self, result: Lzma2Result<T>) {
+        if let Some(slot) = self.slot.take() {
+            Self::fill(&slot, result);
+        }
+    }
+
+    fn fill(slot: &Mutex<Slot<T>>, result: Lzma2Result<T>) {
+        let waker = {
+            let mut slot = lock(slot);
+            slot.result = Some(result);
+            slot.waker.take()
+        };
+
+        // Wake outside the lock, the task may poll right away
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            Self::fill(&slot, Err(Lzma2Error::ProcessingError(
+                "Offload thread stopped before the job ran".to_string()
+            )));
+        }
+    }
+}
+
+type Job<D> = Box<dyn FnOnce(&D) + Send>;
+
+/// Device whose blocking jobs run on a dedicated thread
+///
+/// Jobs run in submission order, each to completion before the next starts.
+/// Dropping the offload finishes the queued jobs before the thread exits.
+pub struct BlockingOffload<D> {
+    device: Arc<D>,
+    jobs: Option<Sender<Job<D>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<D: HardwareCompressionDevice + Send + Sync + 'static> BlockingOffload<D> {
+    /// Start an offload thread for `device`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::DeviceInitError` if the thread cannot be spawned
+    pub fn new(device: D) -> Lzma2Result<Self> {
+        let device = Arc::new(device);
+        let (jobs, queue) = mpsc::channel::<Job<D>>();
+
+        let worker = Arc::clone(&device);
+        let thread = thread::Builder::new()
+            .name("lzma2-offload".to_string())
+            .spawn(move || {
+                for job in queue {
+                    job(&worker);
+                }
+            })
+            .map_err(|e| Lzma2Error::DeviceInitError(
+                format!("Failed to spawn offload thread: {}", e)
+            ))?;
+
+        Ok(Self {
+            device,
+            jobs: Some(jobs),
+            thread: Some(thread),
+        })
+    }
+
+    /// Device driven by the offload thread
+    ///
+    /// Synchronous jobs started here are not ordered with the thread's;
+    /// prefer `submit` unless the device serializes its operations.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Queue `job` to run on the offload thread
+    pub fn submit<T, F>(&self, job: F) -> Completion<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&D) -> Lzma2Result<T> + Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot {
+            result: None,
+            waker: None,
+        }));
+        let completer = Completer { slot: Some(Arc::clone(&slot)) };
+
+        // A failed send drops the completer, failing the future
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(Box::new(move |device: &D| completer.complete(job(device))));
+        }
+        Completion { slot }
+    }
+}
+
+impl<D: HardwareCompressionDevice + Send + Sync + 'static> AsyncCompressionDevice for BlockingOffload<D> {
+    fn compress_async(&self, input: Vec<u8>) -> Completion<Vec<u8>> {
+        self.submit(move |device| device.compress(&input))
+    }
+
+    fn decompress_async(&self, input: Vec<u8>) -> Completion<Vec<u8>> {
+        self.submit(move |device| device.decompress(&input))
+    }
+
+    fn compress_block_async(&self, input: Vec<u8>) -> Completion<Vec<u8>> {
+        self.submit(move |device| device.compress_block(&input))
+    }
+
+    fn decompress_block_async(&self, input: Vec<u8>, uncompressed_len: usize) -> Completion<Vec<u8>> {
+        self.submit(move |device| device.decompress_block(&input, uncompressed_len))
+    }
+}
+
+impl<D> std::fmt::Debug for BlockingOffload<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockingOffload")
+            .field("running", &self.thread.is_some())
+            .finish()
+    }
+}
+
+impl<D> Drop for BlockingOffload<D> {
+    fn drop(&mut self) {
+        // Closing the queue ends the thread once it has drained
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{ControllerState, SimulatedDevice, BLOCK_SIZE};
+    use crate::device::simulator::tests::sample;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    /// Waker unparking the test thread and counting its wakeups
+    struct ThreadWaker {
+        thread: thread::Thread,
+        wakes: AtomicUsize,
+    }
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    fn thread_waker() -> Arc<ThreadWaker> {
+        Arc::new(ThreadWaker {
+            thread: thread::current(),
+            wakes: AtomicUsize::new(0),
+        })
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(thread_waker());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    /// Jobs the simulated engine has started
+    fn jobs_run(device: &SimulatedDevice) -> usize {
+        device.io().transitions().into_iter()
+            .filter(|&state| state == ControllerState::Init)
+            .count()
+    }
+
+    #[test]
+    fn test_concurrent_requests() -> Lzma2Result<()> {
+        let offload = BlockingOffload::new(SimulatedDevice::simulated())?;
+        let reference = SimulatedDevice::simulated();
+
+        // All blocks are queued before the first is awaited
+        let inputs: Vec<Vec<u8>> = (1..=8).map(|seed| sample(BLOCK_SIZE, seed)).collect();
+        let pending: Vec<_> = inputs.iter()
+            .map(|input| offload.compress_block_async(input.clone()))
+            .collect();
+
+        for (input, completion) in inputs.iter().zip(pending) {
+            let compressed = block_on(completion)?;
+            assert_eq!(compressed, reference.compress_block(input)?);
+
+            let restored = block_on(offload.decompress_block_async(compressed, input.len()))?;
+            assert_eq!(&restored, input);
+        }
+        assert_eq!(jobs_run(offload.device()), inputs.len());
+
+        // Device errors reach the awaiting task
+        assert!(block_on(offload.compress_async(vec![0; 100])).is_err());
+        assert!(block_on(offload.decompress_block_async(vec![0xFF; 64], 1000)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wakes_pending_task() -> Lzma2Result<()> {
+        let offload = BlockingOffload::new(SimulatedDevice::simulated())?;
+        let (release, gate) = mpsc::channel::<()>();
+
+        let mut completion = offload.submit(move |device: &SimulatedDevice| {
+            gate.recv().ok();
+            device.compress_block(&sample(BLOCK_SIZE, 5))
+        });
+        let queued = offload.compress_block_async(sample(BLOCK_SIZE, 6));
+
+        // The job is held back, so the first poll registers the waker
+        let waker = thread_waker();
+        let task_waker = Waker::from(Arc::clone(&waker));
+        let mut cx = Context::from_waker(&task_waker);
+        assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+        assert!(!queued.is_complete());
+
+        release.send(()).unwrap();
+        while waker.wakes.load(Ordering::SeqCst) == 0 {
+            thread::park();
+        }
+        assert!(completion.is_complete());
+        match Pin::new(&mut completion).poll(&mut cx) {
+            Poll::Ready(result) => assert!(!result?.is_empty()),
+            Poll::Pending => panic!("woken future is not ready"),
+        }
+
+        // Dropping the offload completes queued jobs
+        let device = Arc::clone(&offload.device);
+        drop(offload);
+        assert!(queued.is_complete());
+        assert!(block_on(queued).is_ok());
+        assert_eq!(jobs_run(&device), 2);
+
+        Ok(())
+    }
+}
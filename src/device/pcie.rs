@@ -1,12 +1,23 @@
 //! PCIe Device Implementation for LZMA2 FPGA Compression Driver
 
-use super::{DeviceConfig, HardwareCompressionDevice};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::{DeviceConfig, EngineFeatures};
+use super::engine::{EngineGuard, EngineLock, Hold};
+use super::backend::{RegisterIo, SysfsBar, VfioDevice, VfioRegion};
+use super::dma::{DmaAllocator, DmaEngine};
+use super::stream::StreamEngine;
+use crate::transfer::{DataTransfer, MmioTransfer};
+use super::interrupt::InterruptSource;
+use super::sysfs::{PciAddress, PciFunction, SysfsScanner};
 use crate::error::{Lzma2Error, Lzma2Result};
-use crate::device::metrics::PerformanceMetrics;
-use crate::transfer::TransferStrategy;
+use crate::transfer::{TransferConfig, TransferStrategy};
 
 /// PCIe Device Constants
-mod constants {
+pub(super) mod constants {
+    use std::time::Duration;
+    
     /// Default Vendor ID for LZMA2 FPGA Device
     pub const VENDOR_ID: u16 = 0x1234;
     
@@ -15,93 +26,500 @@ mod constants {
     
     /// BAR Index for register access
     pub const DEFAULT_BAR_INDEX: usize = 0;
+    
+    /// Offset of the 32KB input data window
+    pub const INPUT_WINDOW: u64 = 0x8000;
+    
+    /// Offset of the output data window
+    pub const OUTPUT_WINDOW: u64 = 0x10000;
+    
+    /// Size of the output data window
+    pub const OUTPUT_WINDOW_SIZE: u64 = 0x10000;
+    
+    /// Minimum BAR size covering the register file and data windows
+    pub const MIN_BAR_SIZE: u64 = 0x20000;
+    
+    /// Control register: start bit
+    pub const CTRL_START: u32 = 1 << 0;
+    
+    /// Control register: decompression mode
+    pub const CTRL_DECOMPRESS: u32 = 1 << 16;
+    
+    /// Control register: take input from and deliver output to the
+    /// streaming rings instead of the data windows
+    pub const CTRL_STREAM: u32 = 1 << 17;
+    
+    /// Control register: encode into the LZMA stream left open by the
+    /// previous job instead of starting a new one
+    pub const CTRL_CONTINUE: u32 = 1 << 18;
+    
+    /// Control register: keep the LZMA stream open after this block and
+    /// deliver only the bytes completed so far
+    pub const CTRL_KEEP_OPEN: u32 = 1 << 19;
+    
+    /// Control register: close the LZMA stream with the end-of-stream marker
+    pub const CTRL_END_MARKER: u32 = 1 << 20;
+    
+    /// Control register: reset bit
+    pub const CTRL_RESET: u32 = 1 << 31;
+    
+    /// Job buffers cycled by batch compression
+    pub const BATCH_BUFFERS: u32 = 2;
+    
+    /// Default completion timeout for a single job
+    pub const COMPLETION_TIMEOUT: Duration = Duration::from_millis(100);
+    
+    /// Status polling interval when no interrupt source is attached
+    pub const POLL_INTERVAL: Duration = Duration::from_micros(10);
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            vendor_id: constants::VENDOR_ID,
+            device_id: constants::DEVICE_ID,
+            bar_index: constants::DEFAULT_BAR_INDEX,
+            features: EngineFeatures::default(),
+        }
+    }
 }
 
-/// Register map for PCIe device
+/// Register map for PCIe device (byte offsets into the BAR)
 #[derive(Debug)]
-struct RegisterMap {
-    control: u32,
-    status: u32,
-    input_data: u64,
-    output_data: u64,
-    performance_counters: u64,
+pub(super) struct RegisterMap {
+    pub(super) control: u64,
+    pub(super) status: u64,
+    /// Only decoded by engines with `EngineFeatures::short_blocks`
+    pub(super) block_length: u64,
+    pub(super) input_data: u64,
+    pub(super) output_data: u64,
+    pub(super) performance_counters: u64,
+    pub(super) dma: u64,
+    pub(super) stream: u64,
+    pub(super) queue: u64,
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        Self {
+            control: 0x00,
+            status: 0x04,
+            block_length: 0x0C,
+            input_data: constants::INPUT_WINDOW,
+            output_data: constants::OUTPUT_WINDOW,
+            performance_counters: 0x20,
+            dma: 0x60,
+            stream: 0x80,
+            queue: 0xC0,
+        }
+    }
 }
 
 /// PCIe Device for LZMA2 FPGA Compression
-pub struct PcieDevice {
+///
+/// Generic over the register backend; the default maps the BAR through
+/// sysfs. The device is `Send + Sync`: jobs from several threads run one
+/// at a time, each holding the engine from reset to readback. A chained
+/// LZMA stream spans several jobs; `reserve` keeps other threads' jobs out
+/// until it closes, and jobs of the same thread that would reset the
+/// engine in between fail with `Lzma2Error::EngineBusy`.
+pub struct PcieDevice<B: RegisterIo = SysfsBar> {
     /// Device configuration
     config: DeviceConfig,
     
-    /// Low-level PCIe handle
-    handle: PcieHandle,
+    /// PCI function backing this device, if known
+    function: Option<PciFunction>,
+    
+    /// Register I/O backend
+    pub(super) io: B,
     
     /// Register mapping
-    registers: RegisterMap,
+    pub(super) registers: RegisterMap,
+    
+    /// Held for the control/status sequence of a job
+    engine: EngineLock,
     
     /// Transfer strategy
-    transfer_strategy: TransferStrategy,
-}
-
-/// Low-level PCIe handle abstraction
-struct PcieHandle {
-    // Placeholder for actual PCIe library handle
-    raw_handle: *mut std::ffi::c_void,
+    transfer: Mutex<Box<dyn DataTransfer>>,
+    
+    /// Completion interrupt, or `None` to poll the status register
+    pub(super) interrupt: Option<Box<dyn InterruptSource>>,
+    
+    /// Timeout applied to each job
+    pub(super) completion_timeout: Duration,
 }
 
 impl PcieDevice {
     /// Probe for available LZMA2 FPGA devices
     pub fn probe() -> Lzma2Result<Vec<Self>> {
+        Self::probe_with(&SysfsScanner::default(), &DeviceConfig::default())
+    }
+    
+    /// Probe for devices matching `config` below the scanner's sysfs root
+    ///
+    /// Functions that fail to open are skipped, so one bad function does
+    /// not hide the others.
+    ///
+    /// # Errors
+    /// Returns the last open error if no matching function could be opened,
+    /// or `Lzma2Error::DeviceInitError` if none matched
+    pub fn probe_with(scanner: &SysfsScanner, config: &DeviceConfig) -> Lzma2Result<Vec<Self>> {
         let mut devices = Vec::new();
+        let mut failure = None;
+        for function in scanner.scan(config)? {
+            let address = function.address;
+            match Self::open(function, config.clone()) {
+                Ok(device) => devices.push(device),
+                Err(e) => {
+                    tracing::warn!("Skipping LZMA2 FPGA device {}: {}", address, e);
+                    failure = Some(e);
+                },
+            }
+        }
         
-        // TODO: Implement actual device discovery
-        // 1. Scan PCIe bus
-        // 2. Filter devices by vendor and device ID
-        // 3. Create PcieDevice instances
-        
-        if devices.is_empty() {
+        if !devices.is_empty() {
+            Ok(devices)
+        } else if let Some(e) = failure {
+            Err(e)
+        } else {
             Err(Lzma2Error::DeviceInitError(
                 "No LZMA2 FPGA devices found".to_string()
             ))
-        } else {
-            Ok(devices)
         }
     }
     
     /// Create a new PCIe device with default configuration
+    ///
+    /// Opens the first matching device found in sysfs.
     pub fn new() -> Lzma2Result<Self> {
-        let config = DeviceConfig {
-            vendor_id: constants::VENDOR_ID,
-            device_id: constants::DEVICE_ID,
-            bar_index: constants::DEFAULT_BAR_INDEX,
-        };
+        Self::probe()?.into_iter().next()
+            .ok_or(Lzma2Error::DeviceInitError(
+                "No LZMA2 FPGA devices found".to_string()
+            ))
+    }
+    
+    /// Open a device on an enumerated PCI function
+    pub fn open(function: PciFunction, config: DeviceConfig) -> Lzma2Result<Self> {
+        let io = SysfsBar::open(&function, config.bar_index)?;
+        let mut device = Self::with_backend(io, config)?;
+        device.function = Some(function);
+        Ok(device)
+    }
+}
+
+impl PcieDevice<VfioRegion> {
+    /// Open a device bound to `vfio-pci`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the IOMMU group or BAR cannot be set up
+    pub fn open_vfio(function: PciFunction, config: DeviceConfig) -> Lzma2Result<Self> {
+        let vfio = VfioDevice::open(function.address)?;
+        Ok(Self::with_vfio(&vfio, config)?.with_function(function))
+    }
+    
+    /// Create a device on an opened VFIO device
+    ///
+    /// Completion is signalled through vector 0 of MSI-X or MSI when the
+    /// device supports either; otherwise the status register is polled.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the BAR cannot be mapped
+    pub fn with_vfio(vfio: &VfioDevice, config: DeviceConfig) -> Lzma2Result<Self> {
+        let bar_index = u32::try_from(config.bar_index).map_err(|_| Lzma2Error::InputValidationError(
+            format!("Invalid BAR index {}", config.bar_index)
+        ))?;
+        let device = Self::with_backend(vfio.map_bar(bar_index)?, config)?;
         
-        let handle = Self::open_device(&config)?;
+        match vfio.enable_msix(0).or_else(|_| vfio.enable_msi(0)) {
+            Ok(interrupt) => Ok(device.with_interrupt(interrupt)),
+            Err(e) => {
+                tracing::debug!("MSI unavailable on {}, polling for completion: {}", vfio.address(), e);
+                Ok(device)
+            },
+        }
+    }
+    
+    /// VFIO device backing the BAR, for DMA buffer allocation
+    pub fn vfio(&self) -> Option<&VfioDevice> {
+        self.io.device()
+    }
+}
+
+impl<B: RegisterIo> PcieDevice<B> {
+    /// Create a device on top of an already opened register backend
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the BAR is too small for the register map
+    pub fn with_backend(io: B, config: DeviceConfig) -> Lzma2Result<Self> {
+        if (io.len() as u64) < constants::MIN_BAR_SIZE {
+            return Err(Lzma2Error::DeviceInitError(
+                format!("BAR{} is too small: {} bytes", config.bar_index, io.len())
+            ));
+        }
         
         Ok(Self {
             config,
-            handle,
-            registers: RegisterMap {
-                control: 0x00,
-                status: 0x04,
-                input_data: 0x08,
-                output_data: 0x0C,
-                performance_counters: 0x20,
-            },
-            transfer_strategy: TransferStrategy::Mmio,
+            function: None,
+            io,
+            registers: RegisterMap::default(),
+            engine: EngineLock::default(),
+            transfer: Mutex::new(Box::new(MmioTransfer)),
+            interrupt: None,
+            completion_timeout: constants::COMPLETION_TIMEOUT,
         })
     }
     
-    /// Open device internal method
-    fn open_device(config: &DeviceConfig) -> Lzma2Result<PcieHandle> {
-        // TODO: Actual PCIe device opening process
-        // 1. Device search
-        // 2. Resource mapping
-        // 3. Device activation
-        
-        Ok(PcieHandle {
-            raw_handle: std::ptr::null_mut(),
+    /// Attach the PCI function the backend belongs to
+    pub fn with_function(mut self, function: PciFunction) -> Self {
+        self.function = Some(function);
+        self
+    }
+    
+    /// Wait for completion interrupts from `source` instead of polling
+    pub fn with_interrupt(mut self, source: impl InterruptSource + 'static) -> Self {
+        self.interrupt = Some(Box::new(source));
+        self
+    }
+    
+    /// Set the timeout applied to each compression job
+    pub fn with_completion_timeout(mut self, timeout: Duration) -> Self {
+        self.completion_timeout = timeout;
+        self
+    }
+    
+    /// Select how job data moves between host and device
+    ///
+    /// Uses the default `TransferConfig` for the strategy; see
+    /// `with_transfer_config`.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the backend cannot perform the strategy
+    pub fn with_transfer_strategy(self, strategy: TransferStrategy) -> Lzma2Result<Self> {
+        self.with_transfer_config(TransferConfig {
+            strategy,
+            ..TransferConfig::default()
         })
     }
+    
+    /// Configure how job data moves between host and device
+    ///
+    /// See `set_transfer_config`.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the backend cannot perform the strategy
+    pub fn with_transfer_config(self, config: TransferConfig) -> Lzma2Result<Self> {
+        self.set_transfer_config(&config)?;
+        Ok(self)
+    }
+    
+    /// Move job data with a custom strategy
+    pub fn with_transfer(self, transfer: impl DataTransfer + 'static) -> Self {
+        self.set_transfer(transfer);
+        self
+    }
+    
+    /// Switch to a built-in strategy
+    ///
+    /// `TransferStrategy::Dma` sets up the descriptor ring and
+    /// `TransferStrategy::Streaming` two rings of `buffer_size` bytes; both
+    /// need a backend with DMA support, such as a VFIO mapping. The current
+    /// strategy is kept on error. Waits for the running job, since the
+    /// rings are programmed through the engine's registers.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the backend cannot perform the strategy
+    pub fn set_transfer_config(&self, config: &TransferConfig) -> Lzma2Result<()> {
+        let _engine = self.lock_engine(Hold::Reset)?;
+        let transfer: Box<dyn DataTransfer> = match config.strategy {
+            TransferStrategy::Mmio => Box::new(MmioTransfer),
+            TransferStrategy::Dma => Box::new(DmaEngine::new(
+                &self.io,
+                self.dma_allocator()?,
+                self.registers.dma,
+                constants::OUTPUT_WINDOW_SIZE as usize,
+            )?),
+            TransferStrategy::Streaming => Box::new(StreamEngine::new(
+                &self.io,
+                self.dma_allocator()?,
+                self.registers.stream,
+                config.buffer_size,
+            )?),
+        };
+        
+        *self.lock_transfer() = transfer;
+        Ok(())
+    }
+    
+    /// Switch to a custom strategy
+    ///
+    /// Takes effect from the next job.
+    pub fn set_transfer(&self, transfer: impl DataTransfer + 'static) {
+        *self.lock_transfer() = Box::new(transfer);
+    }
+    
+    /// Built-in strategy in use, `None` for a custom one
+    pub fn transfer_strategy(&self) -> Option<TransferStrategy> {
+        self.lock_transfer().strategy()
+    }
+    
+    /// Take the engine for a job, a command queue or another register
+    /// sequence
+    ///
+    /// Acquired before the transfer strategy's lock.
+    pub(super) fn lock_engine(&self, hold: Hold) -> Lzma2Result<EngineGuard<'_>> {
+        self.engine.lock(hold)
+    }
+    
+    /// Lock the transfer strategy for the duration of a job
+    pub(super) fn lock_transfer(&self) -> MutexGuard<'_, Box<dyn DataTransfer>> {
+        self.transfer.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    
+    fn dma_allocator(&self) -> Lzma2Result<&dyn DmaAllocator> {
+        self.io.dma_allocator().ok_or_else(|| Lzma2Error::TransferError(
+            "Register backend does not support DMA".to_string()
+        ))
+    }
+    
+    /// Whether completion is interrupt-driven
+    pub fn has_interrupt(&self) -> bool {
+        self.interrupt.is_some()
+    }
+    
+    /// Device configuration
+    pub fn config(&self) -> &DeviceConfig {
+        &self.config
+    }
+    
+    /// Register I/O backend of the device
+    pub fn io(&self) -> &B {
+        &self.io
+    }
+    
+    /// PCI function backing this device, if known
+    pub fn function(&self) -> Option<&PciFunction> {
+        self.function.as_ref()
+    }
+    
+    /// Bus/device/function address of the device, if known
+    pub fn address(&self) -> Option<PciAddress> {
+        self.function.as_ref().map(|function| function.address)
+    }
+    
+    /// NUMA node the device is attached to, if known
+    pub fn numa_node(&self) -> Option<u32> {
+        self.function.as_ref().and_then(|function| function.numa_node)
+    }
+    
+    /// Size of the given BAR, or `None` if it is not implemented or unknown
+    pub fn bar_size(&self, index: usize) -> Option<u64> {
+        self.function.as_ref().and_then(|function| function.bar_size(index))
+    }
 }
 
-// The rest of the implementation will be added in the next step
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::sysfs::tests::create_function;
+    
+    #[test]
+    fn test_probe_fake_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        create_function(root.path(), "0000:41:00.0", 0x1234, 0x5678, 1, 0x20000);
+        create_function(root.path(), "0000:41:00.1", 0x1234, 0x5678, 1, 0x20000);
+        create_function(root.path(), "0000:42:00.0", 0x10ee, 0x9038, 1, 0x20000);
+        
+        let scanner = SysfsScanner::new(root.path());
+        let devices = PcieDevice::probe_with(&scanner, &DeviceConfig::default()).unwrap();
+        
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].address().unwrap().to_string(), "0000:41:00.1");
+        assert_eq!(devices[1].numa_node(), Some(1));
+        assert_eq!(devices[1].bar_size(0), Some(0x20000));
+    }
+    
+    #[test]
+    fn test_probe_skips_bad_function() {
+        let root = tempfile::tempdir().unwrap();
+        create_function(root.path(), "0000:41:00.0", 0x1234, 0x5678, 1, 0x20000);
+        let bad = create_function(root.path(), "0000:41:00.1", 0x1234, 0x5678, 1, 0x20000);
+        std::fs::remove_file(bad.join("resource0")).unwrap();
+        
+        // The function that opens is still found
+        let scanner = SysfsScanner::new(root.path());
+        let devices = PcieDevice::probe_with(&scanner, &DeviceConfig::default()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].address().unwrap().to_string(), "0000:41:00.0");
+        
+        // With none left, its error is reported
+        std::fs::remove_file(root.path().join("0000:41:00.0/resource0")).unwrap();
+        assert!(PcieDevice::probe_with(&scanner, &DeviceConfig::default()).is_err());
+    }
+    
+    #[test]
+    fn test_probe_custom_config() {
+        let root = tempfile::tempdir().unwrap();
+        create_function(root.path(), "0000:42:00.0", 0x10ee, 0x9038, 0, 0x20000);
+        
+        let scanner = SysfsScanner::new(root.path());
+        assert!(PcieDevice::probe_with(&scanner, &DeviceConfig::default()).is_err());
+        
+        let config = DeviceConfig {
+            vendor_id: 0x10ee,
+            device_id: 0x9038,
+            bar_index: 0,
+            features: EngineFeatures::default(),
+        };
+        let devices = PcieDevice::probe_with(&scanner, &config).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].config().vendor_id, 0x10ee);
+    }
+    
+    #[test]
+    fn test_probe_rejects_missing_bar() {
+        let root = tempfile::tempdir().unwrap();
+        create_function(root.path(), "0000:41:00.0", 0x1234, 0x5678, 0, 0x20000);
+        
+        let config = DeviceConfig {
+            bar_index: 2,
+            ..DeviceConfig::default()
+        };
+        assert!(PcieDevice::probe_with(&SysfsScanner::new(root.path()), &config).is_err());
+    }
+    
+    #[test]
+    fn test_with_vfio() {
+        use crate::device::backend::FakeVfio;
+        use std::sync::Arc;
+        
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("0000:41:00.0");
+        std::fs::create_dir(&dir).unwrap();
+        std::os::unix::fs::symlink("../../../kernel/iommu_groups/3", dir.join("iommu_group")).unwrap();
+        let address = "0000:41:00.0".parse().unwrap();
+        
+        let vfio = VfioDevice::open_with(Arc::new(FakeVfio::new(0x20000)), root.path(), address).unwrap();
+        let device = PcieDevice::with_vfio(&vfio, DeviceConfig::default()).unwrap();
+        assert!(device.has_interrupt());
+        assert!(device.vfio().unwrap().alloc_dma(4096).is_ok());
+        
+        // Without MSI the device falls back to polling
+        let fake = Arc::new(FakeVfio::new(0x20000).without_msi());
+        let vfio = VfioDevice::open_with(fake, root.path(), address).unwrap();
+        assert!(!PcieDevice::with_vfio(&vfio, DeviceConfig::default()).unwrap().has_interrupt());
+    }
+    
+    #[test]
+    fn test_with_backend() {
+        use crate::device::backend::MockRegisters;
+        
+        assert!(PcieDevice::with_backend(MockRegisters::new(0x1000), DeviceConfig::default()).is_err());
+        
+        let device = PcieDevice::with_backend(MockRegisters::new(0x20000), DeviceConfig::default()).unwrap();
+        assert_eq!(device.address(), None);
+        assert_eq!(device.io().len(), 0x20000);
+        
+        // Ring-based strategies need a backend that can allocate DMA memory
+        assert!(device.with_transfer_strategy(TransferStrategy::Streaming).is_err());
+    }
+}
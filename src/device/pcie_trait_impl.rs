@@ -1,86 +1,175 @@
 //! PCIe Device Trait Implementation
 
-use super::{PcieDevice, HardwareCompressionDevice};
+use super::{ChainedBlock, EngineGuard, PcieDevice, HardwareCompressionDevice, BLOCK_SIZE};
+use super::backend::RegisterIo;
+use super::engine::Hold;
+use super::pcie::constants;
+use super::queue::{CommandQueue, JobId};
+use super::status::{RegisterSnapshot, StatusRegister, PERF_COUNTER_COUNT};
 use crate::error::{Lzma2Error, Lzma2Result};
 use crate::device::metrics::PerformanceMetrics;
+use crate::lzma;
+use crate::transfer::{JobControl, TransferContext};
 
-impl HardwareCompressionDevice for PcieDevice {
+use std::time::{Duration, Instant};
+
+impl<B: RegisterIo> HardwareCompressionDevice for PcieDevice<B> {
     fn compress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
         // Input size validation
-        if input.len() != 32 * 1024 {
+        if input.len() != BLOCK_SIZE {
             return Err(Lzma2Error::InputValidationError(
                 format!("Input size must be 32KB. Current size: {}", input.len())
             ));
         }
         
-        // Device reset
-        self.reset()?;
+        self.compress_block(input)
+    }
+    
+    fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        self.decompress_block(input, BLOCK_SIZE)
+    }
+    
+    fn compress_block(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        self.compress_chained(input, ChainedBlock {
+            continues: false,
+            closes: true,
+            end_marker: false,
+        })
+    }
+    
+    fn compress_chained(&self, input: &[u8], chain: ChainedBlock) -> Lzma2Result<Vec<u8>> {
+        // Input size validation
+        if input.is_empty() || input.len() > BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Block size must be 1 to {} bytes. Current size: {}", BLOCK_SIZE, input.len())
+            ));
+        }
         
-        // Transfer input data
-        self.transfer_input_data(input)?;
+        // The engine always loads a full window unless it takes a block
+        // length, so a short self-contained block is encoded in software
+        if input.len() < BLOCK_SIZE && !self.has_short_blocks() {
+            if chain.continues || !chain.closes {
+                return Err(Lzma2Error::InputValidationError(
+                    format!("Engine cannot chain a short block of {} bytes", input.len())
+                ));
+            }
+            return Ok(lzma::encode_block(input, chain.end_marker));
+        }
         
-        // Start compression
-        self.start_compression()?;
+        // Engines without the chaining bits ignore them and flush every
+        // block as a stream of its own
+        if (chain.continues || !chain.closes || chain.end_marker) && !self.has_chained_streams() {
+            return Err(Lzma2Error::InputValidationError(
+                "Engine does not support LZMA streams spanning several blocks".to_string()
+            ));
+        }
         
-        // Wait for completion
-        self.wait_for_completion()?;
+        let engine = self.lock_engine(if chain.continues { Hold::Keep } else { Hold::Reset })?;
+        let mut control = constants::CTRL_START;
+        if chain.continues {
+            // A reset would discard the open stream, so only acknowledge
+            // the previous job
+            control |= constants::CTRL_CONTINUE;
+            self.write_register(self.registers.control, 0)?;
+        } else {
+            self.reset()?;
+        }
+        if !chain.closes {
+            control |= constants::CTRL_KEEP_OPEN;
+        } else if chain.end_marker {
+            control |= constants::CTRL_END_MARKER;
+        }
         
-        // Read output data
-        let compressed_data = self.read_output_data()?;
+        // Transfer input, compress and read back the output
+        let output = self.run_job(input, input.len(), control);
         
-        Ok(compressed_data)
+        // Jobs of this thread that reset the engine fail until the stream
+        // is closed
+        engine.set_stream_open(output.is_ok() && !chain.closes);
+        output
     }
     
-    fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+    fn reserve(&self) -> Lzma2Result<EngineGuard<'_>> {
+        self.lock_engine(Hold::Keep)
+    }
+    
+    fn compress_batch(&self, blocks: &[&[u8]]) -> Vec<Lzma2Result<Vec<u8>>> {
+        let mut results: Vec<Option<Lzma2Result<Vec<u8>>>> = blocks.iter().map(|_| None).collect();
+        
+        // A command queue keeps several blocks on the engine at once
+        if let Ok(mut queue) = self.command_queue(constants::BATCH_BUFFERS) {
+            if let Err(e) = Self::run_batch(&mut queue, blocks, &mut results) {
+                tracing::warn!("Command queue failed, finishing batch one block at a time: {}", e);
+            }
+        }
+        
+        // The single job window runs one block at a time, with the transfer
+        // strategy preparing each block while the previous one is processed
+        self.run_serial_batch(blocks, &mut results);
+        
+        // Invalid and software-encoded blocks
+        blocks.iter()
+            .zip(results)
+            .map(|(block, result)| result.unwrap_or_else(|| self.compress_block(block)))
+            .collect()
+    }
+    
+    fn decompress_block(&self, input: &[u8], uncompressed_len: usize) -> Lzma2Result<Vec<u8>> {
         // Input validation
         if input.is_empty() {
             return Err(Lzma2Error::InputValidationError(
                 "Compressed data is empty".to_string()
             ));
         }
+        if uncompressed_len == 0 || uncompressed_len > BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Block size must be 1 to {} bytes. Requested size: {}", BLOCK_SIZE, uncompressed_len)
+            ));
+        }
+        
+        // Decode in software when the engine has no decompression mode or
+        // cannot stop short of a full block
+        if !self.has_decode_engine() || (uncompressed_len < BLOCK_SIZE && !self.has_short_blocks()) {
+            return lzma::decode_block(input, uncompressed_len);
+        }
+        if input.len() > BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Compressed block of {} bytes exceeds the 32KB input window", input.len())
+            ));
+        }
         
         // Device reset
+        let _engine = self.lock_engine(Hold::Reset)?;
         self.reset()?;
         
-        // Transfer compressed data
-        self.transfer_compressed_data(input)?;
-        
-        // Start decompression
-        self.start_decompression()?;
-        
-        // Wait for completion
-        self.wait_for_completion()?;
-        
-        // Read output data
-        let decompressed_data = self.read_output_data()?;
+        // Transfer compressed data, decompress and read back the output
+        let output = self.run_job(input, uncompressed_len, constants::CTRL_START | constants::CTRL_DECOMPRESS)?;
+        if output.len() != uncompressed_len {
+            return Err(Lzma2Error::ProcessingError(
+                format!("Block decompressed to {} bytes, expected {}", output.len(), uncompressed_len)
+            ));
+        }
         
-        Ok(decompressed_data)
+        Ok(output)
     }
     
     fn get_performance_metrics(&self) -> Lzma2Result<PerformanceMetrics> {
-        // Performance counters reading
-        let mut metrics = PerformanceMetrics::default();
-        
-        // Register reading (placeholder)
-        metrics.total_bytes_processed = self.read_register(
-            self.registers.performance_counters
-        )?;
-        
-        metrics.compressed_bytes = self.read_register(
-            self.registers.performance_counters + 4
-        )?;
-        
-        metrics.cycles = self.read_register(
-            self.registers.performance_counters + 8
-        )?;
+        // Counters of the last finished job, not one in progress
+        let _engine = self.lock_engine(Hold::Keep)?;
+        let counters = self.registers.performance_counters;
         
-        metrics.cache_metrics.hits = self.read_register(
-            self.registers.performance_counters + 12
-        )?;
+        // Performance counters reading
+        let mut metrics = PerformanceMetrics {
+            total_bytes_processed: u64::from(self.read_register(counters)?),
+            compressed_bytes: u64::from(self.read_register(counters + 4)?),
+            cycles: u64::from(self.read_register(counters + 8)?),
+            ..PerformanceMetrics::default()
+        };
         
-        metrics.cache_metrics.misses = self.read_register(
-            self.registers.performance_counters + 16
-        )?;
+        metrics.cache_metrics.hits = u64::from(self.read_register(counters + 12)?);
+        metrics.cache_metrics.misses = u64::from(self.read_register(counters + 16)?);
+        metrics.match_hits = u64::from(self.read_register(counters + 20)?);
+        metrics.literal_count = u64::from(self.read_register(counters + 24)?);
         
         // Derived metrics calculation
         metrics.calculate_compression_ratio();
@@ -90,13 +179,128 @@ impl HardwareCompressionDevice for PcieDevice {
     }
 }
 
-impl PcieDevice {
+impl<B: RegisterIo> PcieDevice<B> {
+    /// Whether the engine implements the decompression mode
+    ///
+    /// Without it, `decompress` runs in software. Set through
+    /// `EngineFeatures::decode_engine`.
+    pub fn has_decode_engine(&self) -> bool {
+        self.config().features.decode_engine
+    }
+    
+    /// Whether the engine takes blocks shorter than `BLOCK_SIZE`
+    ///
+    /// Without it, short blocks are encoded and decoded in software.
+    pub fn has_short_blocks(&self) -> bool {
+        self.config().features.short_blocks
+    }
+    
+    /// Whether the engine can keep an LZMA stream open across jobs
+    ///
+    /// Without it, `compress_chained` only takes self-contained blocks.
+    pub fn has_chained_streams(&self) -> bool {
+        self.config().features.chained_streams
+    }
+    
+    /// Whether the engine takes jobs from a command queue
+    pub fn has_command_queue(&self) -> bool {
+        self.config().features.command_queue
+    }
+    
+    /// Set up a command queue holding up to `depth` jobs in flight
+    ///
+    /// The engine serves only the queue until it is dropped. Jobs of other
+    /// threads wait for it; jobs of the thread holding it fail with
+    /// `Lzma2Error::EngineBusy`.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::DeviceInitError` if the engine has no command
+    /// queue, `Lzma2Error::EngineBusy` if the calling thread holds the
+    /// engine, or `Lzma2Error` if the rings cannot be allocated
+    pub fn command_queue(&self, depth: u32) -> Lzma2Result<CommandQueue<'_, B>> {
+        if !self.has_command_queue() {
+            return Err(Lzma2Error::DeviceInitError(
+                "Engine does not implement a command queue".to_string()
+            ));
+        }
+        CommandQueue::new(self, depth)
+    }
+    
+    /// Compress `blocks` through `queue`, uploading the next block while
+    /// the engine works on the previous one and reading back each block as
+    /// it finishes
+    ///
+    /// Stops at the first queue failure, leaving the unfinished results
+    /// empty.
+    fn run_batch(
+        queue: &mut CommandQueue<'_, B>,
+        blocks: &[&[u8]],
+        results: &mut [Option<Lzma2Result<Vec<u8>>>],
+    ) -> Lzma2Result<()> {
+        let mut in_flight: Vec<(JobId, usize)> = Vec::with_capacity(queue.depth());
+        let mut next = 0;
+        
+        loop {
+            while next < blocks.len() && !queue.is_full() {
+                match queue.submit_compress(blocks[next]) {
+                    Ok(id) => in_flight.push((id, next)),
+                    Err(e @ Lzma2Error::InputValidationError(_)) => results[next] = Some(Err(e)),
+                    Err(e) => return Err(e),
+                }
+                next += 1;
+            }
+            if in_flight.is_empty() {
+                return Ok(());
+            }
+            
+            for job in queue.wait()? {
+                if let Some(position) = in_flight.iter().position(|&(id, _)| id == job.id) {
+                    let (_, index) = in_flight.swap_remove(position);
+                    results[index] = Some(job.result);
+                }
+            }
+        }
+    }
+    
+    /// Compress the remaining `blocks` the engine takes as they are, one
+    /// job after another
+    ///
+    /// The engine stays locked for the whole batch, and the transfer
+    /// strategy gets each block ahead of its job through `run_batch_job`.
+    fn run_serial_batch(&self, blocks: &[&[u8]], results: &mut [Option<Lzma2Result<Vec<u8>>>]) {
+        let pending: Vec<usize> = (0..blocks.len())
+            .filter(|&i| results[i].is_none())
+            .filter(|&i| {
+                let len = blocks[i].len();
+                len == BLOCK_SIZE || (len > 0 && len < BLOCK_SIZE && self.has_short_blocks())
+            })
+            .collect();
+        if pending.is_empty() {
+            return;
+        }
+        
+        // Each block then fails in `compress_block`
+        let Ok(_engine) = self.lock_engine(Hold::Reset) else {
+            return;
+        };
+        let mut transfer = self.lock_transfer();
+        for (n, &index) in pending.iter().enumerate() {
+            let input = blocks[index];
+            let next = pending.get(n + 1).map(|&next| blocks[next]);
+            results[index] = Some(self.reset().and_then(|()| {
+                let (ctx, job) = self.prepare_job(input.len(), constants::CTRL_START)?;
+                transfer.run_batch_job(&ctx, input, next, &job)
+            }));
+        }
+        transfer.finish_batch();
+    }
+    
     /// Device reset method
     fn reset(&self) -> Lzma2Result<()> {
         // Set reset bit
         self.write_register(
             self.registers.control, 
-            1 << 31
+            constants::CTRL_RESET
         )?;
         
         // Short delay
@@ -108,130 +312,175 @@ impl PcieDevice {
             0
         )?;
         
+        // Drop interrupts left over from a previous job
+        if let Some(interrupt) = &self.interrupt {
+            while interrupt.try_wait()? {}
+        }
+        
         Ok(())
     }
     
-    /// Input data transfer method
-    fn transfer_input_data(&self, input: &[u8]) -> Lzma2Result<()> {
-        match self.transfer_strategy {
-            TransferStrategy::Mmio => {
-                // Memory-mapped I/O data transfer
-                for (i, chunk) in input.chunks(256).enumerate() {
-                    let offset = self.registers.input_data + (i * 256) as u64;
-                    self.write_chunk(offset, chunk)?;
-                }
-            },
-            _ => return Err(Lzma2Error::TransferError(
-                "Unsupported transfer strategy".to_string()
-            )),
+    /// Run a job on a block of `block_len` uncompressed bytes through the
+    /// configured transfer strategy
+    fn run_job(&self, input: &[u8], block_len: usize, control: u32) -> Lzma2Result<Vec<u8>> {
+        let (ctx, job) = self.prepare_job(block_len, control)?;
+        self.lock_transfer().run_job(&ctx, input, &job)
+    }
+    
+    /// Program the block length and build the context of a job
+    fn prepare_job(&self, block_len: usize, control: u32) -> Lzma2Result<(TransferContext<'_>, DeviceJob<'_, B>)> {
+        if self.has_short_blocks() {
+            self.write_register(self.registers.block_length, block_len as u32)?;
         }
         
-        Ok(())
+        let ctx = TransferContext {
+            io: &self.io,
+            input_window: self.registers.input_data,
+            output_window: self.registers.output_data,
+        };
+        let job = DeviceJob {
+            device: self,
+            control,
+            deadline: Instant::now() + self.completion_timeout,
+        };
+        Ok((ctx, job))
     }
     
-    /// Compressed data transfer method
-    fn transfer_compressed_data(&self, input: &[u8]) -> Lzma2Result<()> {
-        match self.transfer_strategy {
-            TransferStrategy::Mmio => {
-                // Memory-mapped I/O compressed data transfer
-                for (i, chunk) in input.chunks(32).enumerate() {
-                    let offset = self.registers.input_data + (i * 32) as u64;
-                    
-                    // Zero-padding for last chunk less than 32 bytes
-                    let mut padded_chunk = [0u8; 32];
-                    padded_chunk[..chunk.len()].copy_from_slice(chunk);
-                    
-                    self.write_chunk(offset, &padded_chunk)?;
-                }
-            },
-            _ => return Err(Lzma2Error::TransferError(
-                "Unsupported transfer strategy".to_string()
-            )),
+    /// Check whether the current job has completed without blocking
+    ///
+    /// # Errors
+    /// Returns the hardware error if the job failed
+    pub fn poll_completion(&self) -> Lzma2Result<bool> {
+        let status = self.read_status()?;
+        
+        // Check error state before completion
+        if status.has_error() {
+            let snapshot = self.snapshot_registers(status);
+            return Err(match status.error() {
+                Some(code) => Lzma2Error::from_hardware(code, snapshot),
+                None => Lzma2Error::HardwareFault(snapshot),
+            });
         }
         
-        Ok(())
+        Ok(status.is_done())
     }
     
-    /// Start compression method
-    fn start_compression(&self) -> Lzma2Result<()> {
-        self.write_register(
-            self.registers.control, 
-            1  // Compression start bit
-        )
+    /// Block until the current job completes
+    ///
+    /// # Errors
+    /// Returns the hardware error if the job failed
+    pub fn wait_for_completion(&self) -> Lzma2Result<()> {
+        self.wait_until(None)
     }
     
-    /// Start decompression method
-    fn start_decompression(&self) -> Lzma2Result<()> {
-        self.write_register(
-            self.registers.control, 
-            (1 << 0) | (1 << 16)  // Decompression mode + start bit
-        )
+    /// Block until the current job completes or `timeout` expires
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::TimeoutError` if the job is still running after
+    /// `timeout`, or the hardware error if the job failed
+    pub fn wait_for_completion_timeout(&self, timeout: Duration) -> Lzma2Result<()> {
+        self.wait_until(Some(Instant::now() + timeout))
     }
     
-    /// Completion wait method
-    fn wait_for_completion(&self) -> Lzma2Result<()> {
-        const MAX_RETRIES: u32 = 1000;
-        const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_micros(10);
-        
-        for _ in 0..MAX_RETRIES {
-            let status = self.read_register(self.registers.status)?;
-            
-            // Check completion bit
-            if status & (1 << 0) != 0 {
+    /// Completion wait method, interrupt-driven when a source is attached
+    fn wait_until(&self, deadline: Option<Instant>) -> Lzma2Result<()> {
+        loop {
+            // The status register is authoritative; interrupts only wake us
+            if self.poll_completion()? {
                 return Ok(());
             }
             
-            // Check error bit
-            if status & (1 << 16) != 0 {
-                let error_code = self.read_register(self.registers.status + 4)?;
-                return Err(Lzma2Error::ProcessingError(
-                    format!("Processing error: Error code {}", error_code)
-                ));
-            }
+            let remaining = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => return Err(Lzma2Error::TimeoutError),
+                },
+                None => None,
+            };
             
-            std::thread::sleep(POLL_INTERVAL);
+            match &self.interrupt {
+                Some(interrupt) => {
+                    interrupt.wait(remaining)?;
+                },
+                None => {
+                    let interval = remaining.map_or(constants::POLL_INTERVAL, |remaining| {
+                        remaining.min(constants::POLL_INTERVAL)
+                    });
+                    std::thread::sleep(interval);
+                },
+            }
         }
-        
-        Err(Lzma2Error::TimeoutError)
     }
     
-    /// Output data reading method
-    fn read_output_data(&self) -> Lzma2Result<Vec<u8>> {
-        let mut output = Vec::with_capacity(32 * 1024);
-        
-        for i in 0..(32 * 1024 / 256) {
-            let mut chunk = vec![0u8; 256];
-            let offset = self.registers.output_data + (i * 256) as u64;
-            
-            self.read_chunk(offset, &mut chunk)?;
-            output.extend_from_slice(&chunk);
+    /// Status register reading method
+    fn read_status(&self) -> Lzma2Result<StatusRegister> {
+        self.read_register(self.registers.status).map(StatusRegister::from_raw)
+    }
+    
+    /// Register snapshot method for error reporting
+    ///
+    /// Failed reads are left out rather than replacing the hardware error.
+    pub(super) fn snapshot_registers(&self, status: StatusRegister) -> RegisterSnapshot {
+        let mut perf_counters = [None; PERF_COUNTER_COUNT];
+        for (i, counter) in perf_counters.iter_mut().enumerate() {
+            *counter = self.read_register(self.registers.performance_counters + (i * 4) as u64).ok();
         }
         
-        Ok(output)
+        RegisterSnapshot {
+            status,
+            control: self.read_register(self.registers.control).ok(),
+            perf_counters,
+        }
     }
     
-    /// Register reading method (placeholder)
-    fn read_register(&self, _offset: u64) -> Lzma2Result<u64> {
-        // TODO: Actual register reading implementation
-        Err(Lzma2Error::DeviceAccessError)
+    /// Register reading method
+    fn read_register(&self, offset: u64) -> Lzma2Result<u32> {
+        self.io.read32(offset)
     }
     
-    /// Register writing method (placeholder)
-    fn write_register(&self, _offset: u64, _value: u64) -> Lzma2Result<()> {
-        // TODO: Actual register writing implementation
-        Err(Lzma2Error::DeviceAccessError)
+    /// Register writing method
+    fn write_register(&self, offset: u64, value: u32) -> Lzma2Result<()> {
+        self.io.write32(offset, value)
+    }
+}
+
+/// Job control handed to the transfer strategy
+struct DeviceJob<'a, B: RegisterIo> {
+    device: &'a PcieDevice<B>,
+    control: u32,
+    deadline: Instant,
+}
+
+impl<B: RegisterIo> JobControl for DeviceJob<'_, B> {
+    fn start(&self, flags: u32) -> Lzma2Result<()> {
+        self.device.write_register(self.device.registers.control, self.control | flags)
+    }
+    
+    fn poll(&self) -> Lzma2Result<bool> {
+        self.device.poll_completion()
+    }
+    
+    fn wait(&self) -> Lzma2Result<()> {
+        self.device.wait_until(Some(self.deadline))
     }
     
-    /// Chunk reading method (placeholder)
-    fn read_chunk(&self, _offset: u64, _buffer: &mut [u8]) -> Lzma2Result<()> {
-        // TODO: Actual chunk reading implementation
-        Err(Lzma2Error::DeviceAccessError)
+    fn deadline(&self) -> Instant {
+        self.deadline
     }
     
-    /// Chunk writing method (placeholder)
-    fn write_chunk(&self, _offset: u64, _data: &[u8]) -> Lzma2Result<()> {
-        // TODO: Actual chunk writing implementation
-        Err(Lzma2Error::DeviceAccessError)
+    fn output_len(&self) -> Lzma2Result<usize> {
+        // Compression reports compressed_bytes, decompression the restored
+        // total_bytes_processed
+        let counter = if self.control & constants::CTRL_DECOMPRESS != 0 { 0 } else { 4 };
+        let size = u64::from(self.device.read_register(self.device.registers.performance_counters + counter)?);
+        
+        if size > constants::OUTPUT_WINDOW_SIZE {
+            return Err(Lzma2Error::OutputOverflow {
+                size,
+                capacity: constants::OUTPUT_WINDOW_SIZE,
+            });
+        }
+        Ok(size as usize)
     }
 }
 
@@ -239,18 +488,260 @@ impl PcieDevice {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::device::{DeviceConfig, SysfsScanner};
+    use crate::device::backend::MockRegisters;
+    use crate::device::{SimulatedDevice, SimulatorConfig};
+    use crate::device::sysfs::tests::create_function;
+    
+    fn fake_device(root: &std::path::Path) -> PcieDevice {
+        create_function(root, "0000:41:00.0", 0x1234, 0x5678, 0, 0x20000);
+        let scanner = SysfsScanner::new(root);
+        PcieDevice::probe_with(&scanner, &DeviceConfig::default()).unwrap().remove(0)
+    }
     
     #[test]
     fn test_device_probe() -> Lzma2Result<()> {
-        let devices = PcieDevice::probe()?;
+        let root = tempfile::tempdir().unwrap();
+        create_function(root.path(), "0000:41:00.0", 0x1234, 0x5678, 0, 0x20000);
+        
+        let devices = PcieDevice::probe_with(&SysfsScanner::new(root.path()), &DeviceConfig::default())?;
         assert!(!devices.is_empty(), "No devices found");
         Ok(())
     }
     
+    #[test]
+    fn test_register_access() -> Lzma2Result<()> {
+        let root = tempfile::tempdir().unwrap();
+        let device = fake_device(root.path());
+        
+        device.write_register(device.registers.control, 0x8000_0001)?;
+        assert_eq!(device.read_register(device.registers.control)?, 0x8000_0001);
+        
+        let data: Vec<u8> = (0..=255).collect();
+        device.io.write_burst(device.registers.input_data, &data)?;
+        
+        let mut readback = vec![0u8; data.len()];
+        device.io.read_burst(device.registers.input_data, &mut readback)?;
+        assert_eq!(readback, data);
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_reset_clears_control() -> Lzma2Result<()> {
+        let root = tempfile::tempdir().unwrap();
+        let device = fake_device(root.path());
+        
+        device.write_register(device.registers.control, constants::CTRL_START)?;
+        device.reset()?;
+        assert_eq!(device.read_register(device.registers.control)?, 0);
+        
+        Ok(())
+    }
+    
+    /// Mock device that completes immediately and reports its input size
+    fn mock_device() -> PcieDevice<MockRegisters> {
+        let regs = MockRegisters::new(0x20000).on_write(|memory, offset| {
+            if offset == 0x00 && memory[0] & 1 != 0 {
+                // COMPLETE, 32KB processed into 4KB of output
+                memory[0x05] = 0x04;
+                memory[0x20..0x24].copy_from_slice(&(32 * 1024u32).to_le_bytes());
+                memory[0x24..0x28].copy_from_slice(&4096u32.to_le_bytes());
+                memory[0x10000..0x10004].copy_from_slice(b"LZMA");
+            }
+        });
+        PcieDevice::with_backend(regs, DeviceConfig::default()).unwrap()
+    }
+    
+    #[test]
+    fn test_mock_compression_flow() -> Lzma2Result<()> {
+        let device = mock_device();
+        let input: Vec<u8> = (0..32 * 1024).map(|i| (i % 251) as u8).collect();
+        
+        let compressed = device.compress(&input)?;
+        assert_eq!(compressed.len(), 4096);
+        assert_eq!(&compressed[..4], b"LZMA");
+        
+        // Input landed in the input window
+        let uploaded = device.io().with_memory(|memory| memory[0x8000..0x10000].to_vec());
+        assert_eq!(uploaded, input);
+        
+        // Reset pulse followed by start
+        let control: Vec<u64> = device.io().write_log().into_iter()
+            .filter(|&(offset, _)| offset == 0x00)
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(control, vec![1 << 31, 0, 1]);
+        
+        let metrics = device.get_performance_metrics()?;
+        assert_eq!(metrics.total_bytes_processed, 32 * 1024);
+        assert_eq!(metrics.compressed_bytes, 4096);
+        assert!((metrics.compression_ratio - 0.125).abs() < f32::EPSILON);
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_output_overflow() {
+        let regs = MockRegisters::new(0x20000).on_write(|memory, offset| {
+            if offset == 0x00 && memory[0] & 1 != 0 {
+                memory[0x05] = 0x04;
+                memory[0x24..0x28].copy_from_slice(&0x10001u32.to_le_bytes());
+            }
+        });
+        let device = PcieDevice::with_backend(regs, DeviceConfig::default()).unwrap();
+        
+        let error = device.compress(&[0u8; 32 * 1024]).unwrap_err();
+        assert!(matches!(error, Lzma2Error::OutputOverflow { size: 0x10001, capacity: 0x10000 }));
+    }
+    
+    #[test]
+    fn test_mock_compression_timeout() {
+        let device = PcieDevice::with_backend(MockRegisters::new(0x20000), DeviceConfig::default()).unwrap();
+        let result = device.compress(&vec![0u8; 32 * 1024]);
+        assert!(matches!(result, Err(Lzma2Error::TimeoutError)));
+    }
+    
+    #[test]
+    fn test_hardware_error_reporting() {
+        use crate::device::{ControllerState, HardwareErrorCode};
+        use crate::error::ErrorExt;
+        
+        let device = SimulatedDevice::simulated();
+        
+        device.io().inject_error(HardwareErrorCode::CrcMismatch);
+        let error = device.compress(&[0u8; 32 * 1024]).unwrap_err();
+        assert!(matches!(error, Lzma2Error::HardwareCrcMismatch(_)));
+        assert!(error.is_recoverable());
+        
+        device.io().inject_error(HardwareErrorCode::MemoryAccess);
+        let error = device.compress(&[0u8; 32 * 1024]).unwrap_err();
+        assert!(matches!(error, Lzma2Error::HardwareMemoryFault(_)));
+        assert!(!error.is_recoverable());
+        
+        // The snapshot captures the registers at failure time
+        let snapshot = error.register_snapshot().unwrap();
+        assert_eq!(snapshot.status.state(), Some(ControllerState::Error));
+        assert_eq!(snapshot.status.error(), Some(HardwareErrorCode::MemoryAccess));
+        assert_eq!(snapshot.control, Some(constants::CTRL_START));
+        assert_eq!(snapshot.perf_counters[0], Some(32 * 1024));
+        
+        // The next job succeeds after the reset
+        assert!(device.compress(&[0u8; 32 * 1024]).is_ok());
+        
+        // Error codes this driver does not know are still device faults
+        let regs = MockRegisters::new(0x20000).on_write(|memory, offset| {
+            if offset == 0x00 && memory[0] & 1 != 0 {
+                memory[0x05] = 0xF5;
+            }
+        });
+        let device = PcieDevice::with_backend(regs, DeviceConfig::default()).unwrap();
+        let error = device.compress(&[0u8; 32 * 1024]).unwrap_err();
+        assert!(matches!(error, Lzma2Error::HardwareFault(_)));
+        assert!(!error.is_recoverable());
+        assert_eq!(error.register_snapshot().unwrap().status.error_flags(), 0xF);
+    }
+    
+    /// Mock BAR whose performance counters cannot be read
+    struct NoCounters(MockRegisters);
+    
+    impl RegisterIo for NoCounters {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+        
+        fn read32(&self, offset: u64) -> Lzma2Result<u32> {
+            if (0x20..0x48).contains(&offset) {
+                return Err(Lzma2Error::DeviceAccessError);
+            }
+            self.0.read32(offset)
+        }
+        
+        fn write32(&self, offset: u64, value: u32) -> Lzma2Result<()> {
+            self.0.write32(offset, value)
+        }
+        
+        fn read64(&self, offset: u64) -> Lzma2Result<u64> {
+            self.0.read64(offset)
+        }
+        
+        fn write64(&self, offset: u64, value: u64) -> Lzma2Result<()> {
+            self.0.write64(offset, value)
+        }
+        
+        fn read_burst(&self, offset: u64, buffer: &mut [u8]) -> Lzma2Result<()> {
+            self.0.read_burst(offset, buffer)
+        }
+        
+        fn write_burst(&self, offset: u64, data: &[u8]) -> Lzma2Result<()> {
+            self.0.write_burst(offset, data)
+        }
+    }
+    
+    #[test]
+    fn test_partial_register_snapshot() {
+        use crate::device::HardwareErrorCode;
+        use crate::error::ErrorExt;
+        
+        // ERROR state reporting a pipeline stall
+        let regs = MockRegisters::new(0x20000).on_write(|memory, offset| {
+            if offset == 0x00 && memory[0] & 1 != 0 {
+                memory[0x05] = 0x65;
+            }
+        });
+        let device = PcieDevice::with_backend(NoCounters(regs), DeviceConfig::default()).unwrap();
+        
+        // The stall is reported even though the counters are unreadable
+        let error = device.compress(&[0u8; 32 * 1024]).unwrap_err();
+        assert!(matches!(error, Lzma2Error::HardwareStall(_)));
+        let snapshot = error.register_snapshot().unwrap();
+        assert_eq!(snapshot.status.error(), Some(HardwareErrorCode::Stall));
+        assert_eq!(snapshot.control, Some(constants::CTRL_START));
+        assert_eq!(snapshot.perf_counters, [None; PERF_COUNTER_COUNT]);
+        assert!(snapshot.to_string().ends_with("control=0x00000001 counters=[?, ?, ?, ?, ?, ?, ?, ?, ?, ?]"));
+    }
+    
+    #[test]
+    fn test_interrupt_completion() -> Lzma2Result<()> {
+        // Latency larger than the timeout would allow with polling
+        let delay = Duration::from_millis(20);
+        let device = SimulatedDevice::simulated_with(SimulatorConfig {
+            latency_polls: u32::MAX,
+            interrupt_delay: delay,
+            ..SimulatorConfig::default()
+        });
+        let interrupt = device.io().interrupt()?;
+        let device = device.with_interrupt(interrupt);
+        assert!(device.has_interrupt());
+        
+        let compressed = device.compress(&[0u8; 32 * 1024])?;
+        assert_eq!(compressed[0], 0);
+        
+        // The job is still running after the first status read, so the
+        // wait blocks on the eventfd until the MSI arrives
+        device.reset()?;
+        let started = Instant::now();
+        device.write_register(device.registers.control, constants::CTRL_START)?;
+        assert!(!device.poll_completion()?);
+        device.wait_for_completion()?;
+        assert!(started.elapsed() >= delay);
+        assert!(device.poll_completion()?);
+        
+        // Non-blocking and timed waits on an idle device
+        device.reset()?;
+        assert!(!device.poll_completion()?);
+        assert!(matches!(
+            device.wait_for_completion_timeout(Duration::from_millis(5)),
+            Err(Lzma2Error::TimeoutError)
+        ));
+        
+        Ok(())
+    }
+    
     #[test]
     fn test_compression_roundtrip() -> Lzma2Result<()> {
         // Device acquisition
-        let device = PcieDevice::new()?;
+        let device = SimulatedDevice::simulated();
         
         // Test data preparation
         let original_data = vec![0u8; 32 * 1024];
@@ -270,4 +761,269 @@ mod tests {
         
         Ok(())
     }
+    
+    #[test]
+    fn test_software_decode_fallback() -> Lzma2Result<()> {
+        let input: Vec<u8> = (0..BLOCK_SIZE).map(|i| (i % 251) as u8 ^ (i / 1024) as u8).collect();
+        
+        // The RTL has no decode engine, so decompression runs in software
+        // and the device stays on the compression job
+        let device = SimulatedDevice::simulated();
+        assert!(!device.has_decode_engine());
+        let compressed = device.compress(&input)?;
+        assert_eq!(device.decompress(&compressed)?, input);
+        assert_eq!(device.get_performance_metrics()?.compressed_bytes, compressed.len() as u64);
+        assert!(device.decompress_block(&compressed[..compressed.len() - 2], BLOCK_SIZE).is_err());
+        
+        // Starting the missing mode fails on the device
+        let registers = &device.registers;
+        device.reset()?;
+        device.write_register(registers.block_length, 0)?;
+        device.write_register(registers.control, constants::CTRL_START | constants::CTRL_DECOMPRESS)?;
+        assert!(device.wait_for_completion().is_err());
+        
+        // The mode is opted into, never probed from the BAR
+        let regs = MockRegisters::new(0x20000);
+        regs.with_memory(|memory| memory.fill(0xFF));
+        assert!(!PcieDevice::with_backend(regs, DeviceConfig::default())?.has_decode_engine());
+        
+        // A model with the engine decodes on the device
+        let device = SimulatedDevice::simulated_with(SimulatorConfig {
+            decode_engine: true,
+            ..SimulatorConfig::default()
+        });
+        assert!(device.has_decode_engine());
+        assert_eq!(device.decompress(&compressed)?, input);
+        assert_eq!(device.get_performance_metrics()?.compressed_bytes, compressed.len() as u64);
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_short_block_fallback() -> Lzma2Result<()> {
+        use crate::device::ControllerState;
+        
+        let input: Vec<u8> = (0..5000).map(|i| (i / 7 % 13) as u8).collect();
+        let short_blocks = SimulatedDevice::simulated_with(SimulatorConfig {
+            short_blocks: true,
+            ..SimulatorConfig::default()
+        });
+        let expected = short_blocks.compress_block(&input)?;
+        
+        // Like the RTL, the engine ignores the block length register, so
+        // short blocks never reach it
+        let device = SimulatedDevice::simulated();
+        assert!(!device.has_short_blocks());
+        assert_eq!(device.compress_block(&input)?, expected);
+        assert_eq!(device.decompress_block(&expected, input.len())?, input);
+        assert_eq!(device.io().transitions(), [ControllerState::Idle]);
+        
+        // A full block still runs on the engine
+        device.compress_block(&[0; BLOCK_SIZE])?;
+        assert_eq!(device.io().state(), ControllerState::Complete);
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_pluggable_transfer() -> Lzma2Result<()> {
+        use crate::transfer::{DataTransfer, MmioTransfer, TransferConfig, TransferStrategy};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        
+        /// Custom strategy counting the bytes it uploads
+        struct Counting(Arc<AtomicUsize>);
+        
+        impl DataTransfer for Counting {
+            fn transfer_input(&mut self, ctx: &TransferContext<'_>, data: &[u8]) -> Lzma2Result<()> {
+                self.0.fetch_add(data.len(), Ordering::Relaxed);
+                MmioTransfer.transfer_input(ctx, data)
+            }
+            
+            fn read_output(&mut self, ctx: &TransferContext<'_>, len: usize) -> Lzma2Result<Vec<u8>> {
+                MmioTransfer.read_output(ctx, len)
+            }
+        }
+        
+        let device = SimulatedDevice::simulated();
+        assert_eq!(device.transfer_strategy(), Some(TransferStrategy::Mmio));
+        let expected = device.compress(&[7u8; 32 * 1024])?;
+        
+        let uploaded = Arc::new(AtomicUsize::new(0));
+        device.set_transfer(Counting(uploaded.clone()));
+        assert_eq!(device.transfer_strategy(), None);
+        assert_eq!(device.compress(&[7u8; 32 * 1024])?, expected);
+        assert_eq!(uploaded.load(Ordering::Relaxed), 32 * 1024);
+        
+        // Switching back to a built-in strategy at runtime
+        device.set_transfer_config(&TransferConfig {
+            strategy: TransferStrategy::Dma,
+            ..TransferConfig::default()
+        })?;
+        assert_eq!(device.compress(&[7u8; 32 * 1024])?, expected);
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_compress_batch() -> Lzma2Result<()> {
+        use crate::device::HardwareErrorCode;
+        
+        let full: Vec<u8> = (0..BLOCK_SIZE).map(|i| (i / 100 % 7) as u8).collect();
+        let oversized = vec![0u8; BLOCK_SIZE + 1];
+        let blocks: [&[u8]; 5] = [&full, b"short block", &[], &oversized, &full[..9000]];
+        
+        let serial = SimulatedDevice::simulated();
+        let queued = SimulatedDevice::simulated_with(SimulatorConfig {
+            command_queue: true,
+            ..SimulatorConfig::default()
+        });
+        
+        // Invalid blocks fail on their own, with or without the queue
+        for device in [&serial, &queued] {
+            let results = device.compress_batch(&blocks);
+            assert_eq!(results.len(), blocks.len());
+            for (block, result) in blocks.iter().zip(results) {
+                match result {
+                    Ok(compressed) => assert_eq!(compressed, serial.compress_block(block)?),
+                    Err(e) => assert!(matches!(e, Lzma2Error::InputValidationError(_))),
+                }
+            }
+        }
+        
+        // The short block overtakes the full one in the pipeline and takes
+        // the injected fault; the rest of the batch is unaffected
+        queued.io().inject_error(HardwareErrorCode::Stall);
+        let results = queued.compress_batch(&blocks);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Lzma2Error::HardwareStall(_))));
+        assert_eq!(results[4].as_ref().unwrap(), &serial.compress_block(blocks[4])?);
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_serial_batch_pipeline() -> Lzma2Result<()> {
+        use crate::device::HardwareErrorCode;
+        use crate::transfer::{DataTransfer, MmioTransfer, TransferConfig, TransferStrategy};
+        use std::sync::{Arc, Mutex};
+        
+        /// MMIO transfer recording the input and next block of batch jobs
+        struct Recording(Arc<Mutex<Vec<usize>>>);
+        
+        impl DataTransfer for Recording {
+            fn transfer_input(&mut self, ctx: &TransferContext<'_>, data: &[u8]) -> Lzma2Result<()> {
+                MmioTransfer.transfer_input(ctx, data)
+            }
+            
+            fn read_output(&mut self, ctx: &TransferContext<'_>, len: usize) -> Lzma2Result<Vec<u8>> {
+                MmioTransfer.read_output(ctx, len)
+            }
+            
+            fn run_batch_job(
+                &mut self,
+                ctx: &TransferContext<'_>,
+                input: &[u8],
+                next: Option<&[u8]>,
+                job: &dyn JobControl,
+            ) -> Lzma2Result<Vec<u8>> {
+                self.0.lock().unwrap().extend([input.len(), next.map_or(0, <[u8]>::len)]);
+                self.run_job(ctx, input, job)
+            }
+        }
+        
+        let blocks: Vec<Vec<u8>> = (1..=3)
+            .map(|seed| (0..BLOCK_SIZE).map(|i| (i / (seed * 50) % 9) as u8).collect())
+            .collect();
+        let batch: [&[u8]; 6] = [&blocks[0], b"short block", &blocks[1], &[], &blocks[2], &blocks[0]];
+        let reference = SimulatedDevice::simulated();
+        
+        // Without a command queue the full blocks run back to back, each
+        // handed over with the one after it
+        let device = SimulatedDevice::simulated();
+        let jobs = Arc::new(Mutex::new(Vec::new()));
+        device.set_transfer(Recording(Arc::clone(&jobs)));
+        let results = device.compress_batch(&batch);
+        assert_eq!(*jobs.lock().unwrap(), [BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, 0]);
+        assert!(matches!(results[3], Err(Lzma2Error::InputValidationError(_))));
+        for index in [0, 1, 2, 4, 5] {
+            assert_eq!(results[index].as_ref().unwrap(), &reference.compress_block(batch[index])?);
+        }
+        
+        // DMA stages each block while the previous one is compressed; a
+        // failed job does not shift the staged inputs
+        device.set_transfer_config(&TransferConfig {
+            strategy: TransferStrategy::Dma,
+            ..TransferConfig::default()
+        })?;
+        for fault in [None, Some(HardwareErrorCode::Stall)] {
+            if let Some(code) = fault {
+                device.io().inject_error(code);
+            }
+            let results = device.compress_batch(&batch);
+            assert_eq!(results[0].is_err(), fault.is_some());
+            for index in [1, 2, 4, 5] {
+                assert_eq!(results[index].as_ref().unwrap(), &reference.compress_block(batch[index])?);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_shared_between_threads() -> Lzma2Result<()> {
+        use crate::device::backend::VfioRegion;
+        use crate::device::ControllerState;
+        use std::sync::Arc;
+        
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PcieDevice>();
+        assert_send_sync::<PcieDevice<VfioRegion>>();
+        assert_send_sync::<SimulatedDevice>();
+        
+        let device = Arc::new(SimulatedDevice::simulated());
+        let inputs: Vec<Vec<u8>> = (1..=4)
+            .map(|seed| (0..BLOCK_SIZE).map(|i| (i / (seed * 13) % 11) as u8).collect())
+            .collect();
+        
+        // Jobs from several threads interleave only at job boundaries
+        let workers: Vec<_> = inputs.iter().cloned()
+            .map(|input| {
+                let device = Arc::clone(&device);
+                std::thread::spawn(move || -> Lzma2Result<Vec<Vec<u8>>> {
+                    (0..3).map(|_| device.compress_block(&input)).collect()
+                })
+            })
+            .collect();
+        
+        let outputs = workers.into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Lzma2Result<Vec<_>>>()?;
+        
+        // Every job ran on the engine, each from INIT to COMPLETE before the
+        // next one started
+        let jobs: Vec<_> = device.io().transitions().into_iter()
+            .filter(|&state| state != ControllerState::Idle)
+            .collect();
+        assert_eq!(jobs.len(), 12 * 4);
+        for job in jobs.chunks(4) {
+            assert_eq!(job, [
+                ControllerState::Init,
+                ControllerState::Compress,
+                ControllerState::Verify,
+                ControllerState::Complete,
+            ]);
+        }
+        
+        let reference = SimulatedDevice::simulated();
+        for (input, compressed) in inputs.iter().zip(outputs) {
+            let expected = reference.compress_block(input)?;
+            for compressed in compressed {
+                assert_eq!(compressed, expected);
+                assert_eq!(&device.decompress_block(&compressed, input.len())?, input);
+            }
+        }
+        
+        Ok(())
+    }
 }
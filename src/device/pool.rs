@@ -0,0 +1,499 @@
+//! Multi-device pool for LZMA2 FPGA Compression Driver
+//!
+//! `DevicePool` spreads jobs over several devices, picking one per job by
+//! round robin or by the number of jobs it is running. A device returning
+//! non-recoverable errors on several jobs in a row is taken out of
+//! rotation; the job that hit a device fault is queued again on another
+//! healthy device. Each call runs one job to completion, so jobs run
+//! concurrently only when the pool is shared between threads, except for
+//! `compress_batch`, which splits its blocks over the healthy devices and
+//! runs the shares side by side.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+
+use super::{ChainedBlock, HardwareCompressionDevice, PcieDevice, PerformanceMetrics};
+use super::DeviceManager;
+use crate::error::{ErrorExt, Lzma2Error, Lzma2Result};
+
+/// Default number of consecutive non-recoverable errors that take a device
+/// out of rotation
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// How the pool picks a device for a job
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BalancePolicy {
+    /// Healthy devices in turn
+    RoundRobin,
+
+    /// The healthy device running the fewest jobs, in turn among equals
+    #[default]
+    LeastLoaded,
+}
+
+/// Device in the pool and its health
+#[derive(Debug)]
+struct Member<D> {
+    device: D,
+
+    /// Jobs currently running on the device
+    in_flight: AtomicUsize,
+
+    /// Non-recoverable errors since the last success
+    failures: AtomicU32,
+
+    /// Whether the device is in rotation
+    healthy: AtomicBool,
+}
+
+/// Health of a pooled device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemberStatus {
+    /// Whether the device is in rotation
+    pub healthy: bool,
+
+    /// Jobs currently running on the device
+    pub in_flight: usize,
+
+    /// Non-recoverable errors since the last success
+    pub consecutive_failures: u32,
+}
+
+/// Pool of devices dispatching each job to a healthy one
+#[derive(Debug)]
+pub struct DevicePool<D> {
+    members: Vec<Member<D>>,
+    policy: BalancePolicy,
+    failure_threshold: u32,
+
+    /// Rotation cursor
+    next: AtomicUsize,
+}
+
+impl DevicePool<PcieDevice> {
+    /// Pool every device found by `DeviceManager::probe`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if no devices are found
+    pub fn probe() -> Lzma2Result<Self> {
+        Self::new(DeviceManager::probe()?)
+    }
+}
+
+impl<D: HardwareCompressionDevice> DevicePool<D> {
+    /// Pool `devices` with the least-loaded policy
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::DeviceInitError` if `devices` is empty
+    pub fn new(devices: Vec<D>) -> Lzma2Result<Self> {
+        if devices.is_empty() {
+            return Err(Lzma2Error::DeviceInitError(
+                "Device pool needs at least one device".to_string()
+            ));
+        }
+
+        Ok(Self {
+            members: devices.into_iter()
+                .map(|device| Member {
+                    device,
+                    in_flight: AtomicUsize::new(0),
+                    failures: AtomicU32::new(0),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            policy: BalancePolicy::default(),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Select how jobs are assigned to devices
+    pub fn with_policy(mut self, policy: BalancePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Take a device out of rotation after `threshold` consecutive
+    /// non-recoverable errors
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold.max(1);
+        self
+    }
+
+    /// Number of devices, healthy or not
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Whether the pool has no devices, which construction rules out
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Device at `index`
+    pub fn device(&self, index: usize) -> Option<&D> {
+        self.members.get(index).map(|member| &member.device)
+    }
+
+    /// Health of the device at `index`
+    pub fn status(&self, index: usize) -> Option<MemberStatus> {
+        self.members.get(index).map(|member| MemberStatus {
+            healthy: member.healthy.load(Ordering::Acquire),
+            in_flight: member.in_flight.load(Ordering::Acquire),
+            consecutive_failures: member.failures.load(Ordering::Acquire),
+        })
+    }
+
+    /// Number of devices in rotation
+    pub fn healthy_count(&self) -> usize {
+        self.members.iter().filter(|member| member.healthy.load(Ordering::Acquire)).count()
+    }
+
+    /// Put the device at `index` back into rotation, e.g. after a reset
+    pub fn reinstate(&self, index: usize) {
+        if let Some(member) = self.members.get(index) {
+            member.failures.store(0, Ordering::Release);
+            member.healthy.store(true, Ordering::Release);
+        }
+    }
+
+    /// Run `job` on a healthy device, moving it to another device on a
+    /// device fault
+    ///
+    /// Each device is tried at most once per job.
+    ///
+    /// # Errors
+    /// Returns the job's own error, the last device fault once every
+    /// healthy device has failed it, or `Lzma2Error::DeviceInitError` if
+    /// no device is in rotation
+    pub fn run<T>(&self, job: impl Fn(&D) -> Lzma2Result<T>) -> Lzma2Result<T> {
+        self.retry(vec![false; self.members.len()], None, job)
+    }
+
+    /// Continue `run` with the devices in `tried` already failed by the job,
+    /// the last one with `last_error`
+    fn retry<T>(
+        &self,
+        mut tried: Vec<bool>,
+        mut last_error: Option<Lzma2Error>,
+        job: impl Fn(&D) -> Lzma2Result<T>,
+    ) -> Lzma2Result<T> {
+        while let Some(index) = self.select(&tried) {
+            tried[index] = true;
+            let member = &self.members[index];
+
+            member.in_flight.fetch_add(1, Ordering::AcqRel);
+            let result = job(&member.device);
+            member.in_flight.fetch_sub(1, Ordering::AcqRel);
+
+            match result {
+                Ok(output) => {
+                    member.failures.store(0, Ordering::Release);
+                    return Ok(output);
+                },
+                Err(e) if Self::is_job_error(&e) => return Err(e),
+                Err(e) => {
+                    self.record_fault(index, &e);
+                    last_error = Some(e);
+                },
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| Lzma2Error::DeviceInitError(
+            "No healthy devices in the pool".to_string()
+        )))
+    }
+
+    /// Compress `blocks` with one `compress_batch` on the device at `index`,
+    /// recording the outcome of each block in its health
+    fn run_share(&self, index: usize, blocks: &[&[u8]]) -> Vec<Lzma2Result<Vec<u8>>> {
+        let member = &self.members[index];
+        member.in_flight.fetch_add(blocks.len(), Ordering::AcqRel);
+        let results = member.device.compress_batch(blocks);
+        member.in_flight.fetch_sub(blocks.len(), Ordering::AcqRel);
+
+        for result in &results {
+            match result {
+                Ok(_) => member.failures.store(0, Ordering::Release),
+                Err(e) if Self::is_job_error(e) => {},
+                Err(e) => self.record_fault(index, e),
+            }
+        }
+        results
+    }
+
+    /// Errors caused by the job's data, which another device would repeat
+    fn is_job_error(error: &Lzma2Error) -> bool {
+        matches!(
+            error,
+            Lzma2Error::InputValidationError(_)
+                | Lzma2Error::ProcessingError(_)
+                | Lzma2Error::VerificationFailed { .. }
+        )
+    }
+
+    /// Count a device fault, taking the device out of rotation once the
+    /// threshold is reached
+    fn record_fault(&self, index: usize, error: &Lzma2Error) {
+        let member = &self.members[index];
+        if error.is_recoverable() {
+            tracing::debug!("Device {} failed a job, retrying elsewhere: {}", index, error);
+            return;
+        }
+
+        let failures = member.failures.fetch_add(1, Ordering::AcqRel) + 1;
+        if failures >= self.failure_threshold && member.healthy.swap(false, Ordering::AcqRel) {
+            tracing::warn!(
+                "Taking device {} out of rotation after {} consecutive errors: {}",
+                index, failures, error
+            );
+        }
+    }
+
+    /// Pick a healthy device not yet tried for the current job
+    fn select(&self, tried: &[bool]) -> Option<usize> {
+        let count = self.members.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        let mut candidates = (0..count)
+            .map(|offset| (start + offset) % count)
+            .filter(|&index| !tried[index] && self.members[index].healthy.load(Ordering::Acquire));
+
+        match self.policy {
+            BalancePolicy::RoundRobin => candidates.next(),
+            BalancePolicy::LeastLoaded => {
+                candidates.min_by_key(|&index| self.members[index].in_flight.load(Ordering::Acquire))
+            },
+        }
+    }
+}
+
+impl<D: HardwareCompressionDevice + Sync> HardwareCompressionDevice for DevicePool<D> {
+    fn compress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        self.run(|device| device.compress(input))
+    }
+
+    fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        self.run(|device| device.decompress(input))
+    }
+
+    fn compress_block(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+        self.run(|device| device.compress_block(input))
+    }
+
+    /// Self-contained blocks only; consecutive jobs may land on different
+    /// devices, so the pool cannot keep a stream open across them
+    fn compress_chained(&self, input: &[u8], chain: ChainedBlock) -> Lzma2Result<Vec<u8>> {
+        if chain.continues || !chain.closes {
+            return Err(Lzma2Error::InputValidationError(
+                "Device pool does not support LZMA streams spanning several blocks".to_string()
+            ));
+        }
+        self.run(|device| device.compress_chained(input, chain))
+    }
+
+    fn decompress_block(&self, input: &[u8], uncompressed_len: usize) -> Lzma2Result<Vec<u8>> {
+        self.run(|device| device.decompress_block(input, uncompressed_len))
+    }
+
+    /// Split the blocks into one contiguous share per healthy device and
+    /// compress the shares concurrently, each with its device's own
+    /// `compress_batch`
+    ///
+    /// Blocks failed by a device fault are queued again, one at a time, on
+    /// the devices that have not failed them.
+    fn compress_batch(&self, blocks: &[&[u8]]) -> Vec<Lzma2Result<Vec<u8>>> {
+        let healthy: Vec<usize> = (0..self.members.len())
+            .filter(|&index| self.members[index].healthy.load(Ordering::Acquire))
+            .collect();
+        if healthy.is_empty() || blocks.is_empty() {
+            return blocks.iter().map(|block| self.compress_block(block)).collect();
+        }
+
+        let share = blocks.len().div_ceil(healthy.len());
+        let results: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = healthy.iter()
+                .zip(blocks.chunks(share))
+                .map(|(&index, share)| scope.spawn(move || self.run_share(index, share)))
+                .collect();
+            workers.into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+
+        results.into_iter()
+            .enumerate()
+            .map(|(i, result)| match result {
+                Err(e) if !Self::is_job_error(&e) => {
+                    let mut tried = vec![false; self.members.len()];
+                    tried[healthy[i / share]] = true;
+                    self.retry(tried, Some(e), |device| device.compress_block(blocks[i]))
+                },
+                result => result,
+            })
+            .collect()
+    }
+
+    /// Sum of the metrics of the devices in rotation
+    fn get_performance_metrics(&self) -> Lzma2Result<PerformanceMetrics> {
+        let mut metrics = PerformanceMetrics::default();
+        for member in &self.members {
+            if member.healthy.load(Ordering::Acquire) {
+                metrics.accumulate(&member.device.get_performance_metrics()?);
+            }
+        }
+        Ok(metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::simulator::tests::{sample, BlockHook, Hooked};
+    use crate::device::SimulatedDevice;
+
+    /// Hook counting the jobs of a device and failing them while `broken` is set
+    struct Flaky {
+        jobs: AtomicUsize,
+        broken: AtomicBool,
+    }
+
+    impl Flaky {
+        fn new(broken: bool) -> Hooked<Self> {
+            Hooked::new(Self {
+                jobs: AtomicUsize::new(0),
+                broken: AtomicBool::new(broken),
+            })
+        }
+    }
+
+    impl BlockHook for Flaky {
+        fn before(&self) -> Lzma2Result<()> {
+            self.jobs.fetch_add(1, Ordering::SeqCst);
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(Lzma2Error::DeviceAccessError);
+            }
+            Ok(())
+        }
+    }
+
+    fn jobs(pool: &DevicePool<Hooked<Flaky>>) -> Vec<usize> {
+        (0..pool.len()).map(|i| pool.device(i).unwrap().hook.jobs.load(Ordering::SeqCst)).collect()
+    }
+
+    #[test]
+    fn test_balancing() -> Lzma2Result<()> {
+        assert!(DevicePool::<Hooked<Flaky>>::new(Vec::new()).is_err());
+
+        let pool = DevicePool::new(vec![Flaky::new(false), Flaky::new(false), Flaky::new(false)])?
+            .with_policy(BalancePolicy::RoundRobin);
+        for _ in 0..6 {
+            pool.compress_block(b"round robin")?;
+        }
+        assert_eq!(jobs(&pool), [2, 2, 2]);
+
+        // Busy devices are passed over
+        let pool = DevicePool::new(vec![Flaky::new(false), Flaky::new(false)])?;
+        pool.members[0].in_flight.store(4, Ordering::SeqCst);
+        for _ in 0..3 {
+            pool.compress_block(b"least loaded")?;
+        }
+        assert_eq!(jobs(&pool), [0, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failover() -> Lzma2Result<()> {
+        let pool = DevicePool::new(vec![Flaky::new(true), Flaky::new(false)])?
+            .with_policy(BalancePolicy::RoundRobin)
+            .with_failure_threshold(2);
+
+        // Jobs hitting the broken device are queued again on the other one
+        let reference = SimulatedDevice::simulated();
+        for _ in 0..6 {
+            assert_eq!(pool.compress_block(b"failover")?, reference.compress_block(b"failover")?);
+        }
+        assert_eq!(pool.healthy_count(), 1);
+        assert_eq!(jobs(&pool), [2, 6]);
+        let status = pool.status(0).unwrap();
+        assert!(!status.healthy);
+        assert_eq!(status.consecutive_failures, 2);
+
+        // Input errors are the caller's and do not count against a device
+        assert!(matches!(pool.compress_block(&[]), Err(Lzma2Error::InputValidationError(_))));
+        assert!(pool.status(1).unwrap().healthy);
+
+        // With every device out of rotation, jobs fail
+        pool.device(1).unwrap().hook.broken.store(true, Ordering::SeqCst);
+        pool.compress_block(b"x").unwrap_err();
+        assert!(pool.compress_block(b"x").is_err());
+        assert_eq!(pool.healthy_count(), 0);
+        assert!(matches!(pool.compress_block(b"x"), Err(Lzma2Error::DeviceInitError(_))));
+
+        pool.device(0).unwrap().hook.broken.store(false, Ordering::SeqCst);
+        pool.reinstate(0);
+        assert!(pool.compress_block(&[0; crate::device::BLOCK_SIZE]).is_ok());
+        assert!(pool.get_performance_metrics()?.total_bytes_processed > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_formats_over_members() -> Lzma2Result<()> {
+        use crate::compressor::BlockCompressor;
+
+        let pool = DevicePool::new(vec![SimulatedDevice::simulated(), SimulatedDevice::simulated()])?;
+        let compressor = BlockCompressor::new(&pool);
+
+        // Short final blocks restore to their own length
+        for len in [5000, 2 * crate::device::BLOCK_SIZE + 500] {
+            let input = sample(len, 5);
+            assert_eq!(compressor.decompress(&compressor.compress(&input)?)?, input);
+            assert_eq!(compressor.decompress_lzma2(&compressor.compress_lzma2(&input)?)?, input);
+        }
+
+        // A stream fits one job only
+        let input = sample(5000, 7);
+        let file = compressor.compress_lzma(&input)?;
+        let mut decoded = Vec::new();
+        lzma_rs::lzma_decompress(&mut &file[..], &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert!(compressor.compress_lzma(&sample(3 * crate::device::BLOCK_SIZE, 7)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_over_members() -> Lzma2Result<()> {
+        let inputs: Vec<Vec<u8>> = (1..=9).map(|seed| sample(1000 + seed * 100, seed)).collect();
+        let mut blocks: Vec<&[u8]> = inputs.iter().map(Vec::as_slice).collect();
+        blocks[4] = &[];
+        let reference = SimulatedDevice::simulated();
+        let check = |results: Vec<Lzma2Result<Vec<u8>>>| -> Lzma2Result<()> {
+            assert_eq!(results.len(), blocks.len());
+            for (block, result) in blocks.iter().zip(results) {
+                match result {
+                    Ok(compressed) => assert_eq!(compressed, reference.compress_block(block)?),
+                    Err(e) => assert!(block.is_empty() && matches!(e, Lzma2Error::InputValidationError(_))),
+                }
+            }
+            Ok(())
+        };
+
+        // Each healthy device takes a share of the batch
+        let pool = DevicePool::new(vec![Flaky::new(false), Flaky::new(false), Flaky::new(false)])?;
+        check(pool.compress_batch(&blocks))?;
+        assert_eq!(jobs(&pool), [3, 3, 3]);
+
+        // The share of a broken device moves to the others and takes it out
+        // of rotation
+        pool.device(0).unwrap().hook.broken.store(true, Ordering::SeqCst);
+        check(pool.compress_batch(&blocks))?;
+        assert!(!pool.status(0).unwrap().healthy);
+        assert_eq!(jobs(&pool).iter().sum::<usize>(), 9 + 12);
+        check(pool.compress_batch(&blocks))?;
+        assert_eq!(jobs(&pool)[0], 6);
+
+        Ok(())
+    }
+}
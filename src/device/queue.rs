@@ -0,0 +1,675 @@
+//! Command queue for LZMA2 FPGA Compression Driver
+//!
+//! Engines with `EngineFeatures::command_queue` take jobs from a submission
+//! ring of commands in host memory and spread them over the parallel
+//! compression units. Finished jobs are reported through a completion ring
+//! in the order they finish, not the order they were submitted; each
+//! completion echoes the tag of its command. The driver publishes commands
+//! through the `SQ_HEAD` doorbell and returns completion slots through
+//! `CQ_TAIL`.
+//!
+//! Command layout (little-endian):
+//!
+//! | Offset | Field                           |
+//! |--------|---------------------------------|
+//! | 0x00   | Input IOVA (u64)                |
+//! | 0x08   | Output IOVA (u64)               |
+//! | 0x10   | Input length                    |
+//! | 0x14   | Block length (uncompressed)     |
+//! | 0x18   | Control (`CTRL_DECOMPRESS`)     |
+//! | 0x1C   | Tag                             |
+//!
+//! Completion layout:
+//!
+//! | Offset | Field                           |
+//! |--------|---------------------------------|
+//! | 0x00   | Tag                             |
+//! | 0x04   | Job status, as `StatusRegister` |
+//! | 0x08   | Output length                   |
+//! | 0x0C   | Reserved                        |
+
+use std::fmt;
+use std::time::Instant;
+
+use super::backend::RegisterIo;
+use super::dma::DmaBuffer;
+use super::engine::Hold;
+use super::pcie::constants;
+use super::status::StatusRegister;
+use super::{EngineGuard, PcieDevice, BLOCK_SIZE};
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Register offsets relative to the command queue register block
+pub mod registers {
+    /// Submission ring IOVA (64-bit)
+    pub const SQ_BASE: u64 = 0x00;
+
+    /// Completion ring IOVA (64-bit)
+    pub const CQ_BASE: u64 = 0x08;
+
+    /// Number of entries in each ring
+    pub const QUEUE_SIZE: u64 = 0x10;
+
+    /// Submission producer index doorbell, written by the driver
+    pub const SQ_HEAD: u64 = 0x14;
+
+    /// Submission consumer index, advanced by the engine
+    pub const SQ_TAIL: u64 = 0x18;
+
+    /// Completion producer index, advanced by the engine
+    pub const CQ_HEAD: u64 = 0x1C;
+
+    /// Completion consumer index doorbell, written by the driver
+    pub const CQ_TAIL: u64 = 0x20;
+
+    /// Queue control
+    pub const CONTROL: u64 = 0x24;
+
+    /// Control: fetch commands
+    pub const CONTROL_ENABLE: u32 = 1 << 0;
+
+    /// Control: clear indices and drop running jobs
+    pub const CONTROL_RESET: u32 = 1 << 31;
+}
+
+/// Size of one command in bytes
+pub const COMMAND_SIZE: usize = 32;
+
+/// Size of one completion in bytes
+pub const COMPLETION_SIZE: usize = 16;
+
+/// Default number of jobs in flight, one per compression unit
+pub const DEFAULT_QUEUE_DEPTH: u32 = 8;
+
+/// Largest supported queue depth
+pub const MAX_QUEUE_DEPTH: u32 = 256;
+
+/// A command as stored in the submission ring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Command {
+    /// Input IOVA
+    pub input_iova: u64,
+
+    /// Output IOVA, with room for the output window
+    pub output_iova: u64,
+
+    /// Input length in bytes
+    pub input_len: u32,
+
+    /// Uncompressed block length in bytes
+    pub block_len: u32,
+
+    /// Control bits
+    pub control: u32,
+
+    /// Tag echoed by the completion
+    pub tag: u32,
+}
+
+impl Command {
+    /// Serialize into the ring format
+    pub fn to_bytes(&self) -> [u8; COMMAND_SIZE] {
+        let mut bytes = [0u8; COMMAND_SIZE];
+        bytes[0x00..0x08].copy_from_slice(&self.input_iova.to_le_bytes());
+        bytes[0x08..0x10].copy_from_slice(&self.output_iova.to_le_bytes());
+        bytes[0x10..0x14].copy_from_slice(&self.input_len.to_le_bytes());
+        bytes[0x14..0x18].copy_from_slice(&self.block_len.to_le_bytes());
+        bytes[0x18..0x1C].copy_from_slice(&self.control.to_le_bytes());
+        bytes[0x1C..0x20].copy_from_slice(&self.tag.to_le_bytes());
+        bytes
+    }
+
+    /// Parse from the ring format
+    ///
+    /// # Panics
+    /// Panics if `bytes` is shorter than `COMMAND_SIZE`
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+
+        Self {
+            input_iova: u64::from_le_bytes(bytes[0x00..0x08].try_into().unwrap()),
+            output_iova: u64::from_le_bytes(bytes[0x08..0x10].try_into().unwrap()),
+            input_len: u32_at(0x10),
+            block_len: u32_at(0x14),
+            control: u32_at(0x18),
+            tag: u32_at(0x1C),
+        }
+    }
+}
+
+/// A completion as stored in the completion ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompletionEntry {
+    /// Tag of the finished command
+    pub tag: u32,
+
+    /// Final status of the job
+    pub status: StatusRegister,
+
+    /// Output length in bytes
+    pub output_len: u32,
+}
+
+impl CompletionEntry {
+    /// Serialize into the ring format
+    pub fn to_bytes(&self) -> [u8; COMPLETION_SIZE] {
+        let mut bytes = [0u8; COMPLETION_SIZE];
+        bytes[0x00..0x04].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[0x04..0x08].copy_from_slice(&self.status.raw().to_le_bytes());
+        bytes[0x08..0x0C].copy_from_slice(&self.output_len.to_le_bytes());
+        bytes
+    }
+
+    /// Parse from the ring format
+    ///
+    /// # Panics
+    /// Panics if `bytes` is shorter than `COMPLETION_SIZE`
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+
+        Self {
+            tag: u32_at(0x00),
+            status: StatusRegister::from_raw(u32_at(0x04)),
+            output_len: u32_at(0x08),
+        }
+    }
+}
+
+/// Identifier of a submitted job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+impl JobId {
+    /// Submission sequence number, starting at 0 for each queue
+    pub fn value(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job {}", self.0)
+    }
+}
+
+/// Outcome of a finished job
+#[derive(Debug)]
+pub struct JobCompletion {
+    /// Job the result belongs to
+    pub id: JobId,
+
+    /// Output, or the error the job failed with
+    pub result: Lzma2Result<Vec<u8>>,
+}
+
+/// Job occupying a slot
+#[derive(Debug, Clone, Copy)]
+struct SlotJob {
+    id: JobId,
+    block_len: usize,
+    decompress: bool,
+}
+
+/// Host buffers of one in-flight job, addressed by its tag
+#[derive(Debug)]
+struct Slot {
+    input: DmaBuffer,
+    output: DmaBuffer,
+    job: Option<SlotJob>,
+}
+
+/// Submission and completion rings of a device
+///
+/// Created by `PcieDevice::command_queue`. The queue owns the engine while
+/// it exists; dropping it disables the rings.
+pub struct CommandQueue<'a, B: RegisterIo> {
+    device: &'a PcieDevice<B>,
+
+    /// Keeps other jobs off the engine
+    _engine: EngineGuard<'a>,
+
+    /// Offset of the queue register block in the BAR
+    base: u64,
+
+    /// Submission ring
+    commands: DmaBuffer,
+
+    /// Completion ring
+    completions: DmaBuffer,
+
+    /// Buffers of each tag
+    slots: Vec<Slot>,
+
+    /// Free-running submission producer index
+    sq_head: u32,
+
+    /// Free-running completion consumer index
+    cq_tail: u32,
+
+    /// Sequence number of the next job
+    next_id: u64,
+}
+
+impl<'a, B: RegisterIo> CommandQueue<'a, B> {
+    /// Allocate rings and buffers for `depth` jobs and enable the queue
+    pub(super) fn new(device: &'a PcieDevice<B>, depth: u32) -> Lzma2Result<Self> {
+        if depth == 0 || depth > MAX_QUEUE_DEPTH {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Queue depth must be 1 to {}. Requested depth: {}", MAX_QUEUE_DEPTH, depth)
+            ));
+        }
+
+        let engine = device.lock_engine(Hold::Queue)?;
+        let allocator = device.io.dma_allocator().ok_or_else(|| Lzma2Error::TransferError(
+            "Register backend does not support DMA".to_string()
+        ))?;
+        let entries = depth as usize;
+        let slots = (0..entries)
+            .map(|_| Ok(Slot {
+                input: allocator.alloc_dma(BLOCK_SIZE)?,
+                output: allocator.alloc_dma(constants::OUTPUT_WINDOW_SIZE as usize)?,
+                job: None,
+            }))
+            .collect::<Lzma2Result<Vec<_>>>()?;
+
+        let queue = Self {
+            device,
+            _engine: engine,
+            base: device.registers.queue,
+            commands: allocator.alloc_dma(entries * COMMAND_SIZE)?,
+            completions: allocator.alloc_dma(entries * COMPLETION_SIZE)?,
+            slots,
+            sq_head: 0,
+            cq_tail: 0,
+            next_id: 0,
+        };
+
+        let io = &device.io;
+        io.write32(queue.base + registers::CONTROL, registers::CONTROL_RESET)?;
+        io.write64(queue.base + registers::SQ_BASE, queue.commands.iova())?;
+        io.write64(queue.base + registers::CQ_BASE, queue.completions.iova())?;
+        io.write32(queue.base + registers::QUEUE_SIZE, depth)?;
+        io.write32(queue.base + registers::CONTROL, registers::CONTROL_ENABLE)?;
+        Ok(queue)
+    }
+
+    /// Maximum number of jobs in flight
+    pub fn depth(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Number of submitted jobs whose completion has not been collected
+    pub fn in_flight(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.job.is_some()).count()
+    }
+
+    /// Whether every slot holds a job
+    pub fn is_full(&self) -> bool {
+        self.in_flight() == self.depth()
+    }
+
+    /// Queue compression of a block of 1 to `BLOCK_SIZE` bytes
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` for an invalid block,
+    /// `Lzma2Error::TransferError` if the queue is full, or the register
+    /// access error
+    pub fn submit_compress(&mut self, input: &[u8]) -> Lzma2Result<JobId> {
+        if input.is_empty() || input.len() > BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Block size must be 1 to {} bytes. Current size: {}", BLOCK_SIZE, input.len())
+            ));
+        }
+        self.submit(input, input.len(), false)
+    }
+
+    /// Queue decompression of a block that restores to `uncompressed_len`
+    /// bytes
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` for an invalid block,
+    /// `Lzma2Error::TransferError` if the queue is full, or the register
+    /// access error
+    pub fn submit_decompress(&mut self, input: &[u8], uncompressed_len: usize) -> Lzma2Result<JobId> {
+        if input.is_empty() || input.len() > BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Compressed block must be 1 to {} bytes. Current size: {}", BLOCK_SIZE, input.len())
+            ));
+        }
+        if uncompressed_len == 0 || uncompressed_len > BLOCK_SIZE {
+            return Err(Lzma2Error::InputValidationError(
+                format!("Block size must be 1 to {} bytes. Requested size: {}", BLOCK_SIZE, uncompressed_len)
+            ));
+        }
+        self.submit(input, uncompressed_len, true)
+    }
+
+    fn submit(&mut self, input: &[u8], block_len: usize, decompress: bool) -> Lzma2Result<JobId> {
+        let Some(tag) = self.slots.iter().position(|slot| slot.job.is_none()) else {
+            return Err(Lzma2Error::TransferError(
+                format!("Command queue is full: {} jobs in flight", self.depth())
+            ));
+        };
+
+        let id = JobId(self.next_id);
+        let index = (self.sq_head as usize % self.depth()) * COMMAND_SIZE;
+        let slot = &mut self.slots[tag];
+        slot.input.as_mut_slice()[..input.len()].copy_from_slice(input);
+        let command = Command {
+            input_iova: slot.input.iova(),
+            output_iova: slot.output.iova(),
+            input_len: input.len() as u32,
+            block_len: block_len as u32,
+            control: if decompress { constants::CTRL_DECOMPRESS } else { 0 },
+            tag: tag as u32,
+        };
+
+        self.commands.as_mut_slice()[index..index + COMMAND_SIZE].copy_from_slice(&command.to_bytes());
+
+        // Register writes are ordered after the ring stores
+        let sq_head = self.sq_head.wrapping_add(1);
+        self.device.io.write32(self.base + registers::SQ_HEAD, sq_head)?;
+        self.sq_head = sq_head;
+        self.next_id += 1;
+        slot.job = Some(SlotJob { id, block_len, decompress });
+        Ok(id)
+    }
+
+    /// Collect the jobs finished so far without blocking
+    ///
+    /// Completions for a tag without a job in flight are consumed and
+    /// dropped.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if register access fails; errors of individual
+    /// jobs are reported in their `JobCompletion`
+    pub fn poll(&mut self) -> Lzma2Result<Vec<JobCompletion>> {
+        let cq_head = self.device.io.read32(self.base + registers::CQ_HEAD)?;
+        let available = cq_head.wrapping_sub(self.cq_tail);
+        if available as usize > self.depth() {
+            return Err(Lzma2Error::TransferError(
+                format!("Completion head 0x{:x} overran tail 0x{:x}", cq_head, self.cq_tail)
+            ));
+        }
+
+        let mut finished = Vec::with_capacity(available as usize);
+        while self.cq_tail != cq_head {
+            let index = (self.cq_tail as usize % self.depth()) * COMPLETION_SIZE;
+            let entry = CompletionEntry::from_bytes(&self.completions.as_slice()[index..index + COMPLETION_SIZE]);
+            finished.extend(self.complete(&entry));
+            self.cq_tail = self.cq_tail.wrapping_add(1);
+        }
+
+        // Return every consumed slot, the engine stalls on a full ring
+        if available > 0 {
+            self.device.io.write32(self.base + registers::CQ_TAIL, self.cq_tail)?;
+        }
+        Ok(finished)
+    }
+
+    /// Block until at least one job finishes and collect the finished jobs
+    ///
+    /// Returns an empty list if no job is in flight.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::TimeoutError` if no job finishes within the
+    /// device's completion timeout, or the error of `poll`
+    pub fn wait(&mut self) -> Lzma2Result<Vec<JobCompletion>> {
+        let deadline = Instant::now() + self.device.completion_timeout;
+        loop {
+            let finished = self.poll()?;
+            if !finished.is_empty() || self.in_flight() == 0 {
+                return Ok(finished);
+            }
+
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => return Err(Lzma2Error::TimeoutError),
+            };
+            match &self.device.interrupt {
+                Some(interrupt) => {
+                    interrupt.wait(Some(remaining))?;
+                },
+                None => std::thread::sleep(remaining.min(constants::POLL_INTERVAL)),
+            }
+        }
+    }
+
+    /// Release the slot of a finished job and build its result
+    ///
+    /// Returns `None` if no job holds the tag of `entry`.
+    fn complete(&mut self, entry: &CompletionEntry) -> Option<JobCompletion> {
+        let Some(job) = self.slots.get_mut(entry.tag as usize).and_then(|slot| slot.job.take()) else {
+            tracing::warn!("Dropping completion for tag {} without a job in flight", entry.tag);
+            return None;
+        };
+        let slot = &self.slots[entry.tag as usize];
+
+        let result = if entry.status.has_error() {
+            let snapshot = self.device.snapshot_registers(entry.status);
+            Err(match entry.status.error() {
+                Some(code) => Lzma2Error::from_hardware(code, snapshot),
+                None => Lzma2Error::HardwareFault(snapshot),
+            })
+        } else if u64::from(entry.output_len) > constants::OUTPUT_WINDOW_SIZE {
+            Err(Lzma2Error::OutputOverflow {
+                size: u64::from(entry.output_len),
+                capacity: constants::OUTPUT_WINDOW_SIZE,
+            })
+        } else if job.decompress && entry.output_len as usize != job.block_len {
+            Err(Lzma2Error::ProcessingError(
+                format!("Block decompressed to {} bytes, expected {}", entry.output_len, job.block_len)
+            ))
+        } else {
+            Ok(slot.output.as_slice()[..entry.output_len as usize].to_vec())
+        };
+
+        Some(JobCompletion { id: job.id, result })
+    }
+}
+
+impl<B: RegisterIo> fmt::Debug for CommandQueue<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandQueue")
+            .field("depth", &self.depth())
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+impl<B: RegisterIo> Drop for CommandQueue<'_, B> {
+    fn drop(&mut self) {
+        // Stop the engine before the rings and buffers are unmapped
+        let _ = self.device.io.write32(self.base + registers::CONTROL, registers::CONTROL_RESET);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{HardwareCompressionDevice, HardwareErrorCode, SimulatedDevice, SimulatorConfig};
+    use crate::device::simulator::tests::sample;
+
+    fn queue_device() -> SimulatedDevice {
+        SimulatedDevice::simulated_with(SimulatorConfig {
+            command_queue: true,
+            decode_engine: true,
+            ..SimulatorConfig::default()
+        })
+    }
+
+    fn wait_all(queue: &mut CommandQueue<'_, impl RegisterIo>) -> Lzma2Result<Vec<JobCompletion>> {
+        let mut finished = Vec::new();
+        while queue.in_flight() > 0 {
+            finished.extend(queue.wait()?);
+        }
+        Ok(finished)
+    }
+
+    #[test]
+    fn test_command_layout() {
+        let command = Command {
+            input_iova: 0x1_0000_1000,
+            output_iova: 0x2000,
+            input_len: 100,
+            block_len: 100,
+            control: constants::CTRL_DECOMPRESS,
+            tag: 7,
+        };
+        let bytes = command.to_bytes();
+        assert_eq!(bytes[0x1C], 7);
+        assert_eq!(Command::from_bytes(&bytes), command);
+
+        let entry = CompletionEntry {
+            tag: 3,
+            status: StatusRegister::from_raw(0x0400),
+            output_len: 1234,
+        };
+        assert_eq!(CompletionEntry::from_bytes(&entry.to_bytes()), entry);
+    }
+
+    #[test]
+    fn test_out_of_order_completion() -> Lzma2Result<()> {
+        let device = queue_device();
+        let reference = SimulatedDevice::simulated();
+        let mut queue = device.command_queue(4)?;
+
+        // A full block keeps its unit busy longer than a short one
+        let inputs = [sample(BLOCK_SIZE, 3), sample(1000, 5), sample(9000, 7)];
+        let ids = inputs.iter()
+            .map(|input| queue.submit_compress(input))
+            .collect::<Lzma2Result<Vec<_>>>()?;
+        assert_eq!(queue.in_flight(), 3);
+
+        let finished = wait_all(&mut queue)?;
+        let order: Vec<JobId> = finished.iter().map(|job| job.id).collect();
+        assert_eq!(order, [ids[1], ids[2], ids[0]]);
+
+        for job in finished {
+            let index = ids.iter().position(|&id| id == job.id).unwrap();
+            let compressed = job.result?;
+            assert_eq!(compressed, reference.compress_block(&inputs[index])?);
+
+            let id = queue.submit_decompress(&compressed, inputs[index].len())?;
+            let restored = wait_all(&mut queue)?.remove(0);
+            assert_eq!(restored.id, id);
+            assert_eq!(restored.result?, inputs[index]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_limits_and_errors() -> Lzma2Result<()> {
+        // The RTL has no command queue
+        assert!(SimulatedDevice::simulated().command_queue(4).is_err());
+
+        let device = queue_device();
+        assert!(device.command_queue(0).is_err());
+        let mut queue = device.command_queue(2)?;
+
+        queue.submit_compress(&sample(100, 3))?;
+        let bad = queue.submit_decompress(&[0xFF; 64], 1000)?;
+        assert!(queue.is_full());
+        assert!(matches!(queue.submit_compress(b"more"), Err(Lzma2Error::TransferError(_))));
+        assert!(queue.submit_compress(&[]).is_err());
+
+        // A failing job does not affect its neighbour
+        let finished = wait_all(&mut queue)?;
+        assert_eq!(finished.len(), 2);
+        for job in finished {
+            if job.id == bad {
+                assert!(matches!(job.result, Err(Lzma2Error::HardwareCrcMismatch(_))));
+            } else {
+                assert!(job.result.is_ok());
+            }
+        }
+
+        device.io().inject_error(HardwareErrorCode::Stall);
+        queue.submit_compress(&sample(100, 3))?;
+        assert!(matches!(wait_all(&mut queue)?[0].result, Err(Lzma2Error::HardwareStall(_))));
+        assert!(queue.wait()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stray_completion() -> Lzma2Result<()> {
+        let device = queue_device();
+        let mut queue = device.command_queue(4)?;
+        let lost = queue.submit_compress(&sample(1000, 3))?;
+        let kept = queue.submit_compress(&sample(1000, 5))?;
+
+        // Let both jobs finish, then retag the first completion
+        let cq_head = queue.base + registers::CQ_HEAD;
+        while device.io().read32(cq_head)? != 2 {
+            std::thread::yield_now();
+        }
+        queue.completions.as_mut_slice()[0x00..0x04].copy_from_slice(&99u32.to_le_bytes());
+
+        // The stray entry is skipped and both slots go back to the engine
+        let finished = queue.poll()?;
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].id, kept);
+        assert!(finished[0].result.is_ok());
+        assert_eq!(device.io().read32(queue.base + registers::CQ_TAIL)?, 2);
+        assert_eq!(queue.in_flight(), 1);
+        assert!(queue.slots.iter().any(|slot| slot.job.is_some_and(|job| job.id == lost)));
+
+        // The queue keeps running
+        let id = queue.submit_compress(&sample(500, 7))?;
+        assert_eq!(queue.wait()?[0].id, id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_held_by_queue() -> Lzma2Result<()> {
+        use crate::transfer::TransferConfig;
+        use std::time::Duration;
+
+        let device = queue_device();
+        let block = sample(BLOCK_SIZE, 3);
+        let queue = device.command_queue(2)?;
+
+        // Jobs of the thread holding the queue fail instead of waiting for it
+        assert!(matches!(device.compress_block(&block), Err(Lzma2Error::EngineBusy(_))));
+        assert!(matches!(device.compress_batch(&[&block])[0], Err(Lzma2Error::EngineBusy(_))));
+        assert!(matches!(device.get_performance_metrics(), Err(Lzma2Error::EngineBusy(_))));
+        assert!(matches!(device.set_transfer_config(&TransferConfig::default()), Err(Lzma2Error::EngineBusy(_))));
+        assert!(matches!(device.command_queue(2), Err(Lzma2Error::EngineBusy(_))));
+
+        // Other threads wait for it
+        std::thread::scope(|scope| {
+            let job = scope.spawn(|| device.compress_block(&block));
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!job.is_finished());
+            drop(queue);
+            assert!(job.join().unwrap().is_ok());
+        });
+        assert!(device.get_performance_metrics().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupt_driven_queue() -> Lzma2Result<()> {
+        let device = queue_device();
+        let interrupt = device.io().interrupt()?;
+        let device = device.with_interrupt(interrupt);
+        let mut queue = device.command_queue(DEFAULT_QUEUE_DEPTH)?;
+
+        for seed in 1..=DEFAULT_QUEUE_DEPTH as usize {
+            queue.submit_compress(&sample(4000, seed))?;
+        }
+        let finished = wait_all(&mut queue)?;
+        assert_eq!(finished.len(), DEFAULT_QUEUE_DEPTH as usize);
+        assert!(finished.iter().all(|job| job.result.is_ok()));
+
+        Ok(())
+    }
+}
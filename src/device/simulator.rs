@@ -0,0 +1,1312 @@
+//! Simulated LZMA2 FPGA device
+//!
+//! Behavioral model of `lzma2_top` behind the same register map as
+//! `PcieDevice`. The model runs the `lzma2_system_controller` state machine,
+//! publishes its status word and the performance counters, and stands in for
+//! the compression engine with a software LZMA encoder, so the driver's full
+//! control flow runs without an FPGA. Models of the descriptor-ring DMA
+//! engine and the streaming rings move data through a software IOMMU.
+//! Like the RTL, the model has no decompression mode unless
+//! `SimulatorConfig::decode_engine` enables one, no command queue unless
+//! `SimulatorConfig::command_queue` does, and always loads a full input
+//! window unless `SimulatorConfig::short_blocks` adds a block length
+//! register. The chaining control bits are ignored unless
+//! `SimulatorConfig::chained_streams` is set.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Duration;
+
+use super::backend::RegisterIo;
+use super::dma::{
+    registers as dma_regs, Descriptor, DmaAllocator, DmaBuffer, DmaFault, DmaMapper,
+    IovaAllocator, DESCRIPTOR_SIZE, DESC_DONE, DESC_ERROR, DESC_TO_DEVICE,
+};
+use super::interrupt::EventFdInterrupt;
+use super::pcie::{constants, RegisterMap};
+use super::queue::{registers as queue_regs, Command, CompletionEntry, COMMAND_SIZE, COMPLETION_SIZE};
+use super::stream::registers as stream_regs;
+use super::status::{ControllerState, HardwareErrorCode, StatusRegister};
+use super::{DeviceConfig, EngineFeatures, PcieDevice};
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::lzma::decoder::LzmaDecoder;
+use crate::lzma::encoder::{EncoderStats, LzmaEncoder};
+use crate::lzma::{LzmaProperties, DICT_SIZE, INPUT_SIZE};
+
+/// Parallel compression units (`lzma2_pkg::PARALLEL_UNITS`)
+const PARALLEL_UNITS: u32 = 8;
+
+/// Pipeline depth (`lzma2_pkg::PIPE_STAGES`)
+const PIPE_STAGES: u32 = 8;
+
+/// The driver running on the behavioral model
+pub type SimulatedDevice = PcieDevice<SimulatedBackend>;
+
+/// Behavioral model configuration
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// Status reads spent in COMPRESS before a job completes
+    pub latency_polls: u32,
+
+    /// Time a job runs before raising its MSI, with an interrupt attached
+    pub interrupt_delay: Duration,
+
+    /// Model a decompression mode, which the RTL does not implement
+    pub decode_engine: bool,
+
+    /// Model a command queue, which the RTL does not implement
+    pub command_queue: bool,
+
+    /// Model a block length register, which the RTL does not implement
+    pub short_blocks: bool,
+
+    /// Model LZMA streams spanning several jobs, which the RTL does not
+    /// implement
+    pub chained_streams: bool,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            latency_polls: 2,
+            interrupt_delay: Duration::from_millis(1),
+            decode_engine: false,
+            command_queue: false,
+            short_blocks: false,
+            chained_streams: false,
+        }
+    }
+}
+
+impl SimulatorConfig {
+    /// Engine extensions the model implements
+    pub fn features(&self) -> EngineFeatures {
+        EngineFeatures {
+            decode_engine: self.decode_engine,
+            short_blocks: self.short_blocks,
+            chained_streams: self.chained_streams,
+            command_queue: self.command_queue,
+        }
+    }
+}
+
+/// Result of an engine run waiting to be published
+struct JobResult {
+    /// Output window contents
+    output: Vec<u8>,
+
+    /// Performance counter values
+    counters: [u32; 10],
+
+    /// Error reported by the engine
+    error: Option<HardwareErrorCode>,
+}
+
+/// Command running on a compression unit
+struct QueuedJob {
+    /// Tag of the command
+    tag: u32,
+
+    /// Output IOVA of the command
+    output_iova: u64,
+
+    /// Status polls left before the job completes
+    polls: u32,
+
+    result: JobResult,
+}
+
+/// IOMMU of the model, translating IOVAs to host addresses
+struct SimIommu {
+    iova: IovaAllocator,
+
+    /// IOVA -> (host address, size)
+    mappings: Mutex<BTreeMap<u64, (usize, u64)>>,
+}
+
+impl SimIommu {
+    fn new() -> Self {
+        Self {
+            iova: IovaAllocator::new(1 << 32),
+            mappings: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Host address of `len` bytes at `iova`, if the whole range is mapped
+    fn translate(&self, iova: u64, len: usize) -> Option<*mut u8> {
+        let mappings = self.mappings.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (&start, &(vaddr, size)) = mappings.range(..=iova).next_back()?;
+        let offset = iova - start;
+        (offset + len as u64 <= size).then(|| (vaddr + offset as usize) as *mut u8)
+    }
+
+    /// Copy host memory at `iova` into `buffer`
+    fn read(&self, iova: u64, buffer: &mut [u8]) -> bool {
+        let Some(ptr) = self.translate(iova, buffer.len()) else {
+            return false;
+        };
+        // SAFETY: the range lies in a live mapping; DmaBuffer unmaps before
+        // releasing its memory
+        unsafe { std::ptr::copy_nonoverlapping(ptr, buffer.as_mut_ptr(), buffer.len()) };
+        true
+    }
+
+    /// Copy `data` into host memory at `iova`
+    fn write(&self, iova: u64, data: &[u8]) -> bool {
+        let Some(ptr) = self.translate(iova, data.len()) else {
+            return false;
+        };
+        // SAFETY: as in `read`
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+        true
+    }
+}
+
+impl DmaMapper for SimIommu {
+    fn map(&self, vaddr: u64, size: u64) -> Lzma2Result<u64> {
+        let iova = self.iova.allocate(size);
+        self.mappings.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(iova, (vaddr as usize, size));
+        Ok(iova)
+    }
+
+    fn unmap(&self, iova: u64, _size: u64) -> Lzma2Result<()> {
+        self.mappings.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&iova)
+            .ok_or(Lzma2Error::DeviceAccessError)?;
+        self.iova.release(iova);
+        Ok(())
+    }
+}
+
+/// Progress of a job fed through the streaming rings
+struct StreamJob {
+    /// Decompression instead of compression
+    decompress: bool,
+
+    /// Input bytes consumed into the input window
+    received: usize,
+
+    /// Engine output, once the engine has run
+    output: Vec<u8>,
+
+    /// Output bytes pushed into the output ring
+    sent: usize,
+}
+
+/// Mutable model state
+struct Model {
+    /// Handle for the completion timers
+    this: Weak<Mutex<Model>>,
+    config: SimulatorConfig,
+    registers: RegisterMap,
+    memory: Vec<u8>,
+    state: ControllerState,
+    error: Option<HardwareErrorCode>,
+    pending_polls: u32,
+    pending: Option<JobResult>,
+    injected_error: Option<HardwareErrorCode>,
+    transitions: Vec<ControllerState>,
+    interrupt: Option<EventFdInterrupt>,
+    iommu: Arc<SimIommu>,
+    stream: Option<StreamJob>,
+
+    /// Control word that started the current job
+    control: u32,
+
+    /// LZMA stream left open by `CTRL_KEEP_OPEN`
+    open_stream: Option<LzmaEncoder>,
+
+    /// Commands fetched from the submission ring, in fetch order
+    queued: Vec<QueuedJob>,
+}
+
+/// Register backend implemented by the behavioral model
+pub struct SimulatedBackend {
+    model: Arc<Mutex<Model>>,
+    iommu: Arc<SimIommu>,
+}
+
+impl fmt::Debug for SimulatedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimulatedBackend")
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        Self::new(SimulatorConfig::default())
+    }
+}
+
+impl SimulatedBackend {
+    /// Create a model in the IDLE state
+    pub fn new(config: SimulatorConfig) -> Self {
+        let iommu = Arc::new(SimIommu::new());
+        Self {
+            model: Arc::new_cyclic(|this| Mutex::new(Model {
+                this: this.clone(),
+                config,
+                registers: RegisterMap::default(),
+                memory: vec![0; constants::MIN_BAR_SIZE as usize],
+                state: ControllerState::Idle,
+                error: None,
+                pending_polls: 0,
+                pending: None,
+                injected_error: None,
+                transitions: vec![ControllerState::Idle],
+                interrupt: None,
+                iommu: iommu.clone(),
+                stream: None,
+                control: 0,
+                open_stream: None,
+                queued: Vec::new(),
+            })),
+            iommu,
+        }
+    }
+
+    /// Current controller state
+    pub fn state(&self) -> ControllerState {
+        self.lock().state
+    }
+
+    /// Every state the controller has entered, oldest first
+    pub fn transitions(&self) -> Vec<ControllerState> {
+        self.lock().transitions.clone()
+    }
+
+    /// Fail the next job with the given error code
+    pub fn inject_error(&self, code: HardwareErrorCode) {
+        self.lock().injected_error = Some(code);
+    }
+
+    /// Raise an MSI on job completion and return the eventfd receiving it
+    ///
+    /// With an interrupt attached, jobs complete `interrupt_delay` after
+    /// they start instead of after `latency_polls` status reads, so the
+    /// driver has to wait on the eventfd.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the eventfd cannot be created
+    pub fn interrupt(&self) -> Lzma2Result<EventFdInterrupt> {
+        let interrupt = EventFdInterrupt::new()?;
+        self.lock().interrupt = Some(interrupt.try_clone()?);
+        Ok(interrupt)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Model> {
+        self.model.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Model {
+    fn enter(&mut self, state: ControllerState) {
+        self.state = state;
+        self.transitions.push(state);
+    }
+
+    /// Pack the controller outputs like `lzma2_system_controller`
+    fn status(&self) -> StatusRegister {
+        StatusRegister::new(self.state, self.error.map_or(0, HardwareErrorCode::code))
+    }
+
+    fn write_control(&mut self, value: u32) {
+        if value & constants::CTRL_RESET != 0 {
+            self.reset();
+            return;
+        }
+
+        let start = value & constants::CTRL_START != 0;
+        if start && self.state == ControllerState::Idle {
+            // Like the RTL, ignore the chaining bits unless modelled
+            let chaining = constants::CTRL_CONTINUE | constants::CTRL_KEEP_OPEN | constants::CTRL_END_MARKER;
+            self.control = if self.config.chained_streams { value } else { value & !chaining };
+        }
+        match self.state {
+            ControllerState::Idle if start && value & constants::CTRL_STREAM != 0 => {
+                // Input arrives through the ring; the engine starts once it
+                // has a full window or the end of input
+                self.enter(ControllerState::Init);
+                self.stream = Some(StreamJob {
+                    decompress: value & constants::CTRL_DECOMPRESS != 0,
+                    received: 0,
+                    output: Vec::new(),
+                    sent: 0,
+                });
+                self.run_stream();
+            },
+            ControllerState::Idle if start => {
+                self.enter(ControllerState::Init);
+
+                // The input window is already loaded, so input_valid is high
+                self.enter(ControllerState::Compress);
+                self.pending = Some(if value & constants::CTRL_DECOMPRESS != 0 {
+                    self.run_decoder()
+                } else {
+                    self.run_encoder()
+                });
+                if self.interrupt.is_some() {
+                    self.pending_polls = 0;
+                    self.schedule_completion();
+                } else {
+                    self.pending_polls = self.config.latency_polls;
+                }
+            },
+            ControllerState::Complete | ControllerState::Error if !start => {
+                self.enter(ControllerState::Idle);
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+        self.stream = None;
+        self.open_stream = None;
+        self.error = None;
+        let counters = self.registers.performance_counters as usize;
+        self.memory[counters..counters + 40].fill(0);
+        if self.state != ControllerState::Idle {
+            self.enter(ControllerState::Idle);
+        }
+    }
+
+    /// Finish the running jobs from a helper thread after `interrupt_delay`
+    fn schedule_completion(&self) {
+        let model = self.this.clone();
+        let delay = self.config.interrupt_delay;
+        thread::spawn(move || {
+            thread::sleep(delay);
+            // A timer outliving its job only finishes a later one early
+            if let Some(model) = model.upgrade() {
+                let mut model = model.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                model.step();
+                model.step_queue();
+            }
+        });
+    }
+
+    /// Advance a running job by one status poll
+    fn step(&mut self) {
+        if self.state != ControllerState::Compress {
+            return;
+        }
+        if self.stream.as_ref().is_some_and(|job| job.sent < job.output.len()) {
+            // Stalled on out_ready until the driver drains the ring
+            return;
+        }
+        if self.pending_polls > 0 {
+            self.pending_polls -= 1;
+            return;
+        }
+
+        let Some(mut result) = self.pending.take() else {
+            return;
+        };
+        if let Some(code) = self.injected_error.take() {
+            result.error = Some(code);
+        }
+
+        self.enter(ControllerState::Verify);
+
+        let output = self.registers.output_data as usize;
+        self.memory[output..output + result.output.len()].copy_from_slice(&result.output);
+
+        let counters = self.registers.performance_counters as usize;
+        for (i, value) in result.counters.iter().enumerate() {
+            let offset = counters + i * 4;
+            self.memory[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        self.error = result.error;
+        if result.error.is_some() {
+            self.enter(ControllerState::Error);
+        } else {
+            self.enter(ControllerState::Complete);
+        }
+
+        if let Some(interrupt) = &self.interrupt {
+            // A lost MSI surfaces as a completion timeout
+            let _ = interrupt.signal();
+        }
+    }
+
+    fn input_window(&self) -> &[u8] {
+        let input = self.registers.input_data as usize;
+        &self.memory[input..input + INPUT_SIZE]
+    }
+
+    /// Block length register, with 0 selecting a full block
+    ///
+    /// Like the RTL, the model loads a full window unless configured with
+    /// `short_blocks`.
+    fn block_len(&self) -> Option<usize> {
+        if !self.config.short_blocks {
+            return Some(INPUT_SIZE);
+        }
+        match self.word(self.registers.block_length as usize) as usize {
+            0 => Some(INPUT_SIZE),
+            len if len <= INPUT_SIZE => Some(len),
+            _ => None,
+        }
+    }
+
+    fn failed(code: HardwareErrorCode) -> JobResult {
+        JobResult {
+            output: Vec::new(),
+            counters: [0; 10],
+            error: Some(code),
+        }
+    }
+
+    /// Model of `lzma2_compression_engine`
+    ///
+    /// Chained jobs carry the dictionary, model and range coder over from
+    /// the previous block; the stream is only flushed by a closing block.
+    fn run_encoder(&mut self) -> JobResult {
+        let Some(len) = self.block_len() else {
+            return Self::failed(HardwareErrorCode::InvalidState);
+        };
+
+        let open_stream = self.open_stream.take();
+        let encoder = if self.control & constants::CTRL_CONTINUE != 0 {
+            match open_stream {
+                Some(encoder) => encoder,
+                None => return Self::failed(HardwareErrorCode::InvalidState),
+            }
+        } else {
+            LzmaEncoder::new(LzmaProperties::default(), DICT_SIZE)
+        };
+
+        let (result, open_stream) = Self::encode(encoder, &self.input_window()[..len], self.control);
+        self.open_stream = open_stream;
+        result
+    }
+
+    /// Run one block through `encoder`, returning the encoder if `control`
+    /// keeps its stream open
+    fn encode(mut encoder: LzmaEncoder, input: &[u8], control: u32) -> (JobResult, Option<LzmaEncoder>) {
+        let before = encoder.stats();
+        encoder.encode_block(input);
+        let after = encoder.stats();
+        let stats = EncoderStats {
+            literals: after.literals - before.literals,
+            matches: after.matches - before.matches,
+            hash_hits: after.hash_hits - before.hash_hits,
+            hash_misses: after.hash_misses - before.hash_misses,
+        };
+
+        let (output, open_stream) = if control & constants::CTRL_KEEP_OPEN != 0 {
+            (encoder.take_output(), Some(encoder))
+        } else {
+            if control & constants::CTRL_END_MARKER != 0 {
+                encoder.encode_end_marker();
+            }
+            (encoder.finish(), None)
+        };
+
+        if output.len() as u64 > constants::OUTPUT_WINDOW_SIZE {
+            return (Self::failed(HardwareErrorCode::Overflow), open_stream);
+        }
+
+        let total = input.len() as u32;
+        let compressed = output.len() as u32;
+        let counters = [
+            total,
+            compressed,
+            total / PARALLEL_UNITS + PIPE_STAGES,
+            stats.hash_hits as u32,
+            stats.hash_misses as u32,
+            stats.matches as u32,
+            stats.literals as u32,
+            0,
+            ((u64::from(total) << 16) / u64::from(compressed.max(1))) as u32,
+            100,
+        ];
+
+        (JobResult { output, counters, error: None }, open_stream)
+    }
+
+    /// Model of a decode engine for the driver's decompression mode
+    fn run_decoder(&self) -> JobResult {
+        let Some(len) = self.block_len().filter(|_| self.config.decode_engine) else {
+            return Self::failed(HardwareErrorCode::InvalidState);
+        };
+        Self::decode(self.input_window(), len)
+    }
+
+    /// Restore `len` bytes from `input`
+    fn decode(input: &[u8], len: usize) -> JobResult {
+        let mut output = Vec::with_capacity(len);
+        let decoded = LzmaDecoder::new(LzmaProperties::default(), DICT_SIZE)
+            .decode(input, &mut output, Some(len));
+
+        match decoded {
+            Ok(consumed) => {
+                let total = len as u32;
+                let counters = [
+                    total,
+                    consumed as u32,
+                    total / PARALLEL_UNITS + PIPE_STAGES,
+                    0, 0, 0, 0, 0, 0,
+                    100,
+                ];
+                JobResult { output, counters, error: None }
+            },
+            Err(_) => Self::failed(HardwareErrorCode::CrcMismatch),
+        }
+    }
+
+    fn word(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.memory[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_word(&mut self, offset: usize, value: u32) {
+        self.memory[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_dma_control(&mut self, value: u32) {
+        if value & dma_regs::CONTROL_RESET != 0 {
+            let base = self.registers.dma as usize;
+            for register in [dma_regs::HEAD, dma_regs::TAIL, dma_regs::STATUS] {
+                self.set_word(base + register as usize, 0);
+            }
+        }
+    }
+
+    /// Model of the DMA engine: consume descriptors up to the doorbell
+    fn run_dma(&mut self) {
+        let base = self.registers.dma as usize;
+        if self.word(base + dma_regs::CONTROL as usize) & dma_regs::CONTROL_ENABLE == 0
+            || self.word(base + dma_regs::STATUS as usize) & dma_regs::STATUS_ERROR != 0
+        {
+            return;
+        }
+
+        let ring_base = u64::from(self.word(base + dma_regs::RING_BASE as usize))
+            | u64::from(self.word(base + dma_regs::RING_BASE as usize + 4)) << 32;
+        let entries = self.word(base + dma_regs::RING_SIZE as usize);
+        let head = self.word(base + dma_regs::HEAD as usize);
+        let mut tail = self.word(base + dma_regs::TAIL as usize);
+
+        while tail != head && entries > 0 {
+            let slot = tail % entries;
+            let address = ring_base + u64::from(slot) * DESCRIPTOR_SIZE as u64;
+
+            let mut bytes = [0u8; DESCRIPTOR_SIZE];
+            let result = if self.iommu.read(address, &mut bytes) {
+                let mut descriptor = Descriptor::from_bytes(&bytes);
+                let result = self.dma_segment(&descriptor);
+                match result {
+                    Ok(()) => {
+                        descriptor.status = DESC_DONE;
+                        descriptor.transferred = descriptor.len;
+                    },
+                    Err(fault) => descriptor.status = DESC_ERROR | u32::from(fault.code()) << 8,
+                }
+                self.iommu.write(address, &descriptor.to_bytes());
+                result
+            } else {
+                Err(DmaFault::Translation)
+            };
+
+            if let Err(fault) = result {
+                let status = dma_regs::STATUS_ERROR | u32::from(fault.code()) << 8 | slot << 16;
+                self.set_word(base + dma_regs::STATUS as usize, status);
+                break;
+            }
+            tail = tail.wrapping_add(1);
+        }
+
+        self.set_word(base + dma_regs::TAIL as usize, tail);
+    }
+
+    /// Move one segment between host memory and the data windows
+    fn dma_segment(&mut self, descriptor: &Descriptor) -> Result<(), DmaFault> {
+        if descriptor.len == 0 || !descriptor.len.is_multiple_of(4) {
+            return Err(DmaFault::InvalidSegment);
+        }
+
+        let start = descriptor.device_offset as usize;
+        let end = start + descriptor.len as usize;
+        if start < self.registers.input_data as usize || end > self.memory.len() {
+            return Err(DmaFault::DeviceRange);
+        }
+
+        let copied = if descriptor.flags & DESC_TO_DEVICE != 0 {
+            self.iommu.read(descriptor.iova, &mut self.memory[start..end])
+        } else {
+            self.iommu.write(descriptor.iova, &self.memory[start..end])
+        };
+        copied.then_some(()).ok_or(DmaFault::Translation)
+    }
+
+    fn write_stream_control(&mut self, value: u32) {
+        if value & stream_regs::CONTROL_RESET != 0 {
+            let base = self.registers.stream as usize;
+            for register in [stream_regs::IN_HEAD, stream_regs::IN_TAIL, stream_regs::OUT_HEAD, stream_regs::OUT_TAIL] {
+                self.set_word(base + register as usize, 0);
+            }
+        }
+    }
+
+    /// Copy between a host ring and `buffer`, wrapping at the ring end
+    fn ring_copy(&mut self, ring: u64, size: u32, index: u32, range: std::ops::Range<usize>, to_ring: bool) -> bool {
+        let start = (index % size) as usize;
+        let first = range.len().min(size as usize - start);
+        let (head, tail) = (range.start..range.start + first, range.start + first..range.end);
+
+        if to_ring {
+            self.iommu.write(ring + start as u64, &self.memory[head])
+                && self.iommu.write(ring, &self.memory[tail])
+        } else {
+            self.iommu.read(ring + start as u64, &mut self.memory[head])
+                && self.iommu.read(ring, &mut self.memory[tail])
+        }
+    }
+
+    fn ring_base(&self, offset: u64) -> u64 {
+        let base = self.registers.stream as usize + offset as usize;
+        u64::from(self.word(base)) | u64::from(self.word(base + 4)) << 32
+    }
+
+    /// Model of the streaming handshakes: consume input, run the engine,
+    /// produce output as ring space allows
+    fn run_stream(&mut self) {
+        let Some(mut job) = self.stream.take() else {
+            return;
+        };
+        let base = self.registers.stream as usize;
+        let control = self.word(base + stream_regs::CONTROL as usize);
+        if control & stream_regs::CONTROL_ENABLE == 0 {
+            self.stream = Some(job);
+            return;
+        }
+
+        // in_valid/in_ready: accept input until the window is full
+        if self.state == ControllerState::Init {
+            let ring = self.ring_base(stream_regs::IN_BASE);
+            let size = self.word(base + stream_regs::IN_SIZE as usize);
+            let head = self.word(base + stream_regs::IN_HEAD as usize);
+            let tail = self.word(base + stream_regs::IN_TAIL as usize);
+
+            let count = (head.wrapping_sub(tail) as usize).min(INPUT_SIZE - job.received);
+            if count > 0 && size > 0 {
+                let window = self.registers.input_data as usize + job.received;
+                if !self.ring_copy(ring, size, tail, window..window + count, false) {
+                    self.fail_stream(HardwareErrorCode::MemoryAccess);
+                    return;
+                }
+                job.received += count;
+                self.set_word(base + stream_regs::IN_TAIL as usize, tail.wrapping_add(count as u32));
+            }
+
+            let end_of_input = control & stream_regs::CONTROL_END_OF_INPUT != 0 && head == tail.wrapping_add(count as u32);
+            if job.received == INPUT_SIZE || end_of_input {
+                let input = self.registers.input_data as usize;
+                self.memory[input + job.received..input + INPUT_SIZE].fill(0);
+
+                self.enter(ControllerState::Compress);
+                let result = if job.decompress { self.run_decoder() } else { self.run_encoder() };
+                job.output = result.output.clone();
+                self.pending = Some(result);
+                self.pending_polls = if self.interrupt.is_some() { 0 } else { self.config.latency_polls };
+            }
+        }
+
+        // out_valid/out_ready: push output while the ring has room
+        if self.state == ControllerState::Compress && job.sent < job.output.len() {
+            let ring = self.ring_base(stream_regs::OUT_BASE);
+            let size = self.word(base + stream_regs::OUT_SIZE as usize);
+            let head = self.word(base + stream_regs::OUT_HEAD as usize);
+            let tail = self.word(base + stream_regs::OUT_TAIL as usize);
+
+            let space = size.saturating_sub(head.wrapping_sub(tail)) as usize;
+            let count = space.min(job.output.len() - job.sent);
+            if count > 0 {
+                // Stage through the output window, which the job publishes anyway
+                let window = self.registers.output_data as usize;
+                self.memory[window..window + count].copy_from_slice(&job.output[job.sent..job.sent + count]);
+                if !self.ring_copy(ring, size, head, window..window + count, true) {
+                    self.fail_stream(HardwareErrorCode::MemoryAccess);
+                    return;
+                }
+                job.sent += count;
+                self.set_word(base + stream_regs::OUT_HEAD as usize, head.wrapping_add(count as u32));
+            }
+        }
+
+        let ready = self.state == ControllerState::Compress && job.sent == job.output.len();
+        self.stream = Some(job);
+        if ready && self.interrupt.is_some() {
+            self.schedule_completion();
+        }
+    }
+
+    /// Abort a streaming job on a host memory fault
+    fn fail_stream(&mut self, code: HardwareErrorCode) {
+        self.pending = None;
+        self.error = Some(code);
+        self.enter(ControllerState::Error);
+    }
+
+    fn queue_word(&self, register: u64) -> u32 {
+        self.word(self.registers.queue as usize + register as usize)
+    }
+
+    fn set_queue_word(&mut self, register: u64, value: u32) {
+        self.set_word(self.registers.queue as usize + register as usize, value);
+    }
+
+    fn queue_base(&self, register: u64) -> u64 {
+        u64::from(self.queue_word(register)) | u64::from(self.queue_word(register + 4)) << 32
+    }
+
+    fn write_queue_control(&mut self, value: u32) {
+        if value & queue_regs::CONTROL_RESET != 0 {
+            for register in [queue_regs::SQ_HEAD, queue_regs::SQ_TAIL, queue_regs::CQ_HEAD, queue_regs::CQ_TAIL] {
+                self.set_queue_word(register, 0);
+            }
+            self.queued.clear();
+        }
+    }
+
+    /// Model of the command queue after a doorbell: fetch commands and, with
+    /// an interrupt attached, complete them once the timer fires
+    fn run_queue(&mut self) {
+        self.fetch_commands();
+        if self.interrupt.is_some() && !self.queued.is_empty() {
+            self.schedule_completion();
+        }
+    }
+
+    /// Advance the command queue by one status poll
+    fn step_queue(&mut self) {
+        loop {
+            self.fetch_commands();
+            if !self.post_completions() || self.interrupt.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Start commands from the submission ring on free compression units
+    fn fetch_commands(&mut self) {
+        let size = self.queue_word(queue_regs::QUEUE_SIZE);
+        if !self.config.command_queue
+            || self.queue_word(queue_regs::CONTROL) & queue_regs::CONTROL_ENABLE == 0
+            || size == 0
+        {
+            return;
+        }
+
+        let ring = self.queue_base(queue_regs::SQ_BASE);
+        let head = self.queue_word(queue_regs::SQ_HEAD);
+        let mut tail = self.queue_word(queue_regs::SQ_TAIL);
+
+        while tail != head && self.queued.len() < PARALLEL_UNITS as usize {
+            // An unreadable ring stalls the queue
+            let mut bytes = [0u8; COMMAND_SIZE];
+            if !self.iommu.read(ring + u64::from(tail % size) * COMMAND_SIZE as u64, &mut bytes) {
+                break;
+            }
+            let command = Command::from_bytes(&bytes);
+
+            // Larger blocks keep their unit busy for longer
+            let polls = if self.interrupt.is_some() {
+                0
+            } else {
+                self.config.latency_polls + command.block_len / 4096
+            };
+            self.queued.push(QueuedJob {
+                tag: command.tag,
+                output_iova: command.output_iova,
+                polls,
+                result: self.run_command(&command),
+            });
+            tail = tail.wrapping_add(1);
+        }
+
+        self.set_queue_word(queue_regs::SQ_TAIL, tail);
+    }
+
+    /// Run the engine on a queued command
+    fn run_command(&self, command: &Command) -> JobResult {
+        let input_len = command.input_len as usize;
+        let block_len = command.block_len as usize;
+        if input_len == 0 || input_len > INPUT_SIZE || block_len == 0 || block_len > INPUT_SIZE {
+            return Self::failed(HardwareErrorCode::InvalidState);
+        }
+
+        let mut input = vec![0; input_len];
+        if !self.iommu.read(command.input_iova, &mut input) {
+            return Self::failed(HardwareErrorCode::MemoryAccess);
+        }
+
+        // Queued jobs are self-contained blocks
+        match command.control {
+            constants::CTRL_DECOMPRESS if self.config.decode_engine => Self::decode(&input, block_len),
+            0 if input_len == block_len => {
+                Self::encode(LzmaEncoder::new(LzmaProperties::default(), DICT_SIZE), &input, 0).0
+            },
+            _ => Self::failed(HardwareErrorCode::InvalidState),
+        }
+    }
+
+    /// Post the finished jobs the completion ring has room for
+    ///
+    /// Returns whether any job was posted.
+    fn post_completions(&mut self) -> bool {
+        let size = self.queue_word(queue_regs::QUEUE_SIZE);
+        let ring = self.queue_base(queue_regs::CQ_BASE);
+        let tail = self.queue_word(queue_regs::CQ_TAIL);
+        let mut head = self.queue_word(queue_regs::CQ_HEAD);
+
+        let mut posted = false;
+        let mut running = Vec::with_capacity(self.queued.len());
+        for mut job in std::mem::take(&mut self.queued) {
+            if job.polls > 0 || head.wrapping_sub(tail) >= size {
+                job.polls = job.polls.saturating_sub(1);
+                running.push(job);
+                continue;
+            }
+
+            let mut result = job.result;
+            if let Some(code) = self.injected_error.take() {
+                result.error = Some(code);
+            }
+            if result.error.is_none() && !self.iommu.write(job.output_iova, &result.output) {
+                result.error = Some(HardwareErrorCode::MemoryAccess);
+            }
+
+            let status = match result.error {
+                Some(code) => StatusRegister::new(ControllerState::Error, code.code()),
+                None => StatusRegister::new(ControllerState::Complete, 0),
+            };
+            let entry = CompletionEntry {
+                tag: job.tag,
+                status,
+                output_len: if result.error.is_some() { 0 } else { result.output.len() as u32 },
+            };
+            if !self.iommu.write(ring + u64::from(head % size) * COMPLETION_SIZE as u64, &entry.to_bytes()) {
+                // Lost completions surface as a timeout in the driver
+                continue;
+            }
+            head = head.wrapping_add(1);
+            posted = true;
+        }
+        self.queued = running;
+        self.set_queue_word(queue_regs::CQ_HEAD, head);
+
+        if posted {
+            if let Some(interrupt) = &self.interrupt {
+                let _ = interrupt.signal();
+            }
+        }
+        posted
+    }
+
+    /// Validate an access and return its byte range
+    fn range(&self, offset: u64, width: usize, align: usize) -> Lzma2Result<std::ops::Range<usize>> {
+        let start = usize::try_from(offset).map_err(|_| Lzma2Error::DeviceAccessError)?;
+        let end = start.checked_add(width).ok_or(Lzma2Error::DeviceAccessError)?;
+
+        if !start.is_multiple_of(align) || end > self.memory.len() {
+            return Err(Lzma2Error::DeviceAccessError);
+        }
+
+        Ok(start..end)
+    }
+
+    fn read(&mut self, offset: u64, buffer: &mut [u8], align: usize) -> Lzma2Result<()> {
+        let range = self.range(offset, buffer.len(), align)?;
+        let status = self.registers.status as usize;
+        // With an interrupt attached, jobs advance on their timers instead
+        let polled = self.interrupt.is_none();
+        if polled && range.contains(&(self.registers.queue as usize + queue_regs::CQ_HEAD as usize)) {
+            self.step_queue();
+        }
+
+        if range.contains(&status) {
+            if polled {
+                self.step();
+            }
+            let value = self.status().raw().to_le_bytes();
+            self.memory[status..status + 4].copy_from_slice(&value);
+        }
+
+        buffer.copy_from_slice(&self.memory[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], align: usize) -> Lzma2Result<()> {
+        let range = self.range(offset, data.len(), align)?;
+        let control = self.registers.control as usize;
+        let status = self.registers.status as usize;
+
+        // The status register is read-only
+        let saved = self.word(status);
+        self.memory[range.clone()].copy_from_slice(data);
+        self.set_word(status, saved);
+
+        if range.contains(&control) {
+            let mut word = [0u8; 4];
+            word.copy_from_slice(&self.memory[control..control + 4]);
+            self.write_control(u32::from_le_bytes(word));
+        }
+
+        let dma = self.registers.dma as usize;
+        let dma_control = dma + dma_regs::CONTROL as usize;
+        if range.contains(&dma_control) {
+            self.write_dma_control(self.word(dma_control));
+        }
+        if range.contains(&(dma + dma_regs::HEAD as usize)) {
+            self.run_dma();
+        }
+
+        let stream = self.registers.stream as usize;
+        let stream_control = stream + stream_regs::CONTROL as usize;
+        if range.contains(&stream_control) {
+            self.write_stream_control(self.word(stream_control));
+        }
+        if [stream_regs::CONTROL, stream_regs::IN_HEAD, stream_regs::OUT_TAIL]
+            .iter()
+            .any(|register| range.contains(&(stream + *register as usize)))
+        {
+            self.run_stream();
+        }
+
+        let queue = self.registers.queue as usize;
+        let queue_control = queue + queue_regs::CONTROL as usize;
+        if range.contains(&queue_control) {
+            self.write_queue_control(self.word(queue_control));
+        }
+        if [queue_regs::CONTROL, queue_regs::SQ_HEAD, queue_regs::CQ_TAIL]
+            .iter()
+            .any(|register| range.contains(&(queue + *register as usize)))
+        {
+            self.run_queue();
+        }
+
+        Ok(())
+    }
+}
+
+impl RegisterIo for SimulatedBackend {
+    fn len(&self) -> usize {
+        self.lock().memory.len()
+    }
+
+    fn read32(&self, offset: u64) -> Lzma2Result<u32> {
+        let mut word = [0u8; 4];
+        self.lock().read(offset, &mut word, 4)?;
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn write32(&self, offset: u64, value: u32) -> Lzma2Result<()> {
+        self.lock().write(offset, &value.to_le_bytes(), 4)
+    }
+
+    fn read64(&self, offset: u64) -> Lzma2Result<u64> {
+        let mut word = [0u8; 8];
+        self.lock().read(offset, &mut word, 8)?;
+        Ok(u64::from_le_bytes(word))
+    }
+
+    fn write64(&self, offset: u64, value: u64) -> Lzma2Result<()> {
+        self.lock().write(offset, &value.to_le_bytes(), 8)
+    }
+
+    fn read_burst(&self, offset: u64, buffer: &mut [u8]) -> Lzma2Result<()> {
+        self.lock().read(offset, buffer, 4)
+    }
+
+    fn write_burst(&self, offset: u64, data: &[u8]) -> Lzma2Result<()> {
+        let mut padded = data.to_vec();
+        padded.resize(data.len().next_multiple_of(4), 0);
+        self.lock().write(offset, &padded, 4)
+    }
+
+    fn dma_allocator(&self) -> Option<&dyn DmaAllocator> {
+        Some(self)
+    }
+}
+
+impl DmaAllocator for SimulatedBackend {
+    fn alloc_dma(&self, size: usize) -> Lzma2Result<DmaBuffer> {
+        DmaBuffer::new(self.iommu.clone(), size)
+    }
+}
+
+impl PcieDevice<SimulatedBackend> {
+    /// Create a simulated device with the default model configuration
+    pub fn simulated() -> Self {
+        Self::simulated_with(SimulatorConfig::default())
+    }
+
+    /// Create a simulated device with a custom model configuration
+    ///
+    /// The driver is configured with the extensions the model implements.
+    pub fn simulated_with(config: SimulatorConfig) -> Self {
+        let device = DeviceConfig {
+            features: config.features(),
+            ..DeviceConfig::default()
+        };
+        Self::with_backend(SimulatedBackend::new(config), device)
+            .expect("simulated BAR covers the register map")
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::device::{HardwareCompressionDevice, PerformanceMetrics};
+
+    /// Compressible text-like input; different seeds give different data
+    pub(crate) fn sample(len: usize, seed: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| b"simulated lzma2 engine input "[(i * seed + i / 29) % 29])
+            .collect()
+    }
+
+    fn sample_input() -> Vec<u8> {
+        sample(INPUT_SIZE, 3)
+    }
+
+    /// Behaviour injected around the blocks of a `Hooked` device
+    pub(crate) trait BlockHook {
+        /// Run before a block reaches the device; an error fails the block
+        fn before(&self) -> Lzma2Result<()> {
+            Ok(())
+        }
+
+        /// Alter the compressed output of a block
+        fn after(&self, _output: &mut Vec<u8>) {}
+    }
+
+    /// Simulated device running `hook` around every compressed block
+    pub(crate) struct Hooked<H> {
+        pub(crate) inner: SimulatedDevice,
+        pub(crate) hook: H,
+    }
+
+    impl<H> Hooked<H> {
+        pub(crate) fn new(hook: H) -> Self {
+            Self {
+                inner: SimulatedDevice::simulated(),
+                hook,
+            }
+        }
+    }
+
+    impl<H: BlockHook> HardwareCompressionDevice for Hooked<H> {
+        fn compress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+            self.compress_block(input)
+        }
+
+        fn decompress(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+            self.inner.decompress(input)
+        }
+
+        fn compress_block(&self, input: &[u8]) -> Lzma2Result<Vec<u8>> {
+            self.hook.before()?;
+            let mut output = self.inner.compress_block(input)?;
+            self.hook.after(&mut output);
+            Ok(output)
+        }
+
+        fn decompress_block(&self, input: &[u8], uncompressed_len: usize) -> Lzma2Result<Vec<u8>> {
+            self.inner.decompress_block(input, uncompressed_len)
+        }
+
+        fn get_performance_metrics(&self) -> Lzma2Result<PerformanceMetrics> {
+            self.inner.get_performance_metrics()
+        }
+    }
+
+    #[test]
+    fn test_controller_state_machine() {
+        let backend = SimulatedBackend::new(SimulatorConfig { latency_polls: 1, ..SimulatorConfig::default() });
+        let registers = RegisterMap::default();
+
+        backend.write_burst(registers.input_data, &sample_input()).unwrap();
+        backend.write32(registers.control, constants::CTRL_START).unwrap();
+        assert_eq!(backend.state(), ControllerState::Compress);
+
+        // Busy while the engine runs, then complete
+        let status = StatusRegister::from_raw(backend.read32(registers.status).unwrap());
+        assert!(status.is_busy());
+        assert_eq!(status.state(), Some(ControllerState::Compress));
+
+        let status = StatusRegister::from_raw(backend.read32(registers.status).unwrap());
+        assert!(status.is_done());
+        assert_eq!(status.state(), Some(ControllerState::Complete));
+
+        // Dropping start returns to IDLE
+        backend.write32(registers.control, 0).unwrap();
+        assert_eq!(backend.transitions(), vec![
+            ControllerState::Idle,
+            ControllerState::Init,
+            ControllerState::Compress,
+            ControllerState::Verify,
+            ControllerState::Complete,
+            ControllerState::Idle,
+        ]);
+    }
+
+    #[test]
+    fn test_injected_error_status() {
+        let backend = SimulatedBackend::new(SimulatorConfig { latency_polls: 0, ..SimulatorConfig::default() });
+        let registers = RegisterMap::default();
+
+        backend.inject_error(HardwareErrorCode::Stall);
+        backend.write32(registers.control, constants::CTRL_START).unwrap();
+
+        let status = StatusRegister::from_raw(backend.read32(registers.status).unwrap());
+        assert_eq!(status.state(), Some(ControllerState::Error));
+        assert_eq!(status.error(), Some(HardwareErrorCode::Stall));
+
+        // Reset clears the error
+        backend.write32(registers.control, constants::CTRL_RESET).unwrap();
+        assert_eq!(backend.read32(registers.status).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_status_register_read_only() {
+        let backend = SimulatedBackend::default();
+        let registers = RegisterMap::default();
+
+        backend.write32(registers.status, 0xFFFF_FFFF).unwrap();
+        assert_eq!(backend.read32(registers.status).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dma_transfers() -> Lzma2Result<()> {
+        use crate::transfer::TransferStrategy;
+
+        let input = sample_input();
+        let expected = SimulatedDevice::simulated().compress(&input)?;
+
+        let decoder = SimulatorConfig { decode_engine: true, ..SimulatorConfig::default() };
+        let device = SimulatedDevice::simulated_with(decoder).with_transfer_strategy(TransferStrategy::Dma)?;
+        assert_eq!(device.transfer_strategy(), Some(TransferStrategy::Dma));
+        let compressed = device.compress(&input)?;
+        assert_eq!(compressed, expected);
+        assert_eq!(device.decompress(&compressed)?, input);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_transfers() -> Lzma2Result<()> {
+        use crate::transfer::{TransferConfig, TransferStrategy};
+
+        let input = sample_input();
+        let expected = SimulatedDevice::simulated().compress(&input)?;
+
+        // Rings much smaller than the job force input and output to overlap
+        let decoder = SimulatorConfig { decode_engine: true, ..SimulatorConfig::default() };
+        let device = SimulatedDevice::simulated_with(decoder).with_transfer_config(TransferConfig {
+            strategy: TransferStrategy::Streaming,
+            buffer_size: 1000,
+            ..TransferConfig::default()
+        })?;
+        assert_eq!(device.transfer_strategy(), Some(TransferStrategy::Streaming));
+
+        let compressed = device.compress(&input)?;
+        assert_eq!(compressed, expected);
+        assert_eq!(device.decompress(&compressed)?, input);
+
+        let backend = device.io();
+        let stream = RegisterMap::default().stream;
+        assert_eq!(backend.read32(stream + stream_regs::OUT_HEAD)?, INPUT_SIZE as u32);
+        assert_eq!(backend.read32(stream + stream_regs::IN_TAIL)?, compressed.len() as u32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dma_faults() -> Lzma2Result<()> {
+        use crate::device::dma::{DmaDirection, DmaEngine, SgEntry};
+
+        let backend = SimulatedBackend::default();
+        let registers = RegisterMap::default();
+        let mut engine = DmaEngine::new(&backend, &backend, registers.dma, 0x1000)?
+            .with_segment_size(0x400);
+
+        // Segments outside the data windows are rejected
+        let err = engine.upload(&backend, 0x0, &[0xAA; 0x800]).unwrap_err();
+        assert!(err.to_string().contains("device address out of range"), "{}", err);
+
+        // Unmapped IOVAs fault in the IOMMU
+        let unmapped = SgEntry { iova: 0x10, len: 0x100, device_offset: registers.input_data as u32 };
+        let err = engine.submit(&backend, DmaDirection::ToDevice, &[unmapped]).unwrap_err();
+        assert!(err.to_string().contains("translation fault"), "{}", err);
+
+        // The engine is reset after a fault and split transfers still work
+        let data: Vec<u8> = (0..0x1000).map(|i| i as u8).collect();
+        engine.upload(&backend, registers.input_data, &data)?;
+        let mut readback = vec![0u8; data.len()];
+        engine.download(&backend, registers.input_data, &mut readback)?;
+        assert_eq!(readback, data);
+        assert_eq!(backend.read32(registers.dma + dma_regs::TAIL)?, 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chained_blocks() -> Lzma2Result<()> {
+        use crate::device::ChainedBlock;
+
+        let input = sample_input();
+        let open = ChainedBlock { continues: false, closes: false, end_marker: false };
+
+        // Like the RTL, the default model has no chaining bits
+        let device = SimulatedDevice::simulated();
+        assert!(matches!(device.compress_chained(&input, open), Err(Lzma2Error::InputValidationError(_))));
+
+        let device = SimulatedDevice::simulated_with(SimulatorConfig {
+            chained_streams: true,
+            ..SimulatorConfig::default()
+        });
+
+        // Continuing requires a stream left open by the previous job
+        let orphan = ChainedBlock { continues: true, closes: true, end_marker: false };
+        assert!(device.compress_chained(&input, orphan).is_err());
+
+        // Two chained blocks decode as one stream with the second half
+        // referencing the first
+        let first = device.compress_chained(&input, open)?;
+        let second = device.compress_chained(&input, ChainedBlock { continues: true, closes: true, end_marker: true })?;
+        let stream = [first, second].concat();
+        assert!(stream.len() < device.compress(&input)?.len() * 2);
+
+        let mut decoded = Vec::new();
+        LzmaDecoder::new(LzmaProperties::default(), DICT_SIZE).decode(&stream, &mut decoded, None)?;
+        assert_eq!(decoded, [input.clone(), input].concat());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulated_performance_counters() -> Lzma2Result<()> {
+        let device = SimulatedDevice::simulated();
+        let compressed = device.compress(&sample_input())?;
+        assert_eq!(compressed[0], 0, "range coder streams start with a zero byte");
+
+        let metrics = device.get_performance_metrics()?;
+        assert_eq!(compressed.len() as u64, metrics.compressed_bytes);
+        assert_eq!(metrics.total_bytes_processed, INPUT_SIZE as u64);
+        assert!(metrics.compressed_bytes > 0);
+        assert!(metrics.compressed_bytes < INPUT_SIZE as u64 / 10);
+        assert!(metrics.match_hits > 0);
+        assert!(metrics.cycles > 0);
+
+        Ok(())
+    }
+}
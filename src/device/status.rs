@@ -0,0 +1,287 @@
+//! Status register decoding for LZMA2 FPGA Compression Driver
+//!
+//! Follows the packing of `lzma2_system_controller` in `lzma2_top.sv`:
+//!
+//! | Bits    | Field         |
+//! |---------|---------------|
+//! | [31:16] | reserved      |
+//! | [15:12] | `error_flags` |
+//! | [11:8]  | `state`       |
+//! | [7:0]   | reserved      |
+//!
+//! The controller does not drive the `warning_flags` and `status` fields
+//! of `lzma2_pkg::status_reg_t`, so completion is decoded from the state.
+//!
+//! Failures carry a `RegisterSnapshot` of the control, status and
+//! performance counter registers read when the error was detected.
+
+use std::fmt;
+
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+/// States of `lzma2_system_controller`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive)]
+pub enum ControllerState {
+    /// Waiting for start
+    Idle = 0,
+
+    /// Start received, waiting for input
+    Init = 1,
+
+    /// Engine running
+    Compress = 2,
+
+    /// Output verification
+    Verify = 3,
+
+    /// Job finished successfully
+    Complete = 4,
+
+    /// Job failed
+    Error = 5,
+}
+
+impl ControllerState {
+    /// Decode the 4-bit state field
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        Self::from_u8(bits)
+    }
+}
+
+impl fmt::Display for ControllerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Idle => "IDLE",
+            Self::Init => "INIT",
+            Self::Compress => "COMPRESS",
+            Self::Verify => "VERIFY",
+            Self::Complete => "COMPLETE",
+            Self::Error => "ERROR",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Error codes reported in `error_flags` (`lzma2_pkg::ERR_*`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive)]
+pub enum HardwareErrorCode {
+    /// CRC verification failed (`ERR_CRC_MISMATCH`)
+    CrcMismatch = 0x1,
+
+    /// Memory access error (`ERR_MEMORY_ACCESS`)
+    MemoryAccess = 0x2,
+
+    /// Buffer overflow (`ERR_OVERFLOW`)
+    Overflow = 0x3,
+
+    /// Operation timeout (`ERR_TIMEOUT`)
+    Timeout = 0x4,
+
+    /// Invalid state transition (`ERR_INVALID_STATE`)
+    InvalidState = 0x5,
+
+    /// Pipeline stall exceeded limit (`ERR_STALL`)
+    Stall = 0x6,
+}
+
+impl HardwareErrorCode {
+    /// Decode a 4-bit error code; `ERR_NONE` and unknown codes yield `None`
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::from_u8(code)
+    }
+
+    /// Raw 4-bit error code
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl fmt::Display for HardwareErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::CrcMismatch => "CRC verification failed",
+            Self::MemoryAccess => "memory access error",
+            Self::Overflow => "buffer overflow",
+            Self::Timeout => "operation timeout",
+            Self::InvalidState => "invalid state transition",
+            Self::Stall => "pipeline stall exceeded limit",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Decoded view of the status register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusRegister(u32);
+
+impl StatusRegister {
+    /// Wrap a raw register value
+    pub const fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    /// Pack the fields into a register value
+    pub fn new(state: ControllerState, error_flags: u8) -> Self {
+        Self(u32::from(error_flags & 0xF) << 12 | (state as u32) << 8)
+    }
+
+    /// Raw register value
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+
+    /// Raw `state` field
+    pub fn state_bits(self) -> u8 {
+        ((self.0 >> 8) & 0xF) as u8
+    }
+
+    /// Controller state, or `None` for an undefined encoding
+    pub fn state(self) -> Option<ControllerState> {
+        ControllerState::from_bits(self.state_bits())
+    }
+
+    /// `error_flags` field
+    pub fn error_flags(self) -> u8 {
+        ((self.0 >> 12) & 0xF) as u8
+    }
+
+    /// Decoded error code, if a known one is reported
+    pub fn error(self) -> Option<HardwareErrorCode> {
+        HardwareErrorCode::from_code(self.error_flags())
+    }
+
+    /// Whether the job has completed
+    pub fn is_done(self) -> bool {
+        self.state() == Some(ControllerState::Complete)
+    }
+
+    /// Whether the controller is running a job
+    pub fn is_busy(self) -> bool {
+        matches!(
+            self.state(),
+            Some(ControllerState::Init | ControllerState::Compress | ControllerState::Verify)
+        )
+    }
+
+    /// Whether the hardware reports a failure
+    ///
+    /// Undefined state encodings count as failures, as do the reserved
+    /// bits reading back set, e.g. from an unresponsive BAR.
+    pub fn has_error(self) -> bool {
+        self.error_flags() != 0
+            || self.0 & Self::RESERVED != 0
+            || !matches!(self.state(), Some(state) if state != ControllerState::Error)
+    }
+
+    /// Bits the controller always drives low
+    const RESERVED: u32 = 0xFFFF_00FF;
+}
+
+impl From<u32> for StatusRegister {
+    fn from(raw: u32) -> Self {
+        Self::from_raw(raw)
+    }
+}
+
+impl fmt::Display for StatusRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state() {
+            Some(state) => write!(f, "state={}", state)?,
+            None => write!(f, "state=0x{:x}", self.state_bits())?,
+        }
+
+        match self.error() {
+            Some(error) => write!(f, " error={} ({})", error.code(), error),
+            None => write!(f, " error={}", self.error_flags()),
+        }
+    }
+}
+
+/// Number of 32-bit performance counters (`performance_counters_t`)
+pub const PERF_COUNTER_COUNT: usize = 10;
+
+/// Register state captured when a hardware failure is detected
+///
+/// Registers that could not be read are `None`, so the failure is still
+/// reported with whatever was captured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    /// Status register
+    pub status: StatusRegister,
+
+    /// Control register
+    pub control: Option<u32>,
+
+    /// Performance counters in `performance_counters_t` order
+    pub perf_counters: [Option<u32>; PERF_COUNTER_COUNT],
+}
+
+impl fmt::Display for RegisterSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} control=", self.status)?;
+        match self.control {
+            Some(control) => write!(f, "0x{:08x}", control)?,
+            None => f.write_str("?")?,
+        }
+
+        f.write_str(" counters=[")?;
+        for (i, counter) in self.perf_counters.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match counter {
+                Some(counter) => write!(f, "{}", counter)?,
+                None => f.write_str("?")?,
+            }
+        }
+        f.write_str("]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_layout() {
+        let status = StatusRegister::from_raw(0x3200);
+
+        assert_eq!(status.state(), Some(ControllerState::Compress));
+        assert_eq!(status.error(), Some(HardwareErrorCode::Overflow));
+        assert!(status.is_busy());
+        assert!(!status.is_done());
+        assert!(status.has_error());
+
+        let status = StatusRegister::from_raw(0x0400);
+        assert!(status.is_done());
+        assert!(!status.is_busy());
+        assert!(!status.has_error());
+    }
+
+    #[test]
+    fn test_pack_roundtrip() {
+        let status = StatusRegister::new(ControllerState::Error, HardwareErrorCode::Stall.code());
+
+        assert_eq!(status.raw(), 0x6500);
+        assert_eq!(status.error(), Some(HardwareErrorCode::Stall));
+        assert_eq!(status.to_string(), "state=ERROR error=6 (pipeline stall exceeded limit)");
+    }
+
+    #[test]
+    fn test_unknown_encodings() {
+        let status = StatusRegister::from_raw(0x0f00);
+        assert_eq!(status.state(), None);
+        assert!(status.has_error());
+
+        let status = StatusRegister::from_raw(0xf000);
+        assert_eq!(status.error(), None);
+        assert!(status.has_error());
+        assert_eq!(HardwareErrorCode::from_code(0), None);
+
+        // An all-ones read is not mistaken for a completed job
+        let status = StatusRegister::from_raw(0xFFFF_FFFF);
+        assert!(!status.is_done());
+        assert!(status.has_error());
+    }
+}
@@ -0,0 +1,277 @@
+//! Streaming transfers for LZMA2 FPGA Compression Driver
+//!
+//! Two byte rings in host memory connect the driver to the engine's
+//! `in_valid/in_ready` and `out_valid/out_ready` handshakes. The driver
+//! produces into the input ring and announces it through the `IN_HEAD`
+//! doorbell; the engine consumes it and advances `IN_TAIL`. Output flows the
+//! other way: the engine advances `OUT_HEAD` and the driver returns space
+//! by writing `OUT_TAIL`.
+//!
+//! Indices are free-running byte counts; ring sizes are powers of two so
+//! they wrap cleanly.
+
+use std::time::Instant;
+
+use super::backend::RegisterIo;
+use super::dma::{DmaAllocator, DmaBuffer};
+use super::pcie::constants;
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::transfer::{DataTransfer, JobControl, TransferContext, TransferStrategy};
+
+/// Register offsets relative to the streaming register block
+pub mod registers {
+    /// Input ring IOVA (64-bit)
+    pub const IN_BASE: u64 = 0x00;
+
+    /// Input ring size in bytes
+    pub const IN_SIZE: u64 = 0x08;
+
+    /// Input producer index doorbell, written by the driver
+    pub const IN_HEAD: u64 = 0x0C;
+
+    /// Input consumer index, advanced by the engine
+    pub const IN_TAIL: u64 = 0x10;
+
+    /// Output ring IOVA (64-bit)
+    pub const OUT_BASE: u64 = 0x18;
+
+    /// Output ring size in bytes
+    pub const OUT_SIZE: u64 = 0x20;
+
+    /// Output producer index, advanced by the engine
+    pub const OUT_HEAD: u64 = 0x24;
+
+    /// Output consumer index doorbell, written by the driver
+    pub const OUT_TAIL: u64 = 0x28;
+
+    /// Stream control
+    pub const CONTROL: u64 = 0x2C;
+
+    /// Control: rings are valid
+    pub const CONTROL_ENABLE: u32 = 1 << 0;
+
+    /// Control: no more input follows the current `IN_HEAD`
+    pub const CONTROL_END_OF_INPUT: u32 = 1 << 1;
+
+    /// Control: clear all indices
+    pub const CONTROL_RESET: u32 = 1 << 31;
+}
+
+/// Driver side of the streaming rings
+#[derive(Debug)]
+pub struct StreamEngine {
+    /// Offset of the streaming register block in the BAR
+    base: u64,
+
+    /// Input ring
+    input: DmaBuffer,
+
+    /// Output ring
+    output: DmaBuffer,
+
+    /// Ring size in bytes
+    size: u32,
+
+    /// Free-running input producer index
+    in_head: u32,
+
+    /// Free-running output consumer index
+    out_tail: u32,
+}
+
+impl StreamEngine {
+    /// Allocate two rings of `buffer_size` bytes, rounded up to a power of
+    /// two, and program the streaming block at register offset `base`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if allocation or register access fails
+    pub fn new(
+        io: &dyn RegisterIo,
+        allocator: &dyn DmaAllocator,
+        base: u64,
+        buffer_size: usize,
+    ) -> Lzma2Result<Self> {
+        let size = u32::try_from(buffer_size.max(1).next_power_of_two()).map_err(|_| {
+            Lzma2Error::TransferError(format!("Stream buffer of {} bytes is too large", buffer_size))
+        })?;
+
+        let mut engine = Self {
+            base,
+            input: allocator.alloc_dma(size as usize)?,
+            output: allocator.alloc_dma(size as usize)?,
+            size,
+            in_head: 0,
+            out_tail: 0,
+        };
+        engine.restart(io)?;
+        Ok(engine)
+    }
+
+    /// Ring size in bytes
+    pub fn buffer_size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Reset the indices and program the rings for a new job
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if register access fails
+    pub fn restart(&mut self, io: &dyn RegisterIo) -> Lzma2Result<()> {
+        io.write32(self.base + registers::CONTROL, registers::CONTROL_RESET)?;
+        io.write64(self.base + registers::IN_BASE, self.input.iova())?;
+        io.write32(self.base + registers::IN_SIZE, self.size)?;
+        io.write64(self.base + registers::OUT_BASE, self.output.iova())?;
+        io.write32(self.base + registers::OUT_SIZE, self.size)?;
+        io.write32(self.base + registers::CONTROL, registers::CONTROL_ENABLE)?;
+        self.in_head = 0;
+        self.out_tail = 0;
+        Ok(())
+    }
+
+    /// Queue as much of `data` as the input ring has room for
+    ///
+    /// Returns the number of bytes accepted.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if register access fails
+    pub fn push(&mut self, io: &dyn RegisterIo, data: &[u8]) -> Lzma2Result<usize> {
+        let in_tail = io.read32(self.base + registers::IN_TAIL)?;
+        let used = self.in_head.wrapping_sub(in_tail);
+        if used > self.size {
+            return Err(Lzma2Error::TransferError(
+                format!("Stream input tail 0x{:x} is ahead of head 0x{:x}", in_tail, self.in_head)
+            ));
+        }
+
+        let count = data.len().min((self.size - used) as usize);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let start = (self.in_head % self.size) as usize;
+        let first = count.min(self.size as usize - start);
+        let ring = self.input.as_mut_slice();
+        ring[start..start + first].copy_from_slice(&data[..first]);
+        ring[..count - first].copy_from_slice(&data[first..count]);
+
+        // Register writes are ordered after the ring stores
+        self.in_head = self.in_head.wrapping_add(count as u32);
+        io.write32(self.base + registers::IN_HEAD, self.in_head)?;
+        Ok(count)
+    }
+
+    /// Signal that no more input follows
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if register access fails
+    pub fn finish_input(&mut self, io: &dyn RegisterIo) -> Lzma2Result<()> {
+        io.write32(
+            self.base + registers::CONTROL,
+            registers::CONTROL_ENABLE | registers::CONTROL_END_OF_INPUT,
+        )
+    }
+
+    /// Append the output produced so far to `output` and return the space
+    /// to the engine
+    ///
+    /// Returns the number of bytes drained.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if register access fails
+    pub fn drain(&mut self, io: &dyn RegisterIo, output: &mut Vec<u8>) -> Lzma2Result<usize> {
+        let out_head = io.read32(self.base + registers::OUT_HEAD)?;
+        let count = out_head.wrapping_sub(self.out_tail);
+        if count > self.size {
+            return Err(Lzma2Error::TransferError(
+                format!("Stream output head 0x{:x} overran tail 0x{:x}", out_head, self.out_tail)
+            ));
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let count = count as usize;
+        let start = (self.out_tail % self.size) as usize;
+        let first = count.min(self.size as usize - start);
+        let ring = self.output.as_slice();
+        output.extend_from_slice(&ring[start..start + first]);
+        output.extend_from_slice(&ring[..count - first]);
+
+        self.out_tail = out_head;
+        io.write32(self.base + registers::OUT_TAIL, self.out_tail)?;
+        Ok(count)
+    }
+}
+
+impl DataTransfer for StreamEngine {
+    fn strategy(&self) -> Option<TransferStrategy> {
+        Some(TransferStrategy::Streaming)
+    }
+
+    /// Queue a whole input that fits the ring, outside of `run_job`
+    fn transfer_input(&mut self, ctx: &TransferContext<'_>, data: &[u8]) -> Lzma2Result<()> {
+        self.restart(ctx.io)?;
+        if self.push(ctx.io, data)? < data.len() {
+            return Err(Lzma2Error::TransferError(format!(
+                "Input of {} bytes exceeds the {}-byte stream ring", data.len(), self.size
+            )));
+        }
+        self.finish_input(ctx.io)
+    }
+
+    /// Drain up to `len` bytes of the output produced so far
+    fn read_output(&mut self, ctx: &TransferContext<'_>, len: usize) -> Lzma2Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(len);
+        self.drain(ctx.io, &mut output)?;
+        output.truncate(len);
+        Ok(output)
+    }
+
+    /// Feed input while draining output, so neither has to fit the rings
+    /// at once
+    ///
+    /// Returns exactly the bytes the engine produced, which must match the
+    /// size the device reports.
+    fn run_job(&mut self, ctx: &TransferContext<'_>, input: &[u8], job: &dyn JobControl) -> Lzma2Result<Vec<u8>> {
+        self.restart(ctx.io)?;
+        job.start(constants::CTRL_STREAM)?;
+
+        let mut output = Vec::new();
+        let mut sent = 0;
+        let mut input_done = false;
+
+        loop {
+            let mut progress = 0;
+
+            if sent < input.len() {
+                let accepted = self.push(ctx.io, &input[sent..])?;
+                sent += accepted;
+                progress += accepted;
+            }
+            if sent == input.len() && !input_done {
+                self.finish_input(ctx.io)?;
+                input_done = true;
+            }
+
+            progress += self.drain(ctx.io, &mut output)?;
+
+            if job.poll()? {
+                self.drain(ctx.io, &mut output)?;
+                let expected = job.output_len()?;
+                if output.len() != expected {
+                    return Err(Lzma2Error::TransferError(format!(
+                        "Stream delivered {} bytes, device reported {}", output.len(), expected
+                    )));
+                }
+                return Ok(output);
+            }
+
+            if progress == 0 {
+                if Instant::now() >= job.deadline() {
+                    return Err(Lzma2Error::TimeoutError);
+                }
+                std::thread::sleep(constants::POLL_INTERVAL);
+            }
+        }
+    }
+}
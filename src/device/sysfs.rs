@@ -0,0 +1,334 @@
+//! sysfs-based PCIe enumeration for LZMA2 FPGA Compression Driver
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::DeviceConfig;
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Default location of the PCI device directory in sysfs
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys/bus/pci/devices";
+
+/// Number of standard BARs on a PCI function
+pub const PCI_BAR_COUNT: usize = 6;
+
+/// PCI bus/device/function address (e.g. `0000:01:00.0`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PciAddress {
+    /// PCI segment (domain)
+    pub domain: u16,
+
+    /// Bus number
+    pub bus: u8,
+
+    /// Device number (0-31)
+    pub device: u8,
+
+    /// Function number (0-7)
+    pub function: u8,
+}
+
+impl FromStr for PciAddress {
+    type Err = Lzma2Error;
+
+    fn from_str(s: &str) -> Lzma2Result<Self> {
+        let invalid = || Lzma2Error::InputValidationError(
+            format!("Invalid PCI address: {}", s)
+        );
+
+        let (domain, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (bus, rest) = rest.split_once(':').ok_or_else(invalid)?;
+        let (device, function) = rest.split_once('.').ok_or_else(invalid)?;
+
+        let address = Self {
+            domain: u16::from_str_radix(domain, 16).map_err(|_| invalid())?,
+            bus: u8::from_str_radix(bus, 16).map_err(|_| invalid())?,
+            device: u8::from_str_radix(device, 16).map_err(|_| invalid())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| invalid())?,
+        };
+
+        if address.device > 0x1f || address.function > 0x7 {
+            return Err(invalid());
+        }
+
+        Ok(address)
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{:x}",
+            self.domain, self.bus, self.device, self.function)
+    }
+}
+
+/// A PCI function discovered in sysfs
+#[derive(Debug, Clone)]
+pub struct PciFunction {
+    /// Bus/device/function address
+    pub address: PciAddress,
+
+    /// Vendor ID
+    pub vendor_id: u16,
+
+    /// Device ID
+    pub device_id: u16,
+
+    /// NUMA node the function is attached to, if known
+    pub numa_node: Option<u32>,
+
+    /// Size in bytes of each BAR (0 for unimplemented BARs)
+    pub bar_sizes: [u64; PCI_BAR_COUNT],
+
+    /// sysfs directory of the function
+    pub sysfs_path: PathBuf,
+}
+
+impl PciFunction {
+    /// Size of the given BAR, or `None` if it is not implemented
+    pub fn bar_size(&self, index: usize) -> Option<u64> {
+        self.bar_sizes.get(index).copied().filter(|&size| size > 0)
+    }
+
+    /// sysfs `resourceN` file backing the given BAR
+    pub fn resource_path(&self, index: usize) -> PathBuf {
+        self.sysfs_path.join(format!("resource{}", index))
+    }
+}
+
+/// Enumerates PCI functions below a sysfs root
+#[derive(Debug, Clone)]
+pub struct SysfsScanner {
+    root: PathBuf,
+}
+
+impl Default for SysfsScanner {
+    fn default() -> Self {
+        Self::new(DEFAULT_SYSFS_ROOT)
+    }
+}
+
+impl SysfsScanner {
+    /// Create a scanner rooted at the given `devices` directory
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Directory being scanned
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Find all functions matching the vendor and device ID of `config`
+    ///
+    /// Results are sorted by PCI address. Entries that cannot be parsed
+    /// are skipped.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the sysfs root cannot be read
+    pub fn scan(&self, config: &DeviceConfig) -> Lzma2Result<Vec<PciFunction>> {
+        let entries = fs::read_dir(&self.root).map_err(|e| Lzma2Error::DeviceInitError(
+            format!("Failed to read {}: {}", self.root.display(), e)
+        ))?;
+
+        let mut functions = Vec::new();
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            if entry.file_name().to_str().and_then(|name| name.parse::<PciAddress>().ok()).is_none() {
+                continue;
+            }
+
+            match Self::read_function(&path) {
+                Ok(function) => {
+                    if function.vendor_id == config.vendor_id
+                        && function.device_id == config.device_id
+                    {
+                        functions.push(function);
+                    }
+                },
+                Err(e) => {
+                    tracing::debug!("Skipping {}: {}", path.display(), e);
+                },
+            }
+        }
+
+        functions.sort_by_key(|function| function.address);
+
+        Ok(functions)
+    }
+
+    /// Read the attributes of a single function directory
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if a required attribute is missing or malformed
+    pub fn read_function(path: &Path) -> Lzma2Result<PciFunction> {
+        let address = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Lzma2Error::DeviceInitError(
+                format!("Invalid sysfs path: {}", path.display())
+            ))?
+            .parse()?;
+
+        let vendor_id = parse_hex_u16(&read_attribute(path, "vendor")?)?;
+        let device_id = parse_hex_u16(&read_attribute(path, "device")?)?;
+
+        // numa_node is absent on non-NUMA kernels and -1 when unknown
+        let numa_node = read_attribute(path, "numa_node").ok()
+            .and_then(|node| node.parse::<i64>().ok())
+            .and_then(|node| u32::try_from(node).ok());
+
+        let bar_sizes = match read_attribute(path, "resource") {
+            Ok(resource) => parse_bar_sizes(&resource)?,
+            Err(_) => [0; PCI_BAR_COUNT],
+        };
+
+        Ok(PciFunction {
+            address,
+            vendor_id,
+            device_id,
+            numa_node,
+            bar_sizes,
+            sysfs_path: path.to_path_buf(),
+        })
+    }
+}
+
+/// Read a sysfs attribute file with surrounding whitespace trimmed
+fn read_attribute(dir: &Path, name: &str) -> Lzma2Result<String> {
+    let path = dir.join(name);
+    fs::read_to_string(&path)
+        .map(|value| value.trim().to_string())
+        .map_err(|e| Lzma2Error::DeviceInitError(
+            format!("Failed to read {}: {}", path.display(), e)
+        ))
+}
+
+/// Parse a `0x`-prefixed hexadecimal value
+fn parse_hex_u64(value: &str) -> Lzma2Result<u64> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(digits, 16).map_err(|_| Lzma2Error::DeviceInitError(
+        format!("Invalid hexadecimal value: {}", value)
+    ))
+}
+
+/// Parse a 16-bit sysfs ID attribute
+fn parse_hex_u16(value: &str) -> Lzma2Result<u16> {
+    u16::try_from(parse_hex_u64(value)?).map_err(|_| Lzma2Error::DeviceInitError(
+        format!("ID out of range: {}", value)
+    ))
+}
+
+/// Derive BAR sizes from the `resource` attribute
+///
+/// Each line holds the start address, end address and flags of one
+/// resource; the first six lines are the standard BARs.
+fn parse_bar_sizes(resource: &str) -> Lzma2Result<[u64; PCI_BAR_COUNT]> {
+    let mut sizes = [0; PCI_BAR_COUNT];
+
+    for (size, line) in sizes.iter_mut().zip(resource.lines()) {
+        let mut fields = line.split_whitespace();
+        let (start, end) = match (fields.next(), fields.next()) {
+            (Some(start), Some(end)) => (parse_hex_u64(start)?, parse_hex_u64(end)?),
+            _ => return Err(Lzma2Error::DeviceInitError(
+                format!("Malformed resource line: {}", line)
+            )),
+        };
+
+        if end > start {
+            *size = end - start + 1;
+        }
+    }
+
+    Ok(sizes)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Populate a fake sysfs function directory
+    pub(crate) fn create_function(
+        root: &Path,
+        address: &str,
+        vendor_id: u16,
+        device_id: u16,
+        numa_node: i32,
+        bar0_size: u64,
+    ) -> PathBuf {
+        let dir = root.join(address);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("vendor"), format!("0x{:04x}\n", vendor_id)).unwrap();
+        fs::write(dir.join("device"), format!("0x{:04x}\n", device_id)).unwrap();
+        fs::write(dir.join("numa_node"), format!("{}\n", numa_node)).unwrap();
+
+        let mut resource = String::new();
+        let bar0_start = 0xfe00_0000u64;
+        resource.push_str(&format!("0x{:016x} 0x{:016x} 0x{:016x}\n",
+            bar0_start, bar0_start + bar0_size - 1, 0x40200));
+        for _ in 1..13 {
+            resource.push_str(&format!("0x{:016x} 0x{:016x} 0x{:016x}\n", 0, 0, 0));
+        }
+        fs::write(dir.join("resource"), resource).unwrap();
+
+        // A plain file stands in for the mappable BAR
+        let bar0 = fs::File::create(dir.join("resource0")).unwrap();
+        bar0.set_len(bar0_size).unwrap();
+
+        dir
+    }
+
+    fn config() -> DeviceConfig {
+        DeviceConfig {
+            vendor_id: 0x1234,
+            device_id: 0x5678,
+            bar_index: 0,
+            ..DeviceConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_pci_address_roundtrip() {
+        let address: PciAddress = "0000:3b:00.1".parse().unwrap();
+        assert_eq!(address.bus, 0x3b);
+        assert_eq!(address.function, 1);
+        assert_eq!(address.to_string(), "0000:3b:00.1");
+
+        assert!("0000:3b:00".parse::<PciAddress>().is_err());
+        assert!("0000:3b:20.0".parse::<PciAddress>().is_err());
+        assert!("0000:3b:00.8".parse::<PciAddress>().is_err());
+    }
+
+    #[test]
+    fn test_scan_matches_ids() {
+        let root = tempfile::tempdir().unwrap();
+        create_function(root.path(), "0000:02:00.0", 0x1234, 0x5678, 1, 0x20000);
+        create_function(root.path(), "0000:01:00.0", 0x1234, 0x5678, -1, 0x10000);
+        create_function(root.path(), "0000:00:1f.0", 0x8086, 0x1234, 0, 0x1000);
+        fs::create_dir(root.path().join("not-a-device")).unwrap();
+
+        let functions = SysfsScanner::new(root.path()).scan(&config()).unwrap();
+
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].address.to_string(), "0000:01:00.0");
+        assert_eq!(functions[0].numa_node, None);
+        assert_eq!(functions[0].bar_size(0), Some(0x10000));
+        assert_eq!(functions[0].bar_size(1), None);
+        assert_eq!(functions[1].numa_node, Some(1));
+        assert_eq!(functions[1].bar_sizes[0], 0x20000);
+        assert_eq!(
+            functions[1].resource_path(0),
+            root.path().join("0000:02:00.0").join("resource0")
+        );
+    }
+
+    #[test]
+    fn test_scan_missing_root() {
+        let root = tempfile::tempdir().unwrap();
+        let scanner = SysfsScanner::new(root.path().join("missing"));
+        assert!(scanner.scan(&config()).is_err());
+    }
+}
@@ -1,8 +1,9 @@
 //! Error handling for LZMA2 FPGA Driver
 
-use std::fmt;
 use thiserror::Error;
 
+use crate::device::{HardwareErrorCode, RegisterSnapshot};
+
 /// Comprehensive error enum for LZMA2 FPGA Driver
 #[derive(Debug, Error)]
 pub enum Lzma2Error {
@@ -26,6 +27,10 @@ pub enum Lzma2Error {
     #[error("Operation timeout")]
     TimeoutError,
     
+    /// Engine held by the calling thread in a way that rules out the job
+    #[error("Engine busy: {0}")]
+    EngineBusy(String),
+    
     /// CRC verification errors
     #[error("CRC verification failed")]
     CrcError,
@@ -33,6 +38,78 @@ pub enum Lzma2Error {
     /// Input validation errors
     #[error("Invalid input: {0}")]
     InputValidationError(String),
+    
+    /// Job output larger than the device output window
+    #[error("Output overflow: {size} bytes exceed the {capacity}-byte output window")]
+    OutputOverflow {
+        /// Output size reported by the device
+        size: u64,
+        
+        /// Size of the output window
+        capacity: u64,
+    },
+    
+    /// Hardware output that does not decode to its input
+    #[error("Verification failed: block at offset {offset} does not decode to its input")]
+    VerificationFailed {
+        /// Input offset of the block
+        offset: u64,
+    },
+    
+    /// Hardware CRC verification failure (`ERR_CRC_MISMATCH`)
+    #[error("Hardware CRC mismatch: {0}")]
+    HardwareCrcMismatch(RegisterSnapshot),
+    
+    /// Hardware memory access fault (`ERR_MEMORY_ACCESS`)
+    #[error("Hardware memory access fault: {0}")]
+    HardwareMemoryFault(RegisterSnapshot),
+    
+    /// Hardware output buffer overflow (`ERR_OVERFLOW`)
+    #[error("Hardware buffer overflow: {0}")]
+    HardwareOverflow(RegisterSnapshot),
+    
+    /// Hardware pipeline stall limit exceeded (`ERR_STALL`)
+    #[error("Hardware pipeline stall: {0}")]
+    HardwareStall(RegisterSnapshot),
+    
+    /// Hardware invalid state transition (`ERR_INVALID_STATE`)
+    #[error("Hardware invalid state transition: {0}")]
+    HardwareInvalidState(RegisterSnapshot),
+    
+    /// Hardware operation timeout (`ERR_TIMEOUT`)
+    #[error("Hardware timeout: {0}")]
+    HardwareTimeout(RegisterSnapshot),
+    
+    /// Hardware error status without a known error code
+    #[error("Unknown hardware error: {0}")]
+    HardwareFault(RegisterSnapshot),
+}
+
+impl Lzma2Error {
+    /// Build the error variant for a hardware error code
+    pub fn from_hardware(code: HardwareErrorCode, snapshot: RegisterSnapshot) -> Self {
+        match code {
+            HardwareErrorCode::CrcMismatch => Lzma2Error::HardwareCrcMismatch(snapshot),
+            HardwareErrorCode::MemoryAccess => Lzma2Error::HardwareMemoryFault(snapshot),
+            HardwareErrorCode::Overflow => Lzma2Error::HardwareOverflow(snapshot),
+            HardwareErrorCode::Timeout => Lzma2Error::HardwareTimeout(snapshot),
+            HardwareErrorCode::InvalidState => Lzma2Error::HardwareInvalidState(snapshot),
+            HardwareErrorCode::Stall => Lzma2Error::HardwareStall(snapshot),
+        }
+    }
+}
+
+impl From<Lzma2Error> for std::io::Error {
+    fn from(error: Lzma2Error) -> Self {
+        let kind = match error {
+            Lzma2Error::TimeoutError => std::io::ErrorKind::TimedOut,
+            Lzma2Error::InputValidationError(_)
+            | Lzma2Error::ProcessingError(_)
+            | Lzma2Error::VerificationFailed { .. } => std::io::ErrorKind::InvalidData,
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, error)
+    }
 }
 
 /// Error extension trait for additional error handling capabilities
@@ -42,6 +119,11 @@ pub trait ErrorExt {
     
     /// Provides a detailed error context
     fn context(&self) -> Option<&str>;
+    
+    /// Register snapshot taken when a hardware error was detected
+    fn register_snapshot(&self) -> Option<&RegisterSnapshot> {
+        None
+    }
 }
 
 impl ErrorExt for Lzma2Error {
@@ -49,11 +131,26 @@ impl ErrorExt for Lzma2Error {
         match self {
             Lzma2Error::TimeoutError => true,
             Lzma2Error::TransferError(_) => true,
+            // Free again once the holder is dropped
+            Lzma2Error::EngineBusy(_) => true,
             Lzma2Error::DeviceInitError(_) => false,
             Lzma2Error::ProcessingError(_) => false,
             Lzma2Error::DeviceAccessError => false,
             Lzma2Error::CrcError => false,
             Lzma2Error::InputValidationError(_) => false,
+            Lzma2Error::OutputOverflow { .. } => false,
+            // Corruption on the device may not repeat
+            Lzma2Error::VerificationFailed { .. } => true,
+            // Transient engine conditions clear with a device reset
+            Lzma2Error::HardwareCrcMismatch(_) => true,
+            Lzma2Error::HardwareStall(_) => true,
+            Lzma2Error::HardwareInvalidState(_) => true,
+            Lzma2Error::HardwareTimeout(_) => true,
+            // Retrying the same job fails the same way
+            Lzma2Error::HardwareMemoryFault(_) => false,
+            Lzma2Error::HardwareOverflow(_) => false,
+            // Nothing is known about the fault
+            Lzma2Error::HardwareFault(_) => false,
         }
     }
     
@@ -61,11 +158,25 @@ impl ErrorExt for Lzma2Error {
         match self {
             Lzma2Error::DeviceInitError(ctx) => Some(ctx),
             Lzma2Error::TransferError(ctx) => Some(ctx),
+            Lzma2Error::EngineBusy(ctx) => Some(ctx),
             Lzma2Error::ProcessingError(ctx) => Some(ctx),
             Lzma2Error::InputValidationError(ctx) => Some(ctx),
             _ => None
         }
     }
+    
+    fn register_snapshot(&self) -> Option<&RegisterSnapshot> {
+        match self {
+            Lzma2Error::HardwareCrcMismatch(snapshot)
+            | Lzma2Error::HardwareMemoryFault(snapshot)
+            | Lzma2Error::HardwareOverflow(snapshot)
+            | Lzma2Error::HardwareStall(snapshot)
+            | Lzma2Error::HardwareInvalidState(snapshot)
+            | Lzma2Error::HardwareTimeout(snapshot)
+            | Lzma2Error::HardwareFault(snapshot) => Some(snapshot),
+            _ => None
+        }
+    }
 }
 
 /// Convenience result type using Lzma2Error
@@ -0,0 +1,361 @@
+//! LZMA2 chunk framing
+//!
+//! An LZMA2 stream is a sequence of chunks terminated by a zero byte. Each
+//! chunk starts with a control byte:
+//!
+//! | Control     | Chunk                                              |
+//! |-------------|----------------------------------------------------|
+//! | `0x00`      | End of stream                                      |
+//! | `0x01`      | Stored, dictionary reset                           |
+//! | `0x02`      | Stored, no reset                                   |
+//! | `0x80-0xFF` | LZMA; bits [6:5] select the reset, [4:0] are bits  |
+//! |             | [20:16] of the unpacked size minus one             |
+//!
+//! LZMA chunks continue with the low 16 bits of the unpacked size minus
+//! one, the packed size minus one (both big-endian) and, when the reset
+//! includes new properties, the lc/lp/pb properties byte. Stored chunks
+//! carry their size minus one followed by the data.
+
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::lzma::LzmaProperties;
+
+/// Largest unpacked size of one LZMA chunk
+pub const MAX_UNPACKED_CHUNK: usize = 1 << 21;
+
+/// Largest packed size of one chunk
+pub const MAX_PACKED_CHUNK: usize = 1 << 16;
+
+/// Header of an LZMA chunk that carries the properties byte
+pub const LZMA_CHUNK_HEADER_SIZE: usize = 6;
+
+/// Header of a stored chunk
+pub const STORED_CHUNK_HEADER_SIZE: usize = 3;
+
+/// Control byte: end of stream
+pub const CONTROL_END: u8 = 0x00;
+
+/// Control byte: stored chunk with dictionary reset
+pub const CONTROL_STORED_RESET: u8 = 0x01;
+
+/// Control byte: stored chunk without reset
+pub const CONTROL_STORED: u8 = 0x02;
+
+/// Reset performed at the start of an LZMA chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkReset {
+    /// Continue the previous chunk's state
+    None = 0,
+
+    /// Reset the coder state
+    State = 1,
+
+    /// Reset the state and read new properties
+    StateProps = 2,
+
+    /// Reset the state, properties and dictionary
+    Dictionary = 3,
+}
+
+impl ChunkReset {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x3 {
+            0 => Self::None,
+            1 => Self::State,
+            2 => Self::StateProps,
+            _ => Self::Dictionary,
+        }
+    }
+
+    /// Whether the chunk carries a properties byte
+    pub fn has_props(self) -> bool {
+        matches!(self, Self::StateProps | Self::Dictionary)
+    }
+}
+
+/// One parsed chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk<'a> {
+    /// Range-coded LZMA data
+    Lzma {
+        /// Reset performed before decoding
+        reset: ChunkReset,
+
+        /// Properties byte, present with `StateProps` and `Dictionary`
+        props: Option<u8>,
+
+        /// Decoded size in bytes
+        unpacked_len: usize,
+
+        /// Range coder stream
+        data: &'a [u8],
+    },
+
+    /// Uncompressed data
+    Stored {
+        /// Whether the dictionary is reset first
+        dict_reset: bool,
+
+        /// Chunk contents
+        data: &'a [u8],
+    },
+}
+
+impl Chunk<'_> {
+    /// Decoded size in bytes
+    pub fn unpacked_len(&self) -> usize {
+        match self {
+            Self::Lzma { unpacked_len, .. } => *unpacked_len,
+            Self::Stored { data, .. } => data.len(),
+        }
+    }
+
+    /// Whether the chunk resets the dictionary
+    pub fn resets_dictionary(&self) -> bool {
+        match self {
+            Self::Lzma { reset, .. } => *reset == ChunkReset::Dictionary,
+            Self::Stored { dict_reset, .. } => *dict_reset,
+        }
+    }
+}
+
+/// Append an LZMA chunk holding `packed` range coder output
+///
+/// # Errors
+/// Returns `Lzma2Error::InputValidationError` if a size exceeds the chunk
+/// limits
+pub fn write_lzma_chunk(
+    out: &mut Vec<u8>,
+    reset: ChunkReset,
+    props: u8,
+    unpacked_len: usize,
+    packed: &[u8],
+) -> Lzma2Result<()> {
+    if unpacked_len == 0 || unpacked_len > MAX_UNPACKED_CHUNK {
+        return Err(Lzma2Error::InputValidationError(
+            format!("LZMA chunk cannot hold {} unpacked bytes", unpacked_len)
+        ));
+    }
+    if packed.is_empty() || packed.len() > MAX_PACKED_CHUNK {
+        return Err(Lzma2Error::InputValidationError(
+            format!("LZMA chunk cannot hold {} packed bytes", packed.len())
+        ));
+    }
+
+    let unpacked = (unpacked_len - 1) as u32;
+    let packed_size = (packed.len() - 1) as u16;
+    out.push(0x80 | (reset as u8) << 5 | (unpacked >> 16) as u8);
+    out.extend_from_slice(&(unpacked as u16).to_be_bytes());
+    out.extend_from_slice(&packed_size.to_be_bytes());
+    if reset.has_props() {
+        out.push(props);
+    }
+    out.extend_from_slice(packed);
+    Ok(())
+}
+
+/// Append a stored chunk
+///
+/// # Errors
+/// Returns `Lzma2Error::InputValidationError` if `data` is empty or larger
+/// than `MAX_PACKED_CHUNK`
+pub fn write_stored_chunk(out: &mut Vec<u8>, dict_reset: bool, data: &[u8]) -> Lzma2Result<()> {
+    if data.is_empty() || data.len() > MAX_PACKED_CHUNK {
+        return Err(Lzma2Error::InputValidationError(
+            format!("Stored chunk cannot hold {} bytes", data.len())
+        ));
+    }
+
+    out.push(if dict_reset { CONTROL_STORED_RESET } else { CONTROL_STORED });
+    out.extend_from_slice(&((data.len() - 1) as u16).to_be_bytes());
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+/// Append the end-of-stream marker
+pub fn write_end(out: &mut Vec<u8>) {
+    out.push(CONTROL_END);
+}
+
+/// Iterator over the chunks of an LZMA2 stream
+///
+/// Stops after the end marker; `position` then gives the stream length.
+#[derive(Debug)]
+pub struct ChunkReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    done: bool,
+    finished: bool,
+    need_dict_reset: bool,
+    need_props: bool,
+}
+
+impl<'a> ChunkReader<'a> {
+    /// Read chunks from the start of `input`
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            done: false,
+            finished: false,
+            need_dict_reset: true,
+            need_props: true,
+        }
+    }
+
+    /// Bytes consumed so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Whether the end marker has been read
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn take(&mut self, len: usize) -> Lzma2Result<&'a [u8]> {
+        let bytes = self.input.get(self.pos..self.pos + len).ok_or_else(|| {
+            Lzma2Error::InputValidationError("Truncated LZMA2 stream".to_string())
+        })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> Lzma2Result<usize> {
+        let bytes = self.take(2)?;
+        Ok(usize::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+    }
+
+    fn next_chunk(&mut self) -> Lzma2Result<Option<Chunk<'a>>> {
+        let control = self.take(1)?[0];
+
+        let chunk = match control {
+            CONTROL_END => return Ok(None),
+            CONTROL_STORED_RESET | CONTROL_STORED => {
+                let len = self.read_u16()? + 1;
+                Chunk::Stored {
+                    dict_reset: control == CONTROL_STORED_RESET,
+                    data: self.take(len)?,
+                }
+            },
+            0x80..=0xFF => {
+                let reset = ChunkReset::from_bits(control >> 5);
+                let unpacked_len = (usize::from(control & 0x1F) << 16 | self.read_u16()?) + 1;
+                let packed_len = self.read_u16()? + 1;
+                let props = if reset.has_props() { Some(self.take(1)?[0]) } else { None };
+
+                if let Some(byte) = props {
+                    let decoded = LzmaProperties::from_byte(byte)?;
+                    if decoded.lc + decoded.lp > 4 {
+                        return Err(Lzma2Error::InputValidationError(
+                            format!("LZMA2 properties 0x{:02x} exceed lc + lp = 4", byte)
+                        ));
+                    }
+                }
+
+                if self.need_props && props.is_none() {
+                    return Err(Lzma2Error::InputValidationError(
+                        "LZMA2 chunk without properties after a dictionary reset".to_string()
+                    ));
+                }
+                self.need_props = false;
+
+                Chunk::Lzma {
+                    reset,
+                    props,
+                    unpacked_len,
+                    data: self.take(packed_len)?,
+                }
+            },
+            _ => return Err(Lzma2Error::InputValidationError(
+                format!("Invalid LZMA2 control byte 0x{:02x}", control)
+            )),
+        };
+
+        if self.need_dict_reset && !chunk.resets_dictionary() {
+            return Err(Lzma2Error::InputValidationError(
+                "LZMA2 stream does not start with a dictionary reset".to_string()
+            ));
+        }
+        if chunk.resets_dictionary() && matches!(chunk, Chunk::Stored { .. }) {
+            self.need_props = true;
+        }
+        self.need_dict_reset = false;
+
+        Ok(Some(chunk))
+    }
+}
+
+impl<'a> Iterator for ChunkReader<'a> {
+    type Item = Lzma2Result<Chunk<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_chunk() {
+            Ok(Some(chunk)) => Some(Ok(chunk)),
+            Ok(None) => {
+                self.done = true;
+                self.finished = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_roundtrip() -> Lzma2Result<()> {
+        let mut stream = Vec::new();
+        write_lzma_chunk(&mut stream, ChunkReset::Dictionary, 0x5D, 0x12345, &[0, 1, 2])?;
+        write_stored_chunk(&mut stream, false, b"stored")?;
+        write_lzma_chunk(&mut stream, ChunkReset::State, 0x5D, 32768, &[0; 4])?;
+        write_end(&mut stream);
+
+        // Control byte and big-endian sizes minus one
+        assert_eq!(&stream[..6], &[0xE1, 0x23, 0x44, 0x00, 0x02, 0x5D]);
+
+        let mut reader = ChunkReader::new(&stream);
+        let chunks = reader.by_ref().collect::<Lzma2Result<Vec<_>>>()?;
+        assert!(reader.is_finished());
+        assert_eq!(reader.position(), stream.len());
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0], Chunk::Lzma {
+            reset: ChunkReset::Dictionary,
+            props: Some(0x5D),
+            unpacked_len: 0x12345,
+            data: &[0, 1, 2],
+        });
+        assert_eq!(chunks[1], Chunk::Stored { dict_reset: false, data: b"stored" });
+        assert_eq!(chunks[2].unpacked_len(), 32768);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_validation() {
+        let mut out = Vec::new();
+        assert!(write_lzma_chunk(&mut out, ChunkReset::Dictionary, 0x5D, 0, &[0]).is_err());
+        assert!(write_lzma_chunk(&mut out, ChunkReset::Dictionary, 0x5D, 1, &vec![0; MAX_PACKED_CHUNK + 1]).is_err());
+        assert!(write_stored_chunk(&mut out, true, &[]).is_err());
+
+        // Streams must open with a dictionary reset and valid control bytes
+        fn parse(stream: &[u8]) -> Lzma2Result<Vec<Chunk<'_>>> {
+            ChunkReader::new(stream).collect()
+        }
+        assert!(parse(&[CONTROL_STORED, 0, 0, b'x', CONTROL_END]).is_err());
+        assert!(parse(&[0x03, CONTROL_END]).is_err());
+        assert!(parse(&[0xE0, 0, 0, 0, 0, 0xE1, 0]).is_err());
+        assert!(parse(&[CONTROL_STORED_RESET, 0, 4, b'x']).is_err());
+        assert!(parse(&[CONTROL_END]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+//! Legacy `.lzma` (LZMA_Alone) container
+//!
+//! A `.lzma` file is a 13-byte header followed by a single LZMA stream:
+//!
+//! | Field             | Size                                      |
+//! |-------------------|-------------------------------------------|
+//! | Properties        | 1, `(pb * 5 + lp) * 9 + lc`               |
+//! | Dictionary size   | 4 (LE)                                    |
+//! | Uncompressed size | 8 (LE), all ones if the stream ends with  |
+//! |                   | an end-of-stream marker                   |
+
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::lzma::LzmaProperties;
+
+/// Size of the header
+pub const HEADER_SIZE: usize = 13;
+
+/// Uncompressed size field of a stream terminated by its end marker
+pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// Header of a `.lzma` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LzmaAloneHeader {
+    /// lc/lp/pb properties byte
+    pub props: u8,
+
+    /// Dictionary size in bytes
+    pub dict_size: u32,
+
+    /// Uncompressed size, or `None` if the stream ends with an end marker
+    pub uncompressed_size: Option<u64>,
+}
+
+impl LzmaAloneHeader {
+    /// Serialize the header
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0] = self.props;
+        bytes[1..5].copy_from_slice(&self.dict_size.to_le_bytes());
+        bytes[5..].copy_from_slice(&self.uncompressed_size.unwrap_or(UNKNOWN_SIZE).to_le_bytes());
+        bytes
+    }
+
+    /// Parse the header at the start of `input`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if `input` is too short or
+    /// the properties byte is invalid
+    pub fn parse(input: &[u8]) -> Lzma2Result<Self> {
+        let bytes = input.get(..HEADER_SIZE).ok_or_else(|| {
+            Lzma2Error::InputValidationError("Truncated .lzma header".to_string())
+        })?;
+        LzmaProperties::from_byte(bytes[0])?;
+
+        let mut size = [0; 8];
+        size.copy_from_slice(&bytes[5..]);
+        let size = u64::from_le_bytes(size);
+        Ok(Self {
+            props: bytes[0],
+            dict_size: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+            uncompressed_size: (size != UNKNOWN_SIZE).then_some(size),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() -> Lzma2Result<()> {
+        let header = LzmaAloneHeader {
+            props: 0x5D,
+            dict_size: 16384,
+            uncompressed_size: Some(1000),
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(bytes, [0x5D, 0x00, 0x40, 0x00, 0x00, 0xE8, 0x03, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(LzmaAloneHeader::parse(&bytes)?, header);
+
+        let unsized_header = LzmaAloneHeader { uncompressed_size: None, ..header };
+        assert_eq!(&unsized_header.to_bytes()[5..], &[0xFF; 8]);
+        assert_eq!(LzmaAloneHeader::parse(&unsized_header.to_bytes())?, unsized_header);
+
+        assert!(LzmaAloneHeader::parse(&bytes[..12]).is_err());
+        assert!(LzmaAloneHeader::parse(&[0xE1; HEADER_SIZE]).is_err());
+        Ok(())
+    }
+}
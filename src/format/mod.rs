@@ -0,0 +1,8 @@
+//! Container formats for LZMA2 FPGA Compression Driver
+//!
+//! The compression engine emits raw LZMA range-coder streams. The modules
+//! here wrap them in the framings that standard tools understand.
+
+pub mod lzma2;
+pub mod lzma_alone;
+pub mod xz;
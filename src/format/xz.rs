@@ -0,0 +1,230 @@
+//! `.xz` container
+//!
+//! An `.xz` stream wraps LZMA2 data in self-describing blocks:
+//!
+//! | Part          | Contents                                             |
+//! |---------------|------------------------------------------------------|
+//! | Stream header | Magic, stream flags (check type), CRC32              |
+//! | Blocks        | Block header, LZMA2 data, padding, check             |
+//! | Index         | Unpadded and uncompressed size of every block, CRC32 |
+//! | Stream footer | CRC32, index size, stream flags, magic               |
+//!
+//! Block headers carry a single LZMA2 filter whose dictionary size matches
+//! the engine's. Every block is checked with CRC32 of its uncompressed data.
+
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::format::lzma2::ChunkReader;
+use crate::lzma::DICT_SIZE;
+use crate::utils::Crc32;
+
+/// Magic bytes opening a stream
+pub const HEADER_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Magic bytes closing a stream
+pub const FOOTER_MAGIC: [u8; 2] = *b"YZ";
+
+/// Filter ID of LZMA2
+pub const FILTER_LZMA2: u64 = 0x21;
+
+/// Check type: CRC32
+const CHECK_CRC32: u8 = 0x01;
+
+/// Size of the CRC32 check
+const CHECK_SIZE: usize = 4;
+
+/// Block flags: compressed size present
+const BLOCK_FLAG_COMPRESSED_SIZE: u8 = 0x40;
+
+/// Block flags: uncompressed size present
+const BLOCK_FLAG_UNCOMPRESSED_SIZE: u8 = 0x80;
+
+/// Largest value of a variable-length integer
+const VLI_MAX: u64 = u64::MAX / 2;
+
+fn crc32(data: &[u8]) -> u32 {
+    Crc32::new(0xEDB88320, 0xFFFFFFFF).calculate(data)
+}
+
+/// Append `value` as a variable-length integer
+fn write_vli(out: &mut Vec<u8>, mut value: u64) {
+    debug_assert!(value <= VLI_MAX);
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Zero-pad `out` to a multiple of four bytes
+fn pad4(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+/// LZMA2 properties byte for the smallest dictionary of at least `size`
+/// bytes
+///
+/// Dictionary sizes are encoded as `2^n` or `3 * 2^(n-1)`, from 4 KiB up.
+pub fn dict_size_props(size: u32) -> u8 {
+    (0..40u8)
+        .find(|&props| {
+            let encoded = u64::from(2 | (props & 1)) << (props / 2 + 11);
+            encoded >= u64::from(size)
+        })
+        .unwrap_or(40)
+}
+
+/// Size record of a finished block
+#[derive(Debug, Clone, Copy)]
+struct IndexRecord {
+    unpadded_size: u64,
+    uncompressed_size: u64,
+}
+
+/// Writer assembling an `.xz` stream block by block
+#[derive(Debug)]
+pub struct XzWriter {
+    output: Vec<u8>,
+    records: Vec<IndexRecord>,
+}
+
+impl XzWriter {
+    /// Start a stream using CRC32 checks
+    pub fn new() -> Self {
+        let mut output = HEADER_MAGIC.to_vec();
+        let flags = [0x00, CHECK_CRC32];
+        output.extend_from_slice(&flags);
+        output.extend_from_slice(&crc32(&flags).to_le_bytes());
+
+        Self {
+            output,
+            records: Vec::new(),
+        }
+    }
+
+    /// Number of blocks written
+    pub fn block_count(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Append a block holding the LZMA2 stream `lzma2`, which decodes to
+    /// `uncompressed`
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if `lzma2` is not a
+    /// complete LZMA2 stream of `uncompressed.len()` bytes
+    pub fn write_block(&mut self, lzma2: &[u8], uncompressed: &[u8]) -> Lzma2Result<()> {
+        let mut reader = ChunkReader::new(lzma2);
+        let mut unpacked = 0;
+        for chunk in reader.by_ref() {
+            unpacked += chunk?.unpacked_len();
+        }
+        if !reader.is_finished() || reader.position() != lzma2.len() {
+            return Err(Lzma2Error::InputValidationError(
+                "Block data is not a terminated LZMA2 stream".to_string()
+            ));
+        }
+        if unpacked != uncompressed.len() {
+            return Err(Lzma2Error::InputValidationError(format!(
+                "LZMA2 stream decodes to {} bytes, block holds {}", unpacked, uncompressed.len()
+            )));
+        }
+
+        let mut header = vec![0, BLOCK_FLAG_COMPRESSED_SIZE | BLOCK_FLAG_UNCOMPRESSED_SIZE];
+        write_vli(&mut header, lzma2.len() as u64);
+        write_vli(&mut header, uncompressed.len() as u64);
+        write_vli(&mut header, FILTER_LZMA2);
+        write_vli(&mut header, 1);
+        header.push(dict_size_props(DICT_SIZE));
+        header.resize((header.len() + CHECK_SIZE).next_multiple_of(4) - CHECK_SIZE, 0);
+        header[0] = ((header.len() + CHECK_SIZE) / 4 - 1) as u8;
+        let header_crc = crc32(&header);
+        header.extend_from_slice(&header_crc.to_le_bytes());
+
+        self.output.extend_from_slice(&header);
+        self.output.extend_from_slice(lzma2);
+        pad4(&mut self.output);
+        self.output.extend_from_slice(&crc32(uncompressed).to_le_bytes());
+
+        self.records.push(IndexRecord {
+            unpadded_size: (header.len() + lzma2.len() + CHECK_SIZE) as u64,
+            uncompressed_size: uncompressed.len() as u64,
+        });
+        Ok(())
+    }
+
+    /// Write the index and stream footer and return the stream
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut index = vec![0x00];
+        write_vli(&mut index, self.records.len() as u64);
+        for record in &self.records {
+            write_vli(&mut index, record.unpadded_size);
+            write_vli(&mut index, record.uncompressed_size);
+        }
+        pad4(&mut index);
+        let index_crc = crc32(&index);
+        index.extend_from_slice(&index_crc.to_le_bytes());
+        self.output.extend_from_slice(&index);
+
+        let mut footer = ((index.len() / 4 - 1) as u32).to_le_bytes().to_vec();
+        footer.extend_from_slice(&[0x00, CHECK_CRC32]);
+        self.output.extend_from_slice(&crc32(&footer).to_le_bytes());
+        self.output.extend_from_slice(&footer);
+        self.output.extend_from_slice(&FOOTER_MAGIC);
+        self.output
+    }
+}
+
+impl Default for XzWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::lzma2;
+
+    #[test]
+    fn test_dict_size_props() {
+        assert_eq!(dict_size_props(4096), 0);
+        assert_eq!(dict_size_props(6144), 1);
+        assert_eq!(dict_size_props(DICT_SIZE), 4);
+        assert_eq!(dict_size_props(DICT_SIZE + 1), 5);
+        assert_eq!(dict_size_props(u32::MAX), 40);
+    }
+
+    #[test]
+    fn test_xz_stream() -> Lzma2Result<()> {
+        // An empty stream is header, empty index and footer
+        let empty = XzWriter::new().finish();
+        assert_eq!(empty.len(), 32);
+        assert_eq!(&empty[..6], &HEADER_MAGIC);
+        assert_eq!(&empty[30..], &FOOTER_MAGIC);
+
+        let mut writer = XzWriter::new();
+        for data in [&b"first block"[..], b"second"] {
+            let mut stream = Vec::new();
+            lzma2::write_stored_chunk(&mut stream, true, data)?;
+            lzma2::write_end(&mut stream);
+            writer.write_block(&stream, data)?;
+        }
+        assert_eq!(writer.block_count(), 2);
+        let stream = writer.finish();
+
+        let mut decoded = Vec::new();
+        lzma_rs::xz_decompress(&mut &stream[..], &mut decoded).unwrap();
+        assert_eq!(decoded, b"first blocksecond");
+
+        // Blocks must be complete and match their contents
+        let mut writer = XzWriter::new();
+        let mut stream = Vec::new();
+        lzma2::write_stored_chunk(&mut stream, true, b"data")?;
+        assert!(writer.write_block(&stream, b"data").is_err());
+        lzma2::write_end(&mut stream);
+        assert!(writer.write_block(&stream, b"other").is_err());
+        assert_eq!(writer.block_count(), 0);
+
+        Ok(())
+    }
+}
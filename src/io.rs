@@ -0,0 +1,303 @@
+//! Streaming adapters for LZMA2 FPGA Compression Driver
+//!
+//! `Lzma2Encoder` buffers written data into `BLOCK_SIZE` hardware blocks
+//! and writes each one as an LZMA2 chunk; `Lzma2Decoder` reads such a raw
+//! LZMA2 stream chunk by chunk. Both run on any `HardwareCompressionDevice`,
+//! owned or borrowed, through a `BlockCompressor`.
+
+use std::io::{self, Read, Write};
+
+use crate::compressor::BlockCompressor;
+use crate::device::{HardwareCompressionDevice, BLOCK_SIZE};
+use crate::error::Lzma2Error;
+use crate::format::lzma2::{self, Chunk, ChunkReader, CONTROL_END, CONTROL_STORED, CONTROL_STORED_RESET};
+
+/// `io::Write` adapter compressing into a raw LZMA2 stream
+///
+/// `flush` compresses the buffered data as a short block, so everything
+/// written so far can be decoded. The stream is terminated by `finish`, or
+/// on drop, ignoring errors.
+#[derive(Debug)]
+pub struct Lzma2Encoder<W: Write, D: HardwareCompressionDevice> {
+    writer: Option<W>,
+    compressor: BlockCompressor<D>,
+    buffer: Vec<u8>,
+    blocks: usize,
+    finished: bool,
+}
+
+impl<W: Write, D: HardwareCompressionDevice> Lzma2Encoder<W, D> {
+    /// Compress into `writer` on `device`
+    pub fn new(writer: W, device: D) -> Self {
+        Self::with_compressor(writer, BlockCompressor::new(device))
+    }
+
+    /// Compress into `writer` with a configured compressor
+    pub fn with_compressor(writer: W, compressor: BlockCompressor<D>) -> Self {
+        Self {
+            writer: Some(writer),
+            compressor,
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            blocks: 0,
+            finished: false,
+        }
+    }
+
+    /// Compressor running the blocks
+    pub fn compressor(&self) -> &BlockCompressor<D> {
+        &self.compressor
+    }
+
+    /// Underlying writer
+    pub fn get_ref(&self) -> &W {
+        self.writer.as_ref().expect("writer is only taken by finish")
+    }
+
+    /// Compress the remaining data, write the end marker and return the
+    /// writer
+    ///
+    /// # Errors
+    /// Returns the compression or write error
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        self.writer.take().ok_or_else(finished)
+    }
+
+    /// Terminate the stream, keeping the encoder
+    ///
+    /// Later writes fail.
+    ///
+    /// # Errors
+    /// Returns the compression or write error
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+
+        self.write_block()?;
+        let writer = self.writer.as_mut().ok_or_else(finished)?;
+        let mut end = Vec::new();
+        lzma2::write_end(&mut end);
+        writer.write_all(&end)?;
+        writer.flush()?;
+
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Compress the buffered data as one block
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut chunk = Vec::new();
+        self.compressor.write_lzma2_chunk(&mut chunk, &self.buffer, self.blocks)?;
+        self.writer.as_mut().ok_or_else(finished)?.write_all(&chunk)?;
+        self.buffer.clear();
+        self.blocks += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write, D: HardwareCompressionDevice> Write for Lzma2Encoder<W, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(finished());
+        }
+
+        let count = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..count]);
+        if self.buffer.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Err(finished());
+        }
+        self.write_block()?;
+        self.writer.as_mut().ok_or_else(finished)?.flush()
+    }
+}
+
+impl<W: Write, D: HardwareCompressionDevice> Drop for Lzma2Encoder<W, D> {
+    fn drop(&mut self) {
+        let _ = self.try_finish();
+    }
+}
+
+/// `io::Read` adapter restoring a raw LZMA2 stream of hardware blocks
+///
+/// Reading stops at the end marker; the reader is left just after it.
+#[derive(Debug)]
+pub struct Lzma2Decoder<R: Read, D: HardwareCompressionDevice> {
+    reader: R,
+    compressor: BlockCompressor<D>,
+    output: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read, D: HardwareCompressionDevice> Lzma2Decoder<R, D> {
+    /// Decompress from `reader` on `device`
+    pub fn new(reader: R, device: D) -> Self {
+        Self::with_compressor(reader, BlockCompressor::new(device))
+    }
+
+    /// Decompress from `reader` with a configured compressor
+    pub fn with_compressor(reader: R, compressor: BlockCompressor<D>) -> Self {
+        Self {
+            reader,
+            compressor,
+            output: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Release the reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read and restore the next chunk into `output`
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut header = vec![0; 1];
+        self.reader.read_exact(&mut header)?;
+
+        let (header_len, size_offset) = match header[0] {
+            CONTROL_END => {
+                self.finished = true;
+                return Ok(());
+            },
+            CONTROL_STORED_RESET | CONTROL_STORED => (3, 1),
+            0x80..=0xBF => (5, 3),
+            0xC0..=0xFF => (6, 3),
+            control => {
+                return Err(Lzma2Error::InputValidationError(
+                    format!("Invalid LZMA2 control byte 0x{:02x}", control)
+                ).into());
+            },
+        };
+
+        header.resize(header_len, 0);
+        self.reader.read_exact(&mut header[1..])?;
+        let packed_len = usize::from(u16::from_be_bytes([header[size_offset], header[size_offset + 1]])) + 1;
+
+        let mut chunk = header;
+        chunk.resize(header_len + packed_len, 0);
+        self.reader.read_exact(&mut chunk[header_len..])?;
+
+        // Stored chunks may continue a dictionary, which a fresh chunk
+        // reader would reject
+        let chunk = match chunk[0] {
+            CONTROL_STORED_RESET | CONTROL_STORED => Chunk::Stored {
+                dict_reset: chunk[0] == CONTROL_STORED_RESET,
+                data: &chunk[header_len..],
+            },
+            _ => ChunkReader::new(&chunk).next().ok_or(io::ErrorKind::UnexpectedEof)??,
+        };
+        self.compressor.decompress_chunk(chunk, &mut self.output)?;
+        Ok(())
+    }
+}
+
+impl<R: Read, D: HardwareCompressionDevice> Read for Lzma2Decoder<R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.output.clear();
+            self.pos = 0;
+            self.read_chunk()?;
+        }
+
+        let count = buf.len().min(self.output.len() - self.pos);
+        buf[..count].copy_from_slice(&self.output[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+fn finished() -> io::Error {
+    io::Error::other("LZMA2 stream already finished")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::SimulatedDevice;
+    use crate::device::simulator::tests::sample;
+
+    #[test]
+    fn test_write_read_roundtrip() -> io::Result<()> {
+        let device = SimulatedDevice::simulated();
+        let input = sample(2 * BLOCK_SIZE + 12345, 11);
+
+        // Uneven writes are regrouped into hardware blocks
+        let mut encoder = Lzma2Encoder::new(Vec::new(), &device);
+        for piece in input.chunks(7000) {
+            encoder.write_all(piece)?;
+        }
+        let stream = encoder.finish()?;
+
+        let mut decoded = Vec::new();
+        lzma_rs::lzma2_decompress(&mut &stream[..], &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+
+        // The decoder stops at the end marker and leaves trailing data
+        let trailing = [&stream[..], b"next"].concat();
+        let mut decoder = Lzma2Decoder::new(&trailing[..], &device);
+        let mut decoded = Vec::new();
+        io::copy(&mut decoder, &mut decoded)?;
+        assert_eq!(decoded, input);
+        assert_eq!(decoder.into_inner(), b"next");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_and_finish() -> io::Result<()> {
+        let device = SimulatedDevice::simulated();
+        let mut stream = Vec::new();
+
+        // Flushed data decodes before the stream is finished
+        {
+            let mut encoder = Lzma2Encoder::new(&mut stream, &device);
+            encoder.write_all(b"first message")?;
+            encoder.flush()?;
+            encoder.write_all(b", second message")?;
+        }
+        let flushed = &stream[..stream.len() - 1];
+        let mut decoder = Lzma2Decoder::new(flushed, &device);
+        let mut first = [0; 13];
+        decoder.read_exact(&mut first)?;
+        assert_eq!(&first, b"first message");
+
+        // Dropping the encoder finished the stream
+        let mut decoded = String::new();
+        Lzma2Decoder::new(&stream[..], &device).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "first message, second message");
+
+        // Truncated and finished streams fail
+        let mut decoder = Lzma2Decoder::new(&stream[..stream.len() - 2], &device);
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut encoder = Lzma2Encoder::new(Vec::new(), &device);
+        encoder.try_finish()?;
+        assert!(encoder.write(b"late").is_err());
+        assert_eq!(encoder.get_ref(), &[0x00]);
+
+        Ok(())
+    }
+}
@@ -3,13 +3,18 @@
 //! A high-performance driver for LZMA2 compression on FPGA hardware
 
 // Expose public modules
+pub mod compressor;
 pub mod error;
 pub mod device;
+pub mod format;
+pub mod io;
+pub mod lzma;
 pub mod transfer;
 pub mod utils;
 
 // Prelude for convenient imports
 pub mod prelude {
+    pub use crate::compressor::BlockCompressor;
     pub use crate::error::Lzma2Error;
     pub use crate::device::{
         HardwareCompressionDevice,
@@ -0,0 +1,245 @@
+//! Software LZMA decoder
+
+use super::range_coder::RangeDecoder;
+use super::{
+    len_to_pos_state, state_after_literal, state_after_match, state_after_rep,
+    state_after_short_rep, LengthModel, LzmaProperties, Model, END_POS_MODEL_INDEX,
+    MATCH_LEN_MIN, NUM_ALIGN_BITS, NUM_POS_SLOT_BITS, START_POS_MODEL_INDEX,
+};
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// LZMA decoder writing into a caller-provided output buffer
+///
+/// The output buffer doubles as the dictionary. Model and dictionary
+/// persist across `decode` calls until reset, as LZMA2 chunks require.
+pub struct LzmaDecoder {
+    props: LzmaProperties,
+    dict_size: usize,
+    model: Model,
+    state: usize,
+    reps: [u32; 4],
+
+    /// Output position of the first dictionary byte
+    dict_start: Option<usize>,
+}
+
+impl std::fmt::Debug for LzmaDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LzmaDecoder")
+            .field("props", &self.props)
+            .field("dict_size", &self.dict_size)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+fn corrupt(reason: &str) -> Lzma2Error {
+    Lzma2Error::ProcessingError(format!("Corrupt LZMA data: {}", reason))
+}
+
+impl LzmaDecoder {
+    /// Create a decoder with a fresh model
+    pub fn new(props: LzmaProperties, dict_size: u32) -> Self {
+        Self {
+            props,
+            dict_size: dict_size as usize,
+            model: Model::new(props),
+            state: 0,
+            reps: [0; 4],
+            dict_start: None,
+        }
+    }
+
+    /// Properties the model was built for
+    pub fn properties(&self) -> LzmaProperties {
+        self.props
+    }
+
+    /// Reset the model, state and repeated distances, keeping the
+    /// dictionary
+    pub fn reset_state(&mut self) {
+        self.model = Model::new(self.props);
+        self.state = 0;
+        self.reps = [0; 4];
+    }
+
+    /// Switch to new properties, resetting the state
+    pub fn set_properties(&mut self, props: LzmaProperties) {
+        self.props = props;
+        self.reset_state();
+    }
+
+    /// Start a new dictionary at output position `start`
+    ///
+    /// Without a reset the dictionary starts where the first `decode`
+    /// begins appending.
+    pub fn reset_dictionary(&mut self, start: usize) {
+        self.dict_start = Some(start);
+    }
+
+    /// Decode one range-coded stream, appending to `output`
+    ///
+    /// With a known `unpacked_size` decoding stops after that many bytes;
+    /// otherwise the stream must end with an end-of-stream marker.
+    /// Returns the number of input bytes consumed.
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        unpacked_size: Option<usize>,
+    ) -> Lzma2Result<usize> {
+        let mut rc = RangeDecoder::new(input)?;
+        let start = output.len();
+        let end = unpacked_size.map(|size| start + size);
+        let pb_mask = (1 << self.props.pb) - 1;
+        let dict_start = *self.dict_start.get_or_insert(start);
+
+        loop {
+            if rc.is_overrun() {
+                return Err(corrupt("unexpected end of input"));
+            }
+            if end == Some(output.len()) {
+                break;
+            }
+
+            let pos_state = (output.len() - dict_start) & pb_mask;
+            let state = self.state;
+
+            if rc.decode_bit(&mut self.model.is_match[(state << 4) + pos_state]) == 0 {
+                self.decode_literal(&mut rc, output, dict_start);
+                continue;
+            }
+
+            let len = if rc.decode_bit(&mut self.model.is_rep[state]) != 0 {
+                if output.len() == dict_start {
+                    return Err(corrupt("repeated match at stream start"));
+                }
+
+                if rc.decode_bit(&mut self.model.is_rep_g0[state]) == 0 {
+                    if rc.decode_bit(&mut self.model.is_rep0_long[(state << 4) + pos_state]) == 0 {
+                        self.state = state_after_short_rep(state);
+                        let byte = output[output.len() - self.reps[0] as usize - 1];
+                        output.push(byte);
+                        continue;
+                    }
+                } else {
+                    let rep_index = if rc.decode_bit(&mut self.model.is_rep_g1[state]) == 0 {
+                        1
+                    } else if rc.decode_bit(&mut self.model.is_rep_g2[state]) == 0 {
+                        2
+                    } else {
+                        3
+                    };
+
+                    let distance = self.reps[rep_index];
+                    self.reps.copy_within(0..rep_index, 1);
+                    self.reps[0] = distance;
+                }
+
+                self.state = state_after_rep(state);
+                decode_len(&mut rc, &mut self.model.rep_len, pos_state)
+            } else {
+                let len = decode_len(&mut rc, &mut self.model.len, pos_state);
+                self.state = state_after_match(state);
+
+                let distance = decode_distance(&mut rc, &mut self.model, len);
+                if distance == 0xFFFF_FFFF {
+                    if rc.is_overrun() || !rc.is_finished_ok() {
+                        return Err(corrupt("invalid end marker"));
+                    }
+                    if end.is_some_and(|end| end != output.len()) {
+                        return Err(corrupt("end marker before expected size"));
+                    }
+                    return Ok(rc.position());
+                }
+
+                self.reps = [distance, self.reps[0], self.reps[1], self.reps[2]];
+                len
+            };
+
+            let distance = self.reps[0] as usize + 1;
+            if distance > output.len() - dict_start || distance > self.dict_size {
+                return Err(corrupt("match distance out of range"));
+            }
+
+            let len = match end {
+                Some(end) if output.len() + len > end => {
+                    return Err(corrupt("match exceeds expected size"));
+                },
+                _ => len,
+            };
+
+            for _ in 0..len {
+                let byte = output[output.len() - distance];
+                output.push(byte);
+            }
+        }
+
+        if rc.is_overrun() {
+            return Err(corrupt("unexpected end of input"));
+        }
+
+        Ok(rc.position())
+    }
+
+    fn decode_literal(&mut self, rc: &mut RangeDecoder<'_>, output: &mut Vec<u8>, dict_start: usize) {
+        let position = output.len() - dict_start;
+        let previous = if position > 0 { output[output.len() - 1] } else { 0 };
+        let lit_state = ((position & ((1 << self.props.lp) - 1)) << self.props.lc)
+            + (usize::from(previous) >> (8 - self.props.lc));
+        let probs = &mut self.model.literal[0x300 * lit_state..0x300 * (lit_state + 1)];
+
+        let mut symbol = 1usize;
+        if self.state >= 7 && position > self.reps[0] as usize {
+            let mut match_byte = usize::from(output[output.len() - self.reps[0] as usize - 1]);
+            while symbol < 0x100 {
+                let match_bit = (match_byte >> 7) & 1;
+                match_byte <<= 1;
+                let bit = rc.decode_bit(&mut probs[((1 + match_bit) << 8) + symbol]) as usize;
+                symbol = (symbol << 1) | bit;
+                if match_bit != bit {
+                    break;
+                }
+            }
+        }
+        while symbol < 0x100 {
+            symbol = (symbol << 1) | rc.decode_bit(&mut probs[symbol]) as usize;
+        }
+
+        output.push(symbol as u8);
+        self.state = state_after_literal(self.state);
+    }
+}
+
+/// Decode a match length
+fn decode_len(rc: &mut RangeDecoder<'_>, model: &mut LengthModel, pos_state: usize) -> usize {
+    let len = if rc.decode_bit(&mut model.choice) == 0 {
+        rc.decode_tree(&mut model.low[pos_state], 3)
+    } else if rc.decode_bit(&mut model.choice2) == 0 {
+        8 + rc.decode_tree(&mut model.mid[pos_state], 3)
+    } else {
+        16 + rc.decode_tree(&mut model.high, 8)
+    };
+
+    len as usize + MATCH_LEN_MIN
+}
+
+/// Decode a zero-based match distance
+fn decode_distance(rc: &mut RangeDecoder<'_>, model: &mut Model, len: usize) -> u32 {
+    let slot = rc.decode_tree(&mut model.pos_slot[len_to_pos_state(len)], NUM_POS_SLOT_BITS);
+    if slot < START_POS_MODEL_INDEX {
+        return slot;
+    }
+
+    let footer_bits = (slot >> 1) - 1;
+    let base = (2 | (slot & 1)) << footer_bits;
+
+    if slot < END_POS_MODEL_INDEX {
+        let probs = &mut model.pos_special[(base - slot) as usize..];
+        base + rc.decode_reverse_tree(probs, footer_bits)
+    } else {
+        let direct = rc.decode_direct_bits(footer_bits - NUM_ALIGN_BITS) << NUM_ALIGN_BITS;
+        base.wrapping_add(direct)
+            .wrapping_add(rc.decode_reverse_tree(&mut model.align, NUM_ALIGN_BITS))
+    }
+}
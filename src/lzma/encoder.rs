@@ -0,0 +1,377 @@
+//! LZMA encoder model of the compression engine
+
+use super::range_coder::RangeEncoder;
+use super::{
+    len_to_pos_state, state_after_literal, state_after_match, state_after_rep,
+    state_after_short_rep, LengthModel, LzmaProperties, Model, END_POS_MODEL_INDEX,
+    MATCH_LEN_MAX, MATCH_LEN_MIN, NUM_ALIGN_BITS, NUM_POS_SLOT_BITS, POS_STATES_MAX,
+    START_POS_MODEL_INDEX,
+};
+
+/// Hash table size bits (`lzma2_compression_pkg::HASH_BITS`)
+const HASH_BITS: u32 = 15;
+
+/// Maximum hash chain candidates examined per position
+const MAX_CHAIN_DEPTH: usize = 48;
+
+/// Match length at which the search stops early (`NICE_LENGTH`)
+const NICE_LENGTH: usize = 32;
+
+/// Counters mirroring the engine's performance monitor
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct EncoderStats {
+    /// Literal bytes encoded
+    pub literals: u64,
+
+    /// Matches encoded (including repeated matches)
+    pub matches: u64,
+
+    /// Hash lookups that found a candidate
+    pub hash_hits: u64,
+
+    /// Hash lookups that found no candidate
+    pub hash_misses: u64,
+}
+
+/// Greedy hash-chain LZMA encoder
+pub(crate) struct LzmaEncoder {
+    props: LzmaProperties,
+    dict_size: usize,
+    model: Model,
+    rc: RangeEncoder,
+    state: usize,
+    reps: [u32; 4],
+
+    /// Input history; `window[0]` is stream position `window_base`
+    window: Vec<u8>,
+    window_base: usize,
+
+    /// Most recent position + 1 for each hash value
+    head: Vec<usize>,
+
+    /// Previous position + 1 with the same hash, parallel to `window`
+    chain: Vec<usize>,
+
+    stats: EncoderStats,
+}
+
+impl LzmaEncoder {
+    /// Create an encoder with a fresh model and range coder
+    pub fn new(props: LzmaProperties, dict_size: u32) -> Self {
+        Self {
+            props,
+            dict_size: dict_size as usize,
+            model: Model::new(props),
+            rc: RangeEncoder::new(),
+            state: 0,
+            reps: [0; 4],
+            window: Vec::new(),
+            window_base: 0,
+            head: vec![0; 1 << HASH_BITS],
+            chain: Vec::new(),
+            stats: EncoderStats::default(),
+        }
+    }
+
+    /// Performance counters accumulated so far
+    pub fn stats(&self) -> EncoderStats {
+        self.stats
+    }
+
+    /// Encode a block of input, continuing the current stream
+    pub fn encode_block(&mut self, data: &[u8]) {
+        self.trim_window();
+
+        let mut index = self.window.len();
+        self.window.extend_from_slice(data);
+        self.chain.resize(self.window.len(), 0);
+
+        while index < self.window.len() {
+            let available = (self.window.len() - index).min(MATCH_LEN_MAX);
+            let (rep_index, rep_len) = self.find_rep(index, available);
+            let (distance, match_len) = self.find_match(index, available);
+
+            let len = if rep_len >= MATCH_LEN_MIN && rep_len + 1 >= match_len {
+                self.encode_rep(index, rep_index, rep_len);
+                rep_len
+            } else if match_len >= 3 {
+                self.encode_match(index, distance, match_len);
+                match_len
+            } else if self.is_short_rep(index) {
+                self.encode_short_rep(index);
+                1
+            } else {
+                self.encode_literal(index);
+                1
+            };
+
+            for covered in index + 1..index + len {
+                self.insert_hash(covered);
+            }
+            index += len;
+        }
+    }
+
+    /// Encode the end-of-stream marker
+    pub fn encode_end_marker(&mut self) {
+        let pos_state = self.pos_state(self.window.len());
+        self.rc.encode_bit(&mut self.model.is_match[(self.state << 4) + pos_state], 1);
+        self.rc.encode_bit(&mut self.model.is_rep[self.state], 0);
+        encode_len(&mut self.rc, &mut self.model.len, 0, pos_state);
+        encode_distance(&mut self.rc, &mut self.model, 0xFFFF_FFFF, MATCH_LEN_MIN);
+    }
+
+    /// Take the stream bytes completed so far, keeping the stream open
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.rc.take_output()
+    }
+
+    /// Flush the range coder and return the complete stream
+    pub fn finish(self) -> Vec<u8> {
+        self.rc.finish()
+    }
+
+    /// Drop history that can no longer be referenced
+    fn trim_window(&mut self) {
+        if self.window.len() > 2 * self.dict_size {
+            let excess = self.window.len() - self.dict_size;
+            self.window.drain(..excess);
+            self.chain.drain(..excess);
+            self.window_base += excess;
+        }
+    }
+
+    fn pos_state(&self, index: usize) -> usize {
+        (self.window_base + index) & ((1 << self.props.pb) - 1)
+    }
+
+    fn hash(&self, index: usize) -> usize {
+        let bytes = &self.window[index..index + 3];
+        let value = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+        (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert_hash(&mut self, index: usize) {
+        if index + 3 <= self.window.len() {
+            let hash = self.hash(index);
+            self.chain[index] = self.head[hash];
+            self.head[hash] = self.window_base + index + 1;
+        }
+    }
+
+    /// Length of the common prefix of `index` and `index - distance`
+    fn match_len(&self, index: usize, distance: usize, limit: usize) -> usize {
+        let current = &self.window[index..index + limit];
+        let earlier = &self.window[index - distance..];
+        current.iter().zip(earlier).take_while(|(a, b)| a == b).count()
+    }
+
+    /// Whether a distance can be referenced from `index`
+    fn is_valid_distance(&self, index: usize, distance: usize) -> bool {
+        distance >= 1 && distance <= index && distance <= self.dict_size
+    }
+
+    /// Longest match against one of the repeated distances
+    fn find_rep(&self, index: usize, available: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        if available < MATCH_LEN_MIN {
+            return best;
+        }
+
+        for (rep_index, &rep) in self.reps.iter().enumerate() {
+            let distance = rep as usize + 1;
+            if self.is_valid_distance(index, distance) {
+                let len = self.match_len(index, distance, available);
+                if len > best.1 {
+                    best = (rep_index, len);
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Longest match found through the hash chain; inserts `index`
+    fn find_match(&mut self, index: usize, available: usize) -> (usize, usize) {
+        if index + 3 > self.window.len() {
+            return (0, 0);
+        }
+
+        let hash = self.hash(index);
+        let mut candidate = self.head[hash];
+        self.chain[index] = candidate;
+        self.head[hash] = self.window_base + index + 1;
+
+        let mut best = (0, 0);
+        let mut depth = 0;
+
+        while candidate > self.window_base && depth < MAX_CHAIN_DEPTH {
+            let candidate_index = candidate - 1 - self.window_base;
+            let distance = index - candidate_index;
+            if distance > self.dict_size {
+                break;
+            }
+
+            let len = self.match_len(index, distance, available);
+            if len > best.1 {
+                best = (distance, len);
+                if len >= NICE_LENGTH.min(available) {
+                    break;
+                }
+            }
+
+            candidate = self.chain[candidate_index];
+            depth += 1;
+        }
+
+        if best.1 >= 3 {
+            self.stats.hash_hits += 1;
+        } else {
+            self.stats.hash_misses += 1;
+        }
+
+        best
+    }
+
+    fn is_short_rep(&self, index: usize) -> bool {
+        let distance = self.reps[0] as usize + 1;
+        self.is_valid_distance(index, distance)
+            && self.window[index] == self.window[index - distance]
+    }
+
+    fn encode_literal(&mut self, index: usize) {
+        let pos_state = self.pos_state(index);
+        self.rc.encode_bit(&mut self.model.is_match[(self.state << 4) + pos_state], 0);
+
+        let byte = u32::from(self.window[index]);
+        let previous = if index > 0 { self.window[index - 1] } else { 0 };
+        let position = self.window_base + index;
+        let lit_state = ((position & ((1 << self.props.lp) - 1)) << self.props.lc)
+            + (usize::from(previous) >> (8 - self.props.lc));
+        let probs = &mut self.model.literal[0x300 * lit_state..0x300 * (lit_state + 1)];
+
+        if self.state < 7 {
+            self.rc.encode_tree(probs, 8, byte);
+        } else {
+            // Matched literal: code against the byte at the last distance
+            let match_byte = u32::from(self.window[index - self.reps[0] as usize - 1]);
+            let mut symbol = 1usize;
+            let mut matched = true;
+            for i in (0..8).rev() {
+                let bit = (byte >> i) & 1;
+                if matched {
+                    let match_bit = ((match_byte >> i) & 1) as usize;
+                    self.rc.encode_bit(&mut probs[((1 + match_bit) << 8) + symbol], bit);
+                    matched = match_bit as u32 == bit;
+                } else {
+                    self.rc.encode_bit(&mut probs[symbol], bit);
+                }
+                symbol = (symbol << 1) | bit as usize;
+            }
+        }
+
+        self.state = state_after_literal(self.state);
+        self.stats.literals += 1;
+    }
+
+    fn encode_match(&mut self, index: usize, distance: usize, len: usize) {
+        let pos_state = self.pos_state(index);
+        self.rc.encode_bit(&mut self.model.is_match[(self.state << 4) + pos_state], 1);
+        self.rc.encode_bit(&mut self.model.is_rep[self.state], 0);
+        encode_len(&mut self.rc, &mut self.model.len, len - MATCH_LEN_MIN, pos_state);
+        encode_distance(&mut self.rc, &mut self.model, (distance - 1) as u32, len);
+
+        self.reps = [(distance - 1) as u32, self.reps[0], self.reps[1], self.reps[2]];
+        self.state = state_after_match(self.state);
+        self.stats.matches += 1;
+    }
+
+    fn encode_rep(&mut self, index: usize, rep_index: usize, len: usize) {
+        let pos_state = self.pos_state(index);
+        let state = self.state;
+        self.rc.encode_bit(&mut self.model.is_match[(state << 4) + pos_state], 1);
+        self.rc.encode_bit(&mut self.model.is_rep[state], 1);
+
+        if rep_index == 0 {
+            self.rc.encode_bit(&mut self.model.is_rep_g0[state], 0);
+            self.rc.encode_bit(&mut self.model.is_rep0_long[(state << 4) + pos_state], 1);
+        } else {
+            self.rc.encode_bit(&mut self.model.is_rep_g0[state], 1);
+            if rep_index == 1 {
+                self.rc.encode_bit(&mut self.model.is_rep_g1[state], 0);
+            } else {
+                self.rc.encode_bit(&mut self.model.is_rep_g1[state], 1);
+                self.rc.encode_bit(&mut self.model.is_rep_g2[state], (rep_index - 2) as u32);
+            }
+
+            let distance = self.reps[rep_index];
+            self.reps.copy_within(0..rep_index, 1);
+            self.reps[0] = distance;
+        }
+
+        encode_len(&mut self.rc, &mut self.model.rep_len, len - MATCH_LEN_MIN, pos_state);
+        self.state = state_after_rep(self.state);
+        self.stats.matches += 1;
+    }
+
+    fn encode_short_rep(&mut self, index: usize) {
+        let pos_state = self.pos_state(index);
+        let state = self.state;
+        self.rc.encode_bit(&mut self.model.is_match[(state << 4) + pos_state], 1);
+        self.rc.encode_bit(&mut self.model.is_rep[state], 1);
+        self.rc.encode_bit(&mut self.model.is_rep_g0[state], 0);
+        self.rc.encode_bit(&mut self.model.is_rep0_long[(state << 4) + pos_state], 0);
+
+        self.state = state_after_short_rep(self.state);
+        self.stats.matches += 1;
+    }
+}
+
+/// Encode a match length minus `MATCH_LEN_MIN`
+fn encode_len(rc: &mut RangeEncoder, model: &mut LengthModel, len: usize, pos_state: usize) {
+    debug_assert!(pos_state < POS_STATES_MAX);
+    let len = len as u32;
+
+    if len < 8 {
+        rc.encode_bit(&mut model.choice, 0);
+        rc.encode_tree(&mut model.low[pos_state], 3, len);
+    } else if len < 16 {
+        rc.encode_bit(&mut model.choice, 1);
+        rc.encode_bit(&mut model.choice2, 0);
+        rc.encode_tree(&mut model.mid[pos_state], 3, len - 8);
+    } else {
+        rc.encode_bit(&mut model.choice, 1);
+        rc.encode_bit(&mut model.choice2, 1);
+        rc.encode_tree(&mut model.high, 8, len - 16);
+    }
+}
+
+/// Slot of a zero-based match distance
+fn pos_slot(distance: u32) -> u32 {
+    if distance < START_POS_MODEL_INDEX {
+        distance
+    } else {
+        let top = 31 - distance.leading_zeros();
+        (top << 1) | ((distance >> (top - 1)) & 1)
+    }
+}
+
+/// Encode a zero-based match distance
+fn encode_distance(rc: &mut RangeEncoder, model: &mut Model, distance: u32, len: usize) {
+    let slot = pos_slot(distance);
+    rc.encode_tree(&mut model.pos_slot[len_to_pos_state(len)], NUM_POS_SLOT_BITS, slot);
+
+    if slot >= START_POS_MODEL_INDEX {
+        let footer_bits = (slot >> 1) - 1;
+        let base = (2 | (slot & 1)) << footer_bits;
+        let reduced = distance - base;
+
+        if slot < END_POS_MODEL_INDEX {
+            let probs = &mut model.pos_special[(base - slot) as usize..];
+            rc.encode_reverse_tree(probs, footer_bits, reduced);
+        } else {
+            rc.encode_direct_bits(reduced >> NUM_ALIGN_BITS, footer_bits - NUM_ALIGN_BITS);
+            rc.encode_reverse_tree(&mut model.align, NUM_ALIGN_BITS, reduced & 0xF);
+        }
+    }
+}
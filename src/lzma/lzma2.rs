@@ -0,0 +1,181 @@
+//! Software LZMA2 decoder
+
+use super::decoder::LzmaDecoder;
+use super::LzmaProperties;
+use crate::error::{Lzma2Error, Lzma2Result};
+use crate::format::lzma2::{Chunk, ChunkReader, ChunkReset};
+
+/// Decoder for raw LZMA2 streams held in memory
+///
+/// See `io::Lzma2Decoder` for reading a stream incrementally.
+///
+/// Applies the resets requested by each chunk: stored chunks extend the
+/// dictionary, LZMA chunks continue or restart the model.
+#[derive(Debug)]
+pub struct RawLzma2Decoder {
+    dict_size: u32,
+
+    /// LZMA decoder, created by the first chunk with properties
+    lzma: Option<LzmaDecoder>,
+
+    /// Output position of the first dictionary byte
+    dict_start: usize,
+}
+
+impl RawLzma2Decoder {
+    /// Create a decoder for streams using a dictionary of `dict_size` bytes
+    pub fn new(dict_size: u32) -> Self {
+        Self {
+            dict_size,
+            lzma: None,
+            dict_start: 0,
+        }
+    }
+
+    /// Decode one stream, appending to `output`
+    ///
+    /// Returns the number of input bytes consumed, including the end marker.
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::InputValidationError` if the chunk framing is
+    /// malformed, or `Lzma2Error::ProcessingError` if chunk data is corrupt
+    pub fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) -> Lzma2Result<usize> {
+        let mut reader = ChunkReader::new(input);
+
+        for chunk in reader.by_ref() {
+            let chunk = chunk?;
+            if chunk.resets_dictionary() {
+                self.dict_start = output.len();
+            }
+
+            match chunk {
+                Chunk::Stored { data, .. } => output.extend_from_slice(data),
+                Chunk::Lzma { reset, props, unpacked_len, data } => {
+                    self.decode_chunk(reset, props, unpacked_len, data, output)?;
+                },
+            }
+        }
+
+        if !reader.is_finished() {
+            return Err(Lzma2Error::InputValidationError(
+                "LZMA2 stream is not terminated by its end marker".to_string()
+            ));
+        }
+        Ok(reader.position())
+    }
+
+    fn decode_chunk(
+        &mut self,
+        reset: ChunkReset,
+        props: Option<u8>,
+        unpacked_len: usize,
+        data: &[u8],
+        output: &mut Vec<u8>,
+    ) -> Lzma2Result<()> {
+        if let Some(byte) = props {
+            let props = LzmaProperties::from_byte(byte)?;
+            match &mut self.lzma {
+                Some(lzma) => lzma.set_properties(props),
+                None => self.lzma = Some(LzmaDecoder::new(props, self.dict_size)),
+            }
+        }
+
+        let lzma = self.lzma.as_mut().ok_or_else(|| {
+            Lzma2Error::InputValidationError("LZMA2 chunk without properties".to_string())
+        })?;
+        if reset == ChunkReset::State {
+            lzma.reset_state();
+        }
+        lzma.reset_dictionary(self.dict_start);
+
+        let consumed = lzma.decode(data, output, Some(unpacked_len))?;
+        if consumed != data.len() {
+            return Err(Lzma2Error::ProcessingError(format!(
+                "Corrupt LZMA data: chunk ends after {} of {} bytes", consumed, data.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Decode a complete raw LZMA2 stream
+///
+/// # Errors
+/// Returns `Lzma2Error` if the stream is malformed, corrupt or followed by
+/// trailing data
+pub fn decode(input: &[u8], dict_size: u32) -> Lzma2Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let consumed = RawLzma2Decoder::new(dict_size).decode(input, &mut output)?;
+
+    if consumed != input.len() {
+        return Err(Lzma2Error::InputValidationError(
+            format!("{} trailing bytes after the LZMA2 end marker", input.len() - consumed)
+        ));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::lzma2::{write_end, write_lzma_chunk, write_stored_chunk};
+    use crate::lzma::encoder::LzmaEncoder;
+    use crate::lzma::DICT_SIZE;
+    use crate::device::simulator::tests::sample;
+
+    #[test]
+    fn test_chunk_resets() -> Lzma2Result<()> {
+        let props = LzmaProperties::default();
+        let first = sample(5000, 5);
+        let second = sample(7000, 5);
+
+        let mut encoder = LzmaEncoder::new(props, DICT_SIZE);
+        encoder.encode_block(&first);
+        let head = encoder.finish();
+
+        let mut stream = Vec::new();
+        write_lzma_chunk(&mut stream, ChunkReset::Dictionary, props.to_byte(), first.len(), &head)?;
+        write_stored_chunk(&mut stream, false, b"stored\0\0")?;
+
+        // A state reset keeps the dictionary; ending the stored chunk with a
+        // zero byte on a pb boundary matches a fresh encoder's context
+        let mut encoder = LzmaEncoder::new(props, DICT_SIZE);
+        encoder.encode_block(&second);
+        write_lzma_chunk(&mut stream, ChunkReset::State, 0, second.len(), &encoder.finish())?;
+        write_stored_chunk(&mut stream, true, b"new dictionary")?;
+        write_end(&mut stream);
+
+        let decoded = decode(&stream, DICT_SIZE)?;
+        assert_eq!(decoded, [&first[..], b"stored\0\0", &second, b"new dictionary"].concat());
+
+        let mut decoded = Vec::new();
+        lzma_rs::lzma2_decompress(&mut &stream[..], &mut decoded).unwrap();
+        assert_eq!(decoded, [&first[..], b"stored\0\0", &second, b"new dictionary"].concat());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_stream() -> Lzma2Result<()> {
+        let props = LzmaProperties::default();
+        let mut encoder = LzmaEncoder::new(props, DICT_SIZE);
+        encoder.encode_block(&sample(3000, 5));
+        let packed = encoder.finish();
+
+        let mut stream = Vec::new();
+        write_lzma_chunk(&mut stream, ChunkReset::Dictionary, props.to_byte(), 3000, &packed)?;
+        write_end(&mut stream);
+        assert_eq!(decode(&stream, DICT_SIZE)?, sample(3000, 5));
+
+        // Trailing data, truncation and a wrong unpacked size are rejected
+        assert!(decode(&[&stream[..], &[0]].concat(), DICT_SIZE).is_err());
+        assert!(decode(&stream[..stream.len() - 1], DICT_SIZE).is_err());
+
+        let mut wrong_size = Vec::new();
+        write_lzma_chunk(&mut wrong_size, ChunkReset::Dictionary, props.to_byte(), 2000, &packed)?;
+        write_end(&mut wrong_size);
+        assert!(decode(&wrong_size, DICT_SIZE).is_err());
+
+        Ok(())
+    }
+}
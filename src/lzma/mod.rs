@@ -0,0 +1,292 @@
+//! Software LZMA codec
+//!
+//! The decoders restore raw LZMA and LZMA2 streams without the device, so
+//! they back decompression on engines without a decode mode and can check
+//! hardware output. The encoder models the raw LZMA stream produced by the
+//! compression engine (lc=3, lp=0, pb=2, 16KB dictionary) for the
+//! simulated device, and encodes short blocks the engine cannot take.
+
+pub mod decoder;
+pub(crate) mod encoder;
+pub mod lzma2;
+pub(crate) mod range_coder;
+
+pub use decoder::LzmaDecoder;
+pub use lzma2::RawLzma2Decoder;
+
+use crate::device::BLOCK_SIZE;
+use crate::error::{Lzma2Error, Lzma2Result};
+
+/// Dictionary size of the compression engine (`lzma2_pkg::DICT_SIZE`)
+pub const DICT_SIZE: u32 = 16384;
+
+/// Hardware block size (`lzma2_pkg::INPUT_SIZE`)
+pub(crate) const INPUT_SIZE: usize = 32768;
+
+/// Shortest encodable match
+pub(crate) const MATCH_LEN_MIN: usize = 2;
+
+/// Longest encodable match (`lzma2_compression_pkg::MAX_MATCH_LENGTH`)
+pub(crate) const MATCH_LEN_MAX: usize = 273;
+
+const NUM_STATES: usize = 12;
+const POS_STATES_MAX: usize = 1 << 4;
+const LEN_TO_POS_STATES: usize = 4;
+const NUM_POS_SLOT_BITS: u32 = 6;
+const START_POS_MODEL_INDEX: u32 = 4;
+const END_POS_MODEL_INDEX: u32 = 14;
+const NUM_FULL_DISTANCES: usize = 1 << (END_POS_MODEL_INDEX >> 1);
+const NUM_ALIGN_BITS: u32 = 4;
+const LEN_LOW_BITS: u32 = 3;
+const LEN_MID_BITS: u32 = 3;
+const LEN_HIGH_BITS: u32 = 8;
+const LEN_LOW_SYMBOLS: usize = 1 << LEN_LOW_BITS;
+const LEN_MID_SYMBOLS: usize = 1 << LEN_MID_BITS;
+
+/// Initial probability (0.5 in 11-bit fixed point)
+const PROB_INIT: u16 = 1 << 10;
+
+/// Literal context and position bit counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LzmaProperties {
+    /// Literal context bits
+    pub lc: u32,
+
+    /// Literal position bits
+    pub lp: u32,
+
+    /// Position bits
+    pub pb: u32,
+}
+
+impl Default for LzmaProperties {
+    fn default() -> Self {
+        Self { lc: 3, lp: 0, pb: 2 }
+    }
+}
+
+impl LzmaProperties {
+    /// Encode as the LZMA properties byte
+    pub fn to_byte(self) -> u8 {
+        ((self.pb * 5 + self.lp) * 9 + self.lc) as u8
+    }
+
+    /// Decode an LZMA properties byte
+    pub fn from_byte(byte: u8) -> Lzma2Result<Self> {
+        if byte >= 9 * 5 * 5 {
+            return Err(Lzma2Error::ProcessingError(
+                format!("Invalid LZMA properties byte: 0x{:02x}", byte)
+            ));
+        }
+
+        let byte = u32::from(byte);
+        Ok(Self {
+            lc: byte % 9,
+            lp: (byte / 9) % 5,
+            pb: byte / 45,
+        })
+    }
+}
+
+/// Encode one block the way the compression engine does
+///
+/// `end_marker` terminates the stream with the end-of-stream marker.
+pub(crate) fn encode_block(input: &[u8], end_marker: bool) -> Vec<u8> {
+    let mut encoder = encoder::LzmaEncoder::new(LzmaProperties::default(), DICT_SIZE);
+    encoder.encode_block(input);
+    if end_marker {
+        encoder.encode_end_marker();
+    }
+    encoder.finish()
+}
+
+/// Decode the output of one hardware block
+///
+/// # Errors
+/// Returns `Lzma2Error::InputValidationError` if `uncompressed_len` is not
+/// a block size, or `Lzma2Error::ProcessingError` if `compressed` is not a
+/// complete engine stream of `uncompressed_len` bytes
+pub fn decode_block(compressed: &[u8], uncompressed_len: usize) -> Lzma2Result<Vec<u8>> {
+    if uncompressed_len == 0 || uncompressed_len > BLOCK_SIZE {
+        return Err(Lzma2Error::InputValidationError(
+            format!("Block size must be 1 to {} bytes. Requested size: {}", BLOCK_SIZE, uncompressed_len)
+        ));
+    }
+
+    let mut output = Vec::with_capacity(uncompressed_len);
+    let consumed = LzmaDecoder::new(LzmaProperties::default(), DICT_SIZE)
+        .decode(compressed, &mut output, Some(uncompressed_len))?;
+
+    if consumed != compressed.len() {
+        return Err(Lzma2Error::ProcessingError(format!(
+            "Block stream ends after {} of {} bytes", consumed, compressed.len()
+        )));
+    }
+    Ok(output)
+}
+
+/// Probabilities of a length coder
+#[derive(Clone)]
+pub(crate) struct LengthModel {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; LEN_LOW_SYMBOLS]; POS_STATES_MAX],
+    mid: [[u16; LEN_MID_SYMBOLS]; POS_STATES_MAX],
+    high: [u16; 1 << LEN_HIGH_BITS],
+}
+
+impl LengthModel {
+    fn new() -> Self {
+        Self {
+            choice: PROB_INIT,
+            choice2: PROB_INIT,
+            low: [[PROB_INIT; LEN_LOW_SYMBOLS]; POS_STATES_MAX],
+            mid: [[PROB_INIT; LEN_MID_SYMBOLS]; POS_STATES_MAX],
+            high: [PROB_INIT; 1 << LEN_HIGH_BITS],
+        }
+    }
+}
+
+/// Complete adaptive probability model
+#[derive(Clone)]
+pub(crate) struct Model {
+    literal: Vec<u16>,
+    is_match: [u16; NUM_STATES * POS_STATES_MAX],
+    is_rep: [u16; NUM_STATES],
+    is_rep_g0: [u16; NUM_STATES],
+    is_rep_g1: [u16; NUM_STATES],
+    is_rep_g2: [u16; NUM_STATES],
+    is_rep0_long: [u16; NUM_STATES * POS_STATES_MAX],
+    pos_slot: [[u16; 1 << NUM_POS_SLOT_BITS]; LEN_TO_POS_STATES],
+    pos_special: [u16; 1 + NUM_FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << NUM_ALIGN_BITS],
+    len: LengthModel,
+    rep_len: LengthModel,
+}
+
+impl Model {
+    /// Fresh model for the given properties
+    pub fn new(props: LzmaProperties) -> Self {
+        Self {
+            literal: vec![PROB_INIT; 0x300 << (props.lc + props.lp)],
+            is_match: [PROB_INIT; NUM_STATES * POS_STATES_MAX],
+            is_rep: [PROB_INIT; NUM_STATES],
+            is_rep_g0: [PROB_INIT; NUM_STATES],
+            is_rep_g1: [PROB_INIT; NUM_STATES],
+            is_rep_g2: [PROB_INIT; NUM_STATES],
+            is_rep0_long: [PROB_INIT; NUM_STATES * POS_STATES_MAX],
+            pos_slot: [[PROB_INIT; 1 << NUM_POS_SLOT_BITS]; LEN_TO_POS_STATES],
+            pos_special: [PROB_INIT; 1 + NUM_FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+            align: [PROB_INIT; 1 << NUM_ALIGN_BITS],
+            len: LengthModel::new(),
+            rep_len: LengthModel::new(),
+        }
+    }
+}
+
+/// State after a literal
+fn state_after_literal(state: usize) -> usize {
+    match state {
+        0..=3 => 0,
+        4..=9 => state - 3,
+        _ => state - 6,
+    }
+}
+
+/// State after a match
+fn state_after_match(state: usize) -> usize {
+    if state < 7 { 7 } else { 10 }
+}
+
+/// State after a repeated match
+fn state_after_rep(state: usize) -> usize {
+    if state < 7 { 8 } else { 11 }
+}
+
+/// State after a single-byte repeated match
+fn state_after_short_rep(state: usize) -> usize {
+    if state < 7 { 9 } else { 11 }
+}
+
+/// Distance coder context for a match length
+fn len_to_pos_state(len: usize) -> usize {
+    (len - MATCH_LEN_MIN).min(LEN_TO_POS_STATES - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::decoder::LzmaDecoder;
+    use super::encoder::LzmaEncoder;
+
+    fn roundtrip(data: &[u8]) {
+        let props = LzmaProperties::default();
+        let mut encoder = LzmaEncoder::new(props, DICT_SIZE);
+        encoder.encode_block(data);
+        let compressed = encoder.finish();
+
+        let mut output = Vec::new();
+        LzmaDecoder::new(props, DICT_SIZE)
+            .decode(&compressed, &mut output, Some(data.len()))
+            .unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_properties_byte() {
+        assert_eq!(LzmaProperties::default().to_byte(), 0x5d);
+        assert_eq!(LzmaProperties::from_byte(0x5d).unwrap(), LzmaProperties::default());
+        assert!(LzmaProperties::from_byte(225).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip_patterns() {
+        roundtrip(&[]);
+        roundtrip(b"a");
+        roundtrip(&[0u8; INPUT_SIZE]);
+        roundtrip(&b"abcabcabcabd".repeat(3000)[..INPUT_SIZE]);
+
+        let text: Vec<u8> = (0..INPUT_SIZE)
+            .map(|i| b"the quick brown fox jumps over the lazy dog "[(i * 7 + i / 13) % 44])
+            .collect();
+        roundtrip(&text);
+    }
+
+    #[test]
+    fn test_roundtrip_random() {
+        // xorshift keeps the data incompressible without extra dependencies
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let data: Vec<u8> = (0..INPUT_SIZE).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        }).collect();
+        roundtrip(&data);
+    }
+
+    #[test]
+    fn test_corrupt_input() {
+        let props = LzmaProperties::default();
+        let mut encoder = LzmaEncoder::new(props, DICT_SIZE);
+        encoder.encode_block(&b"corruption ".repeat(500));
+        let mut compressed = encoder.finish();
+        compressed.truncate(compressed.len() / 2);
+
+        let mut output = Vec::new();
+        assert!(LzmaDecoder::new(props, DICT_SIZE)
+            .decode(&compressed, &mut output, Some(5500))
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_block_limits() {
+        let compressed = encode_block(&b"limits ".repeat(100), false);
+        assert_eq!(decode_block(&compressed, 700).unwrap(), b"limits ".repeat(100));
+
+        // Sizes no block can have are rejected before allocating
+        for len in [0, BLOCK_SIZE + 1, usize::MAX] {
+            assert!(matches!(decode_block(&compressed, len), Err(Lzma2Error::InputValidationError(_))));
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Binary range coder shared by the LZMA encoder and decoder
+
+use crate::error::{Lzma2Error, Lzma2Result};
+
+const NUM_BIT_MODEL_TOTAL_BITS: u32 = 11;
+const BIT_MODEL_TOTAL: u32 = 1 << NUM_BIT_MODEL_TOTAL_BITS;
+const NUM_MOVE_BITS: u32 = 5;
+const TOP_VALUE: u32 = 1 << 24;
+
+/// Range encoder producing the compressed byte stream
+pub(crate) struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    output: Vec<u8>,
+}
+
+impl RangeEncoder {
+    pub fn new() -> Self {
+        Self {
+            low: 0,
+            range: 0xFFFF_FFFF,
+            cache: 0,
+            cache_size: 1,
+            output: Vec::new(),
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.output.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    /// Encode one bit with an adaptive probability
+    pub fn encode_bit(&mut self, prob: &mut u16, bit: u32) {
+        let bound = (self.range >> NUM_BIT_MODEL_TOTAL_BITS) * u32::from(*prob);
+        if bit == 0 {
+            self.range = bound;
+            *prob += ((BIT_MODEL_TOTAL - u32::from(*prob)) >> NUM_MOVE_BITS) as u16;
+        } else {
+            self.low += u64::from(bound);
+            self.range -= bound;
+            *prob -= *prob >> NUM_MOVE_BITS;
+        }
+        while self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    /// Encode the low `count` bits of `value` with fixed probability
+    pub fn encode_direct_bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += u64::from(self.range);
+            }
+            while self.range < TOP_VALUE {
+                self.range <<= 8;
+                self.shift_low();
+            }
+        }
+    }
+
+    /// Encode `bits` bits of `value`, most significant first
+    pub fn encode_tree(&mut self, probs: &mut [u16], bits: u32, value: u32) {
+        let mut m = 1;
+        for i in (0..bits).rev() {
+            let bit = (value >> i) & 1;
+            self.encode_bit(&mut probs[m], bit);
+            m = (m << 1) | bit as usize;
+        }
+    }
+
+    /// Encode `bits` bits of `value`, least significant first
+    pub fn encode_reverse_tree(&mut self, probs: &mut [u16], bits: u32, value: u32) {
+        let mut m = 1;
+        for i in 0..bits {
+            let bit = (value >> i) & 1;
+            self.encode_bit(&mut probs[m], bit);
+            m = (m << 1) | bit as usize;
+        }
+    }
+
+    /// Take the bytes completed so far, keeping the coder open
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Flush all pending state and return the remaining bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.output
+    }
+}
+
+/// Range decoder over an in-memory buffer
+pub(crate) struct RangeDecoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    range: u32,
+    code: u32,
+    overrun: bool,
+}
+
+impl<'a> RangeDecoder<'a> {
+    /// Initialise from the five header bytes of the stream
+    pub fn new(input: &'a [u8]) -> Lzma2Result<Self> {
+        if input.len() < 5 || input[0] != 0 {
+            return Err(Lzma2Error::ProcessingError(
+                "Invalid range coder header".to_string()
+            ));
+        }
+
+        let code = u32::from_be_bytes([input[1], input[2], input[3], input[4]]);
+        if code == 0xFFFF_FFFF {
+            return Err(Lzma2Error::ProcessingError(
+                "Invalid range coder header".to_string()
+            ));
+        }
+
+        Ok(Self {
+            input,
+            position: 5,
+            range: 0xFFFF_FFFF,
+            code,
+            overrun: false,
+        })
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        match self.input.get(self.position) {
+            Some(&byte) => {
+                self.position += 1;
+                byte
+            },
+            None => {
+                self.overrun = true;
+                0
+            },
+        }
+    }
+
+    fn normalize(&mut self) {
+        if self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.code = (self.code << 8) | u32::from(self.next_byte());
+        }
+    }
+
+    /// Decode one bit with an adaptive probability
+    pub fn decode_bit(&mut self, prob: &mut u16) -> u32 {
+        let bound = (self.range >> NUM_BIT_MODEL_TOTAL_BITS) * u32::from(*prob);
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((BIT_MODEL_TOTAL - u32::from(*prob)) >> NUM_MOVE_BITS) as u16;
+            0
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> NUM_MOVE_BITS;
+            1
+        };
+        self.normalize();
+        bit
+    }
+
+    /// Decode `count` bits with fixed probability
+    pub fn decode_direct_bits(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = u32::from(self.code >= self.range);
+            if bit != 0 {
+                self.code -= self.range;
+            }
+            value = (value << 1) | bit;
+            self.normalize();
+        }
+        value
+    }
+
+    /// Decode `bits` bits, most significant first
+    pub fn decode_tree(&mut self, probs: &mut [u16], bits: u32) -> u32 {
+        let mut m = 1;
+        for _ in 0..bits {
+            m = (m << 1) | self.decode_bit(&mut probs[m]) as usize;
+        }
+        (m - (1 << bits)) as u32
+    }
+
+    /// Decode `bits` bits, least significant first
+    pub fn decode_reverse_tree(&mut self, probs: &mut [u16], bits: u32) -> u32 {
+        let mut m = 1;
+        let mut value = 0;
+        for i in 0..bits {
+            let bit = self.decode_bit(&mut probs[m]);
+            m = (m << 1) | bit as usize;
+            value |= bit << i;
+        }
+        value
+    }
+
+    /// Whether the decoder has read past the end of its input
+    pub fn is_overrun(&self) -> bool {
+        self.overrun
+    }
+
+    /// Whether the stream ended cleanly after a flush
+    pub fn is_finished_ok(&self) -> bool {
+        self.code == 0
+    }
+
+    /// Number of input bytes consumed
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
@@ -0,0 +1,45 @@
+//! Memory-mapped I/O transfers
+
+use super::{DataTransfer, TransferContext, TransferStrategy};
+use crate::error::Lzma2Result;
+
+/// Bytes moved per burst (eight 256-bit beats)
+const BURST_SIZE: usize = 256;
+
+/// Size of one 256-bit beat
+const BEAT_SIZE: usize = 32;
+
+/// Programmed I/O through the BAR data windows
+///
+/// Needs no DMA support, so it works on every register backend.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MmioTransfer;
+
+impl DataTransfer for MmioTransfer {
+    fn strategy(&self) -> Option<TransferStrategy> {
+        Some(TransferStrategy::Mmio)
+    }
+
+    fn transfer_input(&mut self, ctx: &TransferContext<'_>, data: &[u8]) -> Lzma2Result<()> {
+        for (i, chunk) in data.chunks(BURST_SIZE).enumerate() {
+            let offset = ctx.input_window + (i * BURST_SIZE) as u64;
+
+            // Zero-pad a trailing partial beat
+            let mut burst = [0u8; BURST_SIZE];
+            burst[..chunk.len()].copy_from_slice(chunk);
+            ctx.io.write_burst(offset, &burst[..chunk.len().next_multiple_of(BEAT_SIZE)])?;
+        }
+
+        Ok(())
+    }
+
+    fn read_output(&mut self, ctx: &TransferContext<'_>, len: usize) -> Lzma2Result<Vec<u8>> {
+        let mut output = vec![0u8; len];
+
+        for (i, chunk) in output.chunks_mut(BURST_SIZE).enumerate() {
+            ctx.io.read_burst(ctx.output_window + (i * BURST_SIZE) as u64, chunk)?;
+        }
+
+        Ok(output)
+    }
+}
@@ -1,6 +1,18 @@
 //! Data transfer strategies for LZMA2 FPGA Compression Driver
+//!
+//! A `DataTransfer` moves job data between host memory and the device's
+//! data windows. `PcieDevice` owns one transfer object and hands it the
+//! register backend for each job, so strategies can be swapped at runtime
+//! and implemented outside this crate.
 
-use crate::error::{Lzma2Error, Lzma2Result};
+mod mmio;
+
+pub use mmio::MmioTransfer;
+
+use std::time::Instant;
+
+use crate::device::RegisterIo;
+use crate::error::Lzma2Result;
 
 /// Data transfer strategies
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -15,19 +27,106 @@ pub enum TransferStrategy {
     Streaming,
 }
 
+/// Device resources available to a `DataTransfer`
+#[derive(Clone, Copy)]
+pub struct TransferContext<'a> {
+    /// Register backend of the device
+    pub io: &'a dyn RegisterIo,
+    
+    /// BAR offset of the input window
+    pub input_window: u64,
+    
+    /// BAR offset of the output window
+    pub output_window: u64,
+}
+
+/// Control over the job a `DataTransfer` is running
+pub trait JobControl {
+    /// Start the job, setting `flags` in addition to the mode bits
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error` if the control register cannot be written
+    fn start(&self, flags: u32) -> Lzma2Result<()>;
+    
+    /// Check whether the job has completed without blocking
+    ///
+    /// # Errors
+    /// Returns the hardware error if the job failed
+    fn poll(&self) -> Lzma2Result<bool>;
+    
+    /// Block until the job completes or its deadline passes
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::TimeoutError` on expiry, or the hardware error
+    /// if the job failed
+    fn wait(&self) -> Lzma2Result<()>;
+    
+    /// Number of output bytes the completed job produced
+    ///
+    /// # Errors
+    /// Returns `Lzma2Error::OutputOverflow` if the size exceeds the output
+    /// window
+    fn output_len(&self) -> Lzma2Result<usize>;
+    
+    /// Time by which the job must complete
+    fn deadline(&self) -> Instant;
+}
+
 /// Trait defining data transfer capabilities
-pub trait DataTransfer {
+pub trait DataTransfer: Send {
+    /// Built-in strategy this object implements, `None` for custom ones
+    fn strategy(&self) -> Option<TransferStrategy> {
+        None
+    }
+    
     /// Transfer input data to the device
     /// 
     /// # Errors
     /// Returns `Lzma2Error` if data transfer fails
-    fn transfer_input(&self, data: &[u8]) -> Lzma2Result<()>;
+    fn transfer_input(&mut self, ctx: &TransferContext<'_>, data: &[u8]) -> Lzma2Result<()>;
     
-    /// Read output data from the device
+    /// Read `len` bytes of output data from the device
     /// 
     /// # Errors
     /// Returns `Lzma2Error` if data reading fails
-    fn read_output(&self) -> Lzma2Result<Vec<u8>>;
+    fn read_output(&mut self, ctx: &TransferContext<'_>, len: usize) -> Lzma2Result<Vec<u8>>;
+    
+    /// Run one job: load the input, start, wait and read the output
+    ///
+    /// Strategies that overlap data movement with processing override this.
+    /// 
+    /// # Errors
+    /// Returns `Lzma2Error` if data transfer or the job fails
+    fn run_job(&mut self, ctx: &TransferContext<'_>, input: &[u8], job: &dyn JobControl) -> Lzma2Result<Vec<u8>> {
+        self.transfer_input(ctx, input)?;
+        job.start(0)?;
+        job.wait()?;
+        self.read_output(ctx, job.output_len()?)
+    }
+    
+    /// Run one job of a batch, preparing `next` while the engine processes
+    /// `input`
+    ///
+    /// Batches run their jobs back to back, each call getting the `next` of
+    /// the previous one as its `input`, and end with `finish_batch`. The
+    /// default prepares nothing and runs `run_job`.
+    /// 
+    /// # Errors
+    /// Returns `Lzma2Error` if data transfer or the job fails; a block that
+    /// cannot be prepared fails in its own job
+    fn run_batch_job(
+        &mut self,
+        ctx: &TransferContext<'_>,
+        input: &[u8],
+        next: Option<&[u8]>,
+        job: &dyn JobControl,
+    ) -> Lzma2Result<Vec<u8>> {
+        let _ = next;
+        self.run_job(ctx, input, job)
+    }
+    
+    /// Drop anything prepared for a batch job that did not run
+    fn finish_batch(&mut self) {}
 }
 
 /// Configuration for data transfer
@@ -70,6 +169,7 @@ pub struct TransferMetrics {
 }
 
 /// Transfer statistics collector
+#[derive(Default)]
 pub struct TransferStatistics {
     /// Accumulated metrics
     metrics: TransferMetrics,
@@ -119,7 +219,6 @@ impl TransferStrategy {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Instant;
     
     #[test]
     fn test_transfer_config_default() {
@@ -133,7 +232,6 @@ mod tests {
     fn test_transfer_statistics() {
         let mut stats = TransferStatistics::new();
         
-        let start = Instant::now();
         let duration = std::time::Duration::from_millis(100);
         let bytes = 1_000_000u64;
         
@@ -1,6 +1,6 @@
 //! Utility functions and helpers for LZMA2 FPGA Compression Driver
 
-use crate::error::{Lzma2Error, Lzma2Result};
+use crate::error::Lzma2Error;
 use std::time::{Duration, Instant};
 
 /// Utility for timing operations